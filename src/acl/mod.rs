@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use rsheet_lib::replies::Reply;
+
+use crate::spreadsheet::Spreadsheet;
+use crate::utils::{cell_in_range, is_valid_cell, range_bounds};
+
+/// What a rule lets someone do with a range. Writing to a cell includes
+/// reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Who a rule applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Subject {
+    User(String),
+    Role(String),
+    /// Every client, including ones that haven't authenticated.
+    Everyone,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    subject: Subject,
+    access: Access,
+    range: String,
+}
+
+/// Rules granting users read or write access to ranges of the spreadsheet.
+/// Anything not granted by a rule is denied.
+///
+/// Rules are loaded from a file with one rule per line. Blank lines and lines
+/// starting with `#` are ignored. Roles group users together, and a rule
+/// applies to a user, a `@role`, or `*` for everyone.
+///
+/// ```text
+/// role finance alice bob
+/// allow @finance write A1_C20
+/// allow carol read A1_C20
+/// allow * read E1_E5
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    roles: HashMap<String, Vec<String>>,
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Loads the access control file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parses the contents of an access control file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::acl::{Access, Acl};
+    /// let acl = Acl::parse("allow alice write A1_B2").unwrap();
+    /// assert!(acl.allows(Some("alice"), "B2", Access::Write));
+    /// assert!(!acl.allows(Some("bob"), "B2", Access::Read));
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut acl = Acl::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: &str| format!("Line {}: {}", number + 1, message);
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["role", name, ref users @ ..] => {
+                    let users = users.iter().map(|user| user.to_string());
+                    acl.roles.entry(name.to_string()).or_default().extend(users);
+                }
+                ["allow", subject, access, range] => {
+                    let subject = match subject {
+                        "*" => Subject::Everyone,
+                        _ => match subject.strip_prefix('@') {
+                            Some(role) => Subject::Role(role.to_string()),
+                            None => Subject::User(subject.to_string()),
+                        },
+                    };
+                    let access = match access {
+                        "read" => Access::Read,
+                        "write" => Access::Write,
+                        _ => return Err(error("access must be read or write")),
+                    };
+                    if !is_valid_cell(range) {
+                        return Err(error("invalid range"));
+                    }
+
+                    acl.rules.push(Rule {
+                        subject,
+                        access,
+                        range: range.to_string(),
+                    });
+                }
+                _ => {
+                    return Err(error(
+                        "expected `role <name> <users>...` or `allow <who> <read|write> <range>`",
+                    ))
+                }
+            }
+        }
+
+        Ok(acl)
+    }
+
+    /// Checks if a user, or a client that hasn't authenticated if `user` is
+    /// `None`, can access a cell.
+    pub fn allows(&self, user: Option<&str>, cell: &str, access: Access) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.access >= access && self.applies_to(rule, user) && cell_in_range(cell, &rule.range)
+        })
+    }
+
+    /// Checks if a user can access every cell in a range. The range is
+    /// compared with the ranges of the rules rather than cell by cell, so a
+    /// huge range takes no longer to check than a small one. A range which
+    /// isn't valid, or has no cells, is denied.
+    pub fn allows_range(&self, user: Option<&str>, range: &str, access: Access) -> bool {
+        let ((start_col, start_row), (end_col, end_row)) = match range_bounds(range) {
+            Some(((col, row), (end_col, end_row))) if col <= end_col && row <= end_row => {
+                ((col, row), (end_col, end_row))
+            }
+            _ => return false,
+        };
+
+        // The parts of the granted ranges which fall inside the range.
+        let granted: Vec<((u32, u32), (u32, u32))> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.access >= access && self.applies_to(rule, user))
+            .filter_map(|rule| range_bounds(&rule.range))
+            .map(|((col, row), (rule_end_col, rule_end_row))| {
+                (
+                    (col.max(start_col), row.max(start_row)),
+                    (rule_end_col.min(end_col), rule_end_row.min(end_row)),
+                )
+            })
+            .filter(|((col, row), (end_col, end_row))| col <= end_col && row <= end_row)
+            .collect();
+
+        // Cutting the range along the edges of the granted ranges leaves
+        // blocks which are either entirely inside or entirely outside of each
+        // of them, so checking the corner of each block checks all of it.
+        let cols = edges(start_col, end_col, granted.iter().map(|(s, e)| (s.0, e.0)));
+        let rows = edges(start_row, end_row, granted.iter().map(|(s, e)| (s.1, e.1)));
+        cols.iter().all(|&col| {
+            rows.iter().all(|&row| {
+                granted
+                    .iter()
+                    .any(|((start_col, start_row), (end_col, end_row))| {
+                        (*start_col..=*end_col).contains(&col)
+                            && (*start_row..=*end_row).contains(&row)
+                    })
+            })
+        })
+    }
+
+    fn applies_to(&self, rule: &Rule, user: Option<&str>) -> bool {
+        match (&rule.subject, user) {
+            (Subject::Everyone, _) => true,
+            (Subject::User(name), Some(user)) => name == user,
+            (Subject::Role(role), Some(user)) => self
+                .roles
+                .get(role)
+                .is_some_and(|users| users.iter().any(|member| member == user)),
+            (_, None) => false,
+        }
+    }
+}
+
+/// Finds where the spans inside `start..=end` begin and end, along with
+/// `start` itself, in order.
+fn edges(start: u32, end: u32, spans: impl Iterator<Item = (u32, u32)>) -> Vec<u32> {
+    let mut edges = vec![start];
+    for (span_start, span_end) in spans {
+        edges.push(span_start);
+        if span_end < end {
+            edges.push(span_end + 1);
+        }
+    }
+
+    edges.sort_unstable();
+    edges.dedup();
+    edges
+}
+
+/// Checks that a user is allowed to run a command. `get` needs to read the
+/// cell, and `set`, `clear`, `lock` and `unlock` need to write it. A formula also needs to read
+/// every cell it references, so that it can't be used to copy a range the
+/// user isn't allowed to see. Its result can only spill into cells the user
+/// can write to, which is checked when the result is stored. Everything is
+/// allowed if the spreadsheet has no access control rules.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::acl::{check, Acl};
+/// # use rsheet::spreadsheet::Spreadsheet;
+/// let mut spreadsheet = Spreadsheet::new();
+/// spreadsheet.set_acl(Some(Acl::parse("allow alice write A1_A9").unwrap()));
+///
+/// assert!(check(&spreadsheet, Some("alice"), &["set", "A1", "A2 + 1"]).is_ok());
+/// assert!(check(&spreadsheet, Some("alice"), &["set", "A1", "sum(B1_B3)"]).is_err());
+/// ```
+pub fn check(spreadsheet: &Spreadsheet, user: Option<&str>, args: &[&str]) -> Result<(), Reply> {
+    let acl = match spreadsheet.acl() {
+        Some(acl) => acl,
+        None => return Ok(()),
+    };
+
+    let allowed = match args {
+        ["get", cell, ..] => acl.allows_range(user, cell, Access::Read),
+        ["clear", cell, ..] | ["lock", cell, ..] | ["unlock", cell, ..] => {
+            acl.allows_range(user, cell, Access::Write)
+        }
+        ["set", cell, expr @ ..] => {
+            let expr = expr.join(" ");
+            acl.allows_range(user, cell, Access::Write)
+                && spreadsheet
+                    .engine()
+                    .find_variables(&expr)
+                    .iter()
+                    .all(|var| acl.allows_range(user, var, Access::Read))
+        }
+        _ => true,
+    };
+
+    match allowed {
+        true => Ok(()),
+        false => Err(Reply::Error("permission denied".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::EngineKind;
+
+    const RULES: &str = "
+        # Finance owns the first three columns.
+        role finance alice bob
+        allow @finance write A1_C20
+        allow carol read A1_C20
+        allow carol write D1_D20
+        allow * read E1_E5
+    ";
+
+    #[test]
+    fn test_rules() {
+        let acl = Acl::parse(RULES).unwrap();
+
+        assert!(acl.allows(Some("alice"), "C20", Access::Write));
+        assert!(acl.allows(Some("bob"), "A1", Access::Read));
+        assert!(!acl.allows(Some("alice"), "D1", Access::Read));
+        assert!(acl.allows(Some("carol"), "B2", Access::Read));
+        assert!(!acl.allows(Some("carol"), "B2", Access::Write));
+        assert!(acl.allows(None, "E5", Access::Read));
+        assert!(!acl.allows(None, "E5", Access::Write));
+        assert!(acl.allows_range(Some("carol"), "A1_E2", Access::Read));
+        assert!(!acl.allows_range(Some("carol"), "A1_E6", Access::Read));
+    }
+
+    #[test]
+    fn test_ranges_covered_by_several_rules() {
+        let acl = Acl::parse(
+            "
+            allow alice write A1_B2
+            allow alice write C1_C2
+            allow alice read A3_C3
+            allow carol write A1_ZZZ99999
+            allow bob write B1_C1
+            allow bob write A2_B2
+            ",
+        )
+        .unwrap();
+
+        assert!(acl.allows_range(Some("alice"), "A1_C3", Access::Read));
+        assert!(!acl.allows_range(Some("alice"), "A1_C3", Access::Write));
+        assert!(acl.allows_range(Some("carol"), "A1_ZZZ99999", Access::Write));
+        assert!(!acl.allows_range(Some("carol"), "A1_ZZZ100000", Access::Write));
+        assert!(acl.allows_range(Some("bob"), "B1_B2", Access::Write));
+        assert!(!acl.allows_range(Some("bob"), "A1_C2", Access::Write));
+
+        // A range which can't be read, or is empty, doesn't grant anything.
+        assert!(!acl.allows_range(Some("carol"), "A1_A99999999999", Access::Read));
+        assert!(!acl.allows_range(Some("carol"), "B2_A1", Access::Read));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Acl::parse("allow alice delete A1").is_err());
+        assert!(Acl::parse("allow alice read a1").is_err());
+        assert!(Acl::parse("deny alice read A1").is_err());
+    }
+
+    #[test]
+    fn test_check_commands() {
+        let mut spreadsheet = Spreadsheet::with_engine(EngineKind::Native);
+        spreadsheet.set_acl(Some(Acl::parse(RULES).unwrap()));
+        let check = |user, args: &[&str]| check(&spreadsheet, Some(user), args).is_ok();
+
+        assert!(check("carol", &["get", "A1"]));
+        assert!(!check("carol", &["set", "A1", "1"]));
+        assert!(check("carol", &["set", "D1", "sum(A1_C20)"]));
+        assert!(!check("carol", &["clear", "A1"]));
+        assert!(!check("carol", &["lock", "A1_D1"]));
+
+        // Alice can't copy carol's column into a cell she owns.
+        assert!(!check("alice", &["get", "D1"]));
+        assert!(!check("alice", &["set", "A1", "sum(D1_D3)"]));
+        assert!(!check("alice", &["set", "A1", "D1", "+", "1"]));
+        assert!(check("alice", &["set", "A1", "E1", "+", "1"]));
+
+        // Commands which don't touch a cell are left alone.
+        assert!(check("dave", &["recalc"]));
+    }
+}
@@ -0,0 +1,472 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use rsheet_lib::replies::Reply;
+use serde::{Deserialize, Serialize};
+
+use crate::spreadsheet::Spreadsheet;
+use crate::utils::{is_valid_cell, ranges_overlap};
+use crate::value::Value;
+
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The writes made by the command being audited on this thread, or
+    /// `None` if no command is. A command runs on a single thread, so every
+    /// cell it writes is recorded here.
+    static CHANGES: RefCell<Option<Vec<Change>>> = const { RefCell::new(None) };
+}
+
+/// Where a command came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// Numbers each connection in the order they were made, as a peer
+    /// address can be reused.
+    pub connection: u64,
+    pub peer: Option<String>,
+    pub user: Option<String>,
+}
+
+impl Origin {
+    /// The origin of a new connection, which hasn't authenticated yet.
+    pub fn connect(peer: Option<String>) -> Self {
+        Self {
+            connection: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+            peer,
+            user: None,
+        }
+    }
+}
+
+/// The contents of a cell before or after a change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellState {
+    pub expr: Option<String>,
+    pub value: Value,
+}
+
+impl CellState {
+    fn of(spreadsheet: &Spreadsheet, cell: &str) -> Self {
+        Self {
+            expr: spreadsheet.get_cell_expr(cell),
+            value: spreadsheet.get_cell_val(cell),
+        }
+    }
+}
+
+/// A write to a cell made by a command being audited.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Change {
+    pub(crate) sequence: u64,
+    pub(crate) cell: String,
+    /// The array formula the cell was spilled from, before or after the
+    /// write.
+    pub(crate) spilled_from: Option<String>,
+    pub(crate) old: CellState,
+    pub(crate) new: CellState,
+}
+
+/// Checks if the writes made on this thread are being recorded, so that the
+/// spreadsheet only takes the states of a cell when they are needed.
+pub(crate) fn is_recording() -> bool {
+    CHANGES.with(|changes| changes.borrow().is_some())
+}
+
+/// Records a write made by the command being audited on this thread. The
+/// spreadsheet calls this while it still holds the cell, so that `old` and
+/// `new` are exactly the states either side of the write.
+pub(crate) fn record(change: Change) {
+    CHANGES.with(|changes| {
+        if let Some(changes) = changes.borrow_mut().as_mut() {
+            changes.push(change);
+        }
+    });
+}
+
+/// Runs a command, returning its result along with every write it made.
+fn recording<T>(run: impl FnOnce() -> T) -> (T, Vec<Change>) {
+    CHANGES.with(|changes| *changes.borrow_mut() = Some(Vec::new()));
+    let result = run();
+    let changes = CHANGES.with(|changes| changes.borrow_mut().take());
+    (result, changes.unwrap_or_default())
+}
+
+/// A single change to the spreadsheet. Locking and unlocking a range doesn't
+/// change the contents of any cell, so it has no `old` or `new` state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Orders the entries of a log by when their change was made, which
+    /// can differ from the order they were written in.
+    #[serde(default)]
+    pub sequence: u64,
+    /// When the change was made, in RFC 3339.
+    pub time: String,
+    /// The logical timestamp the command was run with.
+    pub timestamp: u64,
+    pub connection: u64,
+    pub peer: Option<String>,
+    pub user: Option<String>,
+    pub command: String,
+    pub cell: String,
+    /// The cell whose array formula the command set or cleared, if this is a
+    /// cell its result spilled into, or no longer spills into.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spilled_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old: Option<CellState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new: Option<CellState>,
+}
+
+impl fmt::Display for AuditEntry {
+    /// Shows an entry on one line, such as
+    /// `2024-01-31T12:00:00+00:00 #3 127.0.0.1:50000 alice set A1: 1 -> 2`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = |state: &Option<CellState>| match state {
+            Some(CellState {
+                expr: Some(expr), ..
+            }) => expr.clone(),
+            Some(CellState { value, .. }) => value.to_string(),
+            None => String::new(),
+        };
+
+        write!(
+            f,
+            "{} #{} {} {} {} {}",
+            self.time,
+            self.connection,
+            self.peer.as_deref().unwrap_or("-"),
+            self.user.as_deref().unwrap_or("-"),
+            self.command,
+            self.cell
+        )?;
+        if let Some(anchor) = &self.spilled_from {
+            write!(f, " (spilled from {})", anchor)?;
+        }
+        if self.old.is_some() || self.new.is_some() {
+            write!(f, ": {} -> {}", state(&self.old), state(&self.new))?;
+        }
+        Ok(())
+    }
+}
+
+/// An append-only log of every change made to the spreadsheet, with one JSON
+/// entry per line.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+    /// The sequence number of the next change.
+    sequence: AtomicU64,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, creating it if it doesn't exist. Existing
+    /// entries are kept, and the sequence numbers carry on from theirs.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let last = BufReader::new(&file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+            .map(|entry| entry.sequence)
+            .max()
+            .unwrap_or(0);
+
+        Ok(Self {
+            file: Mutex::new(file),
+            sequence: AtomicU64::new(last + 1),
+        })
+    }
+
+    /// Takes the sequence number of a change.
+    pub fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Appends an entry. The whole line is written at once so that entries
+    /// from concurrent commands don't interleave.
+    pub fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()
+    }
+}
+
+/// Runs a command which changes `cell`, or every cell in a range for `lock`
+/// and `unlock`, recording the change in the spreadsheet's audit log if the
+/// command succeeds. The cells an array formula in `cell` spills into, or no
+/// longer spills into, get entries of their own. Failing to write the log
+/// doesn't undo the change, but is reported on stderr.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::audit::{audited, Origin};
+/// # use rsheet::commands::set::set;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # let spreadsheet = new_shared_spreadsheet();
+/// let origin = Origin::connect(Some("127.0.0.1:5000".to_string()));
+/// audited(&spreadsheet, &origin, "set", "A1", 0, || {
+///     set(&spreadsheet, vec!["set", "A1", "5"], 0)
+/// })?;
+/// # Ok::<(), rsheet_lib::replies::Reply>(())
+/// ```
+pub fn audited(
+    spreadsheet: &Spreadsheet,
+    origin: &Origin,
+    command: &str,
+    cell: &str,
+    timestamp: u64,
+    run: impl FnOnce() -> Result<(), Reply>,
+) -> Result<(), Reply> {
+    let log = match spreadsheet.audit_log() {
+        Some(log) => log,
+        None => return run(),
+    };
+
+    let (result, changes) = recording(run);
+    result?;
+
+    let entry = |sequence, cell: &str, spilled_from, states: Option<(CellState, CellState)>| {
+        let (old, new) = states.unzip();
+        AuditEntry {
+            sequence,
+            time: chrono::Utc::now().to_rfc3339(),
+            timestamp,
+            connection: origin.connection,
+            peer: origin.peer.clone(),
+            user: origin.user.clone(),
+            command: command.to_string(),
+            cell: cell.to_string(),
+            spilled_from,
+            old,
+            new,
+        }
+    };
+
+    let mut entries = Vec::new();
+    if matches!(command, "set" | "clear") {
+        // A cell written more than once, such as an array formula that turns
+        // out to be blocked, gets one entry from its first state to its last.
+        let mut merged: Vec<Change> = Vec::new();
+        let ours = changes
+            .into_iter()
+            .filter(|change| change.cell == cell || change.spilled_from.as_deref() == Some(cell));
+        for change in ours {
+            match merged.iter_mut().find(|other| other.cell == change.cell) {
+                Some(other) => {
+                    other.sequence = change.sequence;
+                    other.new = change.new;
+                }
+                None => merged.push(change),
+            }
+        }
+
+        // A command which left its cell alone, such as one with an old
+        // timestamp, is still logged.
+        if !merged.iter().any(|change| change.cell == cell) {
+            let state = CellState::of(spreadsheet, cell);
+            entries.push(entry(
+                log.next_sequence(),
+                cell,
+                None,
+                Some((state.clone(), state)),
+            ));
+        }
+        for change in merged {
+            let spilled_from = change.spilled_from.filter(|_| change.cell != cell);
+            let states = Some((change.old, change.new));
+            entries.push(entry(change.sequence, &change.cell, spilled_from, states));
+        }
+    } else {
+        entries.push(entry(log.next_sequence(), cell, None, None));
+    }
+
+    for entry in entries {
+        if let Err(e) = log.append(&entry) {
+            eprintln!("Error writing to the audit log: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the entries of an audit log, keeping those for cells in `range` and
+/// made by `user`, if given. An entry for a range, such as a `lock`, is kept
+/// if it covers any cell of `range`.
+pub fn query(
+    path: impl AsRef<Path>,
+    range: Option<&str>,
+    user: Option<&str>,
+) -> io::Result<Vec<AuditEntry>> {
+    if range.is_some_and(|range| !is_valid_cell(range)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid range"));
+    }
+
+    let mut entries = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line {}: {}", number + 1, e),
+            )
+        })?;
+        let cell_matches = range.is_none_or(|range| ranges_overlap(&entry.cell, range));
+        let user_matches = user.is_none_or(|user| entry.user.as_deref() == Some(user));
+        if cell_matches && user_matches {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::commands::{clear::clear, set::set};
+    use crate::formula::EngineKind;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rsheet-audit-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_changes_are_logged() {
+        let path = log_path("changes");
+        let mut spreadsheet = Spreadsheet::with_engine(EngineKind::Native);
+        spreadsheet.set_audit_log(Some(Arc::new(AuditLog::open(&path).unwrap())));
+        let spreadsheet = Arc::new(spreadsheet);
+
+        let alice = Origin {
+            user: Some("alice".to_string()),
+            ..Origin::connect(Some("127.0.0.1:5000".to_string()))
+        };
+        let anonymous = Origin::connect(None);
+        let run = |origin: &Origin, args: Vec<&str>, timestamp| {
+            audited(
+                &spreadsheet,
+                origin,
+                args[0],
+                args[1],
+                timestamp,
+                || match args[0] {
+                    "clear" => clear(&spreadsheet, args[1], timestamp),
+                    _ => set(&spreadsheet, args.clone(), timestamp),
+                },
+            )
+        };
+
+        set(&spreadsheet, vec!["set", "B1", "5"], 0).unwrap();
+        run(&alice, vec!["set", "A1", "1"], 1).unwrap();
+        run(&anonymous, vec!["set", "A1", "B1 + 2"], 2).unwrap();
+        run(&alice, vec!["clear", "A1"], 3).unwrap();
+        // Failed commands don't change anything, so they aren't logged.
+        assert!(run(&alice, vec!["set", "a1", "1"], 4).is_err());
+
+        let entries = query(&path, None, None).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].timestamp, 2);
+        assert_eq!(entries[1].connection, anonymous.connection);
+        assert_eq!(
+            entries[1].old,
+            Some(CellState {
+                expr: None,
+                value: Value::Int(1)
+            })
+        );
+        assert_eq!(
+            entries[1].new,
+            Some(CellState {
+                expr: Some("B1 + 2".to_string()),
+                value: Value::Int(7)
+            })
+        );
+        assert!(entries[1].to_string().ends_with(&format!(
+            "#{} - - set A1: 1 -> B1 + 2",
+            anonymous.connection
+        )));
+
+        let by_alice = query(&path, Some("A1_B2"), Some("alice")).unwrap();
+        let commands: Vec<&str> = by_alice.iter().map(|e| e.command.as_str()).collect();
+        assert_eq!(commands, vec!["set", "clear"]);
+        assert_eq!(by_alice[0].peer.as_deref(), Some("127.0.0.1:5000"));
+        assert!(query(&path, Some("B1_B9"), None).unwrap().is_empty());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_spills_are_logged_in_sequence() {
+        let path = log_path("spills");
+        let mut spreadsheet = Spreadsheet::with_engine(EngineKind::Native);
+        spreadsheet.set_audit_log(Some(Arc::new(AuditLog::open(&path).unwrap())));
+        let spreadsheet = Arc::new(spreadsheet);
+        for (cell, value) in [("A1", "1"), ("A2", "2")] {
+            set(&spreadsheet, vec!["set", cell, value], 0).unwrap();
+        }
+
+        let origin = Origin::connect(None);
+        let run = |args: Vec<&str>| {
+            audited(&spreadsheet, &origin, args[0], args[1], 1, || {
+                match args[0] {
+                    "clear" => clear(&spreadsheet, args[1], 1),
+                    _ => set(&spreadsheet, args.clone(), 1),
+                }
+            })
+        };
+        run(vec!["set", "B1", "A1_A2 * 10"]).unwrap();
+        run(vec!["clear", "B1"]).unwrap();
+
+        let entries = query(&path, None, None).unwrap();
+        let cells: Vec<(&str, Option<&str>)> = entries
+            .iter()
+            .map(|e| (e.cell.as_str(), e.spilled_from.as_deref()))
+            .collect();
+        assert_eq!(
+            cells,
+            vec![
+                ("B1", None),
+                ("B2", Some("B1")),
+                ("B2", Some("B1")),
+                ("B1", None)
+            ]
+        );
+        assert_eq!(entries[1].new.as_ref().unwrap().value, Value::Int(20));
+        assert_eq!(entries[2].new.as_ref().unwrap().value, Value::None);
+        assert!(entries[1]
+            .to_string()
+            .ends_with("set B2 (spilled from B1): None -> 20"));
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].sequence < pair[1].sequence));
+
+        // A log opened again carries on from its last sequence number.
+        let log = AuditLog::open(&path).unwrap();
+        assert_eq!(log.next_sequence(), entries[3].sequence + 1);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// The users allowed to connect over the network, each with the SHA-256 hash
+/// of their token. Tokens themselves are never stored.
+///
+/// Credentials are loaded from a file with one user per line, followed by the
+/// hex encoded hash of their token. Blank lines and lines starting with `#`
+/// are ignored. The hash of a token is printed by `rsheet --hash-token`.
+///
+/// ```text
+/// # user   sha256(token)
+/// alice    2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Loads the credentials file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parses the contents of a credentials file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::auth::{hash_token, Credentials};
+    /// let credentials = Credentials::parse(&format!("alice {}", hash_token("secret"))).unwrap();
+    /// assert!(credentials.verify("alice", "secret"));
+    /// ```
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [user, hash] => (user, hash.to_ascii_lowercase()),
+                _ => return Err(format!("Line {}: expected a user and a hash", number + 1)),
+            };
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!(
+                    "Line {}: the hash for {} is not a SHA-256 hash",
+                    number + 1,
+                    user
+                ));
+            }
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(Self { users })
+    }
+
+    /// Checks a user's token against the hash stored for them.
+    pub fn verify(&self, user: &str, token: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => constant_time_eq(hash.as_bytes(), hash_token(token).as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// Hashes a token in the form stored in the credentials file.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::auth::hash_token;
+/// assert_eq!(
+///     hash_token("secret"),
+///     "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+/// );
+/// ```
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Handles the arguments of an `auth <user> <token>` command, returning the
+/// user if they are who they say they are. Without credentials there is no
+/// one to authenticate as.
+pub fn authenticate(credentials: Option<&Credentials>, args: &[&str]) -> Result<String, String> {
+    let credentials = credentials.ok_or("Authentication is not enabled")?;
+    match args {
+        [user, token] if credentials.verify(user, token) => Ok(user.to_string()),
+        [_, _] => Err("Authentication failed".to_string()),
+        _ => Err("Invalid number of arguments supplied for auth".to_string()),
+    }
+}
+
+/// Compares two hashes without stopping at the first difference, so that the
+/// time taken doesn't reveal how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_verify() {
+        let text = format!(
+            "# users\n\nalice {}\nbob {}\n",
+            hash_token("secret"),
+            hash_token("hunter2").to_uppercase()
+        );
+        let credentials = Credentials::parse(&text).unwrap();
+
+        assert_eq!(
+            hash_token("secret"),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+        assert!(credentials.verify("alice", "secret"));
+        assert!(credentials.verify("bob", "hunter2"));
+        assert!(!credentials.verify("alice", "hunter2"));
+        assert!(!credentials.verify("carol", "secret"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Credentials::parse("alice").is_err());
+        assert!(Credentials::parse("alice secret").is_err());
+        assert!(Credentials::parse(&format!("alice {} extra", hash_token("x"))).is_err());
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let credentials = Credentials::parse(&format!("alice {}", hash_token("secret"))).unwrap();
+
+        assert_eq!(
+            authenticate(Some(&credentials), &["alice", "secret"]),
+            Ok("alice".to_string())
+        );
+        assert!(authenticate(Some(&credentials), &["alice", "wrong"]).is_err());
+        assert!(authenticate(Some(&credentials), &["alice"]).is_err());
+        assert!(authenticate(None, &["alice", "secret"]).is_err());
+    }
+}
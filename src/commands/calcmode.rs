@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use rsheet_lib::replies::Reply;
+
+use crate::{
+    commands::recalc::recalc,
+    spreadsheet::{CalcMode, Spreadsheet},
+};
+
+/// Switches the spreadsheet between automatic, manual and lazy calculation.
+/// In manual mode, `set` only stores formulas and marks the cells depending
+/// on them as dirty, leaving `recalc` to bring them up to date. Lazy mode
+/// marks cells the same way, but evaluates them when they are read.
+/// Switching back to automatic mode recalculates every dirty cell.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::calcmode::calcmode;
+/// # use rsheet::spreadsheet::{new_shared_spreadsheet, CalcMode};
+/// let spreadsheet = new_shared_spreadsheet();
+/// calcmode(&spreadsheet, vec!["calcmode", "manual"], 0).unwrap();
+/// assert_eq!(spreadsheet.calc_mode(), CalcMode::Manual);
+/// ```
+pub fn calcmode(
+    spreadsheet: &Arc<Spreadsheet>,
+    args: Vec<&str>,
+    timestamp: u64,
+) -> Result<(), Reply> {
+    let mode = match args[..] {
+        [_, name] => CalcMode::from_name(name)
+            .ok_or_else(|| Reply::Error("Invalid calculation mode".to_string()))?,
+        _ => {
+            return Err(Reply::Error(
+                "Invalid number of arguments supplied for calcmode".to_string(),
+            ))
+        }
+    };
+
+    spreadsheet.set_calc_mode(mode);
+    match mode {
+        CalcMode::Auto => recalc(spreadsheet, vec!["recalc"], timestamp),
+        CalcMode::Manual | CalcMode::Lazy => Ok(()),
+    }
+}
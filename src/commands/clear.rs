@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use rsheet_lib::replies::Reply;
+
+use crate::{
+    commands::{
+        dependencies::{remove_all_dependencies, update_dependency},
+        lock::check_unlocked,
+        spill::{respill, store_result},
+    },
+    spreadsheet::Spreadsheet,
+    utils::is_valid_cell,
+    value::{Argument, Value},
+};
+
+/// Clears a cell in the spreadsheet, removing its expression and any
+/// dependencies on other cells. Cells that depend on the cleared cell are
+/// recalculated. A locked cell can't be cleared.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::{clear::clear, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
+/// set(&spreadsheet, vec!["set", "A1", "5"], 0).unwrap();
+///
+/// clear(&spreadsheet, "A1", 0).unwrap();
+/// assert_eq!(spreadsheet.get_cell_val("A1"), Value::None);
+/// ```
+pub fn clear(spreadsheet: &Arc<Spreadsheet>, cell: &str, timestamp: u64) -> Result<(), Reply> {
+    if !is_valid_cell(cell) {
+        return Err(Reply::Error("Invalid cell provided.".to_string()));
+    }
+    check_unlocked(spreadsheet, cell)?;
+
+    remove_all_dependencies(spreadsheet, cell, &String::new());
+    spreadsheet.set_volatile(cell, false);
+    spreadsheet.mark_clean(cell);
+    // Storing an empty value also clears anything the cell spilled into.
+    let empty = Argument::Value(Value::None);
+    store_result(spreadsheet, cell, empty, None, &[], timestamp)?;
+
+    update_dependency(spreadsheet, cell, &mut Vec::new(), timestamp)?;
+    respill(spreadsheet, cell, timestamp)
+}
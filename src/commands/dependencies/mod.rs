@@ -1,12 +1,16 @@
+use std::collections::{HashSet, VecDeque};
 use std::sync::Arc;
 
 use rsheet_lib::{
     cells::{column_name_to_number, column_number_to_name},
-    command_runner::{CellValue, CommandRunner},
     replies::Reply,
 };
 
-use crate::{commands::variables::variable_map_for_runner, spreadsheet::Spreadsheet};
+use crate::{
+    commands::{spill::store_result, variables::variable_map_for_runner},
+    spreadsheet::Spreadsheet,
+    value::{CellError, ErrorKind, Value},
+};
 
 /// Add the current cell as a dependency to all cells in the given range. Acts
 /// as a wrapper around `spreadsheet`'s `add_dependency` method for multiple
@@ -14,16 +18,15 @@ use crate::{commands::variables::variable_map_for_runner, spreadsheet::Spreadshe
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::dependencies::add_as_dependent;
-///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
+/// ```
+/// # use rsheet::commands::dependencies::add_dependencies;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// let spreadsheet = new_shared_spreadsheet();
 /// add_dependencies(&spreadsheet, "A1", "A", "2", "A", "4");
 ///
-/// let dependencies = spreadsheet.get_dependencies("A1").unwrap();
-/// assert_eq!(dependencies, vec!["A2".to_string(), "A3".to_string(), "A4".to_string()]);
+/// for cell in ["A2", "A3", "A4"] {
+///     assert_eq!(spreadsheet.get_dependencies(cell), Some(vec!["A1".to_string()]));
+/// }
 /// ```
 pub fn add_dependencies(
     spreadsheet: &Arc<Spreadsheet>,
@@ -54,24 +57,22 @@ pub fn add_dependencies(
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::dependencies::remove_all_dependencies;
-///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
-/// spreadsheet.set_cell("A1", CellValue::Int(10), Some("A2 + 10".to_string()));
+/// ```
+/// # use rsheet::commands::{dependencies::remove_all_dependencies, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// let spreadsheet = new_shared_spreadsheet();
+/// set(&spreadsheet, vec!["set", "A1", "A2 + 10"], 0).unwrap();
 /// assert_eq!(spreadsheet.get_dependencies("A2"), Some(vec!["A1".to_string()]));
 ///
-/// remove_all_dependencies(&spreadsheet, "A1", &"A2 + 10".to_string());
-/// assert_eq!(spreadsheet.get_dependencies("A2"), None);
+/// remove_all_dependencies(&spreadsheet, "A1", &"5".to_string());
+/// assert_eq!(spreadsheet.get_dependencies("A2"), Some(vec![]));
 /// ```
 pub fn remove_all_dependencies(spreadsheet: &Arc<Spreadsheet>, cell: &str, new_expr: &String) {
     let old_expr = spreadsheet.get_cell_expr(cell);
 
     if let Some(old_expr) = old_expr {
         if old_expr != *new_expr {
-            let old_vars = CommandRunner::new(&old_expr).find_variables();
+            let old_vars = spreadsheet.engine().find_variables(&old_expr);
             old_vars.into_iter().for_each(|var| {
                 // This isn't actually comparing the old variables and the
                 // new variables, its just removing all variables from the old
@@ -86,22 +87,20 @@ pub fn remove_all_dependencies(spreadsheet: &Arc<Spreadsheet>, cell: &str, new_e
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::dependencies::update_dependency;
-/// use rsheet_lib::cells::CellValue;
-///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
-/// spreadsheet.set_cell("A1", CellValue::Int(10), None);
-/// spreadsheet.set_cell("A2", CellValue::Int(20), Some("A1 + 10".to_string()));
-/// spreadsheet.set_cell("A3", CellValue::Int(30), Some("A2 + 10".to_string()));
+/// ```
+/// # use rsheet::commands::{dependencies::update_dependency, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
+/// set(&spreadsheet, vec!["set", "A1", "10"], 0).unwrap();
+/// set(&spreadsheet, vec!["set", "A2", "A1 + 10"], 0).unwrap();
+/// set(&spreadsheet, vec!["set", "A3", "A2 + 10"], 0).unwrap();
 ///
-/// update_dependency(&spreadsheet, "A1", 0);
+/// spreadsheet.set_cell("A1", Value::Int(0), None, 1);
+/// update_dependency(&spreadsheet, "A1", &mut Vec::new(), 1).unwrap();
 ///
-/// assert_eq!(spreadsheet.get_cell_val("A1"), CellValue::Int(0));
-/// assert_eq!(spreadsheet.get_cell_val("A2"), CellValue::Int(10));
-/// assert_eq!(spreadsheet.get_cell_val("A3"), CellValue::Int(20));
+/// assert_eq!(spreadsheet.get_cell_val("A2"), Value::Int(10));
+/// assert_eq!(spreadsheet.get_cell_val("A3"), Value::Int(20));
 /// ```
 pub fn update_dependency(
     spreadsheet: &Arc<Spreadsheet>,
@@ -116,6 +115,23 @@ pub fn update_dependency(
         return Ok(());
     }
 
+    // In manual and lazy calculation modes the dependents are only marked as
+    // out of date, to be recalculated later.
+    if spreadsheet.calc_mode().defers() {
+        mark_dependents_dirty(spreadsheet, parent);
+        return Ok(());
+    }
+
+    // A long chain of dependencies would recurse too deeply, so this branch
+    // of the recalculation is cut off at the limit. The other branches are
+    // still recalculated.
+    let limits = spreadsheet.limits();
+    if path.len() >= limits.max_depth {
+        let message = format!("Recalculation is more than {} cells deep", limits.max_depth);
+        set_calc_error(spreadsheet, parent, message, timestamp);
+        return Ok(());
+    }
+
     // Add the parent to the path to keep track of the cells that has been
     // visited in this call.
     path.push(parent.to_string());
@@ -123,6 +139,21 @@ pub fn update_dependency(
     // Get the dependencies of the parent cell. If there are no dependencies,
     // then it defaults to an empty vec.
     let dependencies = spreadsheet.get_dependencies(parent).unwrap_or_default();
+    if dependencies.len() > limits.max_fan_out {
+        // Too many cells depend on the parent to evaluate them all, so they
+        // are set to an error instead, without following their dependents.
+        for dep in &dependencies {
+            let message = format!(
+                "{} has {} dependents, more than the limit of {}",
+                parent,
+                dependencies.len(),
+                limits.max_fan_out
+            );
+            set_calc_error(spreadsheet, dep, message, timestamp);
+        }
+        path.pop();
+        return Ok(());
+    }
 
     // Update these dependencies with the new values.
     for dep in dependencies {
@@ -135,17 +166,16 @@ pub fn update_dependency(
                 continue;
             }
         };
-        let runner = CommandRunner::new(&expr);
-        let vars = runner.find_variables();
+        let engine = spreadsheet.engine();
+        let vars = engine.find_variables(&expr);
         let var_map = variable_map_for_runner(spreadsheet, &vars);
-        let cell_val = runner.run(&var_map);
+        let result = engine.evaluate_array(&expr, &var_map);
 
         // If there aren't any variables, then its a scalar value and we set
         // the cell value directly. Otherwise, we need to store the expression.
-        match vars.is_empty() {
-            true => spreadsheet.set_cell(&dep, cell_val, None, timestamp),
-            false => spreadsheet.set_cell(&dep, cell_val, Some(expr), timestamp),
-        }
+        // An array result also recalculates the cells it spills into.
+        let expr = (!vars.is_empty()).then_some(expr);
+        store_result(spreadsheet, &dep, result, expr, path, timestamp)?;
 
         // Each dependency could have its own set of dependencies, so we need
         // to update those as well.
@@ -158,24 +188,70 @@ pub fn update_dependency(
     Ok(())
 }
 
-/// Handles updating dependencies with the circular dependency error.
+/// Gets the cells which have to be recalculated after a cell changes: the
+/// cells depending on it, and if it holds an array formula, the cells
+/// depending on the cells it spills into.
+pub fn dependents(spreadsheet: &Spreadsheet, cell: &str) -> Vec<String> {
+    let mut dependents = spreadsheet.get_dependencies(cell).unwrap_or_default();
+    for spilled in spreadsheet.get_spill(cell) {
+        if spilled != cell {
+            dependents.extend(spreadsheet.get_dependencies(&spilled).unwrap_or_default());
+        }
+    }
+
+    dependents.sort();
+    dependents.dedup();
+    dependents
+}
+
+/// Marks every cell that depends on a changed cell, directly or through other
+/// cells, as dirty. Array formulas which spill, or are blocked from spilling,
+/// into the cell are marked too.
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::dependencies::handle_circular_dependency;
-/// use rsheet_lib::cells::CellValue;
+/// ```
+/// # use rsheet::commands::dependencies::mark_dependents_dirty;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// let spreadsheet = new_shared_spreadsheet();
+/// spreadsheet.add_dependency("A1", "B1");
+/// spreadsheet.add_dependency("B1", "C1");
 ///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
-/// handle_circular_dependency(&spreadsheet, "A1", 0);
-/// assert_eq!(spreadsheet.get_cell_val("A1"), CellValue::Error("Cell A1 is self-referential".to_string()));
+/// mark_dependents_dirty(&spreadsheet, "A1");
+/// assert!(spreadsheet.is_stale("B1") && spreadsheet.is_stale("C1"));
+/// assert!(!spreadsheet.is_stale("A1"));
 /// ```
+pub fn mark_dependents_dirty(spreadsheet: &Spreadsheet, cell: &str) {
+    let mut queue: VecDeque<String> = dependents(spreadsheet, cell).into();
+    queue.extend(spreadsheet.get_spills_over(cell));
+
+    // Cells already seen are skipped so that a cycle doesn't loop forever.
+    let mut seen = HashSet::new();
+    while let Some(dep) = queue.pop_front() {
+        if seen.insert(dep.clone()) {
+            queue.extend(dependents(spreadsheet, &dep));
+            spreadsheet.mark_dirty(&dep);
+        }
+    }
+}
+
+/// Sets a cell whose recalculation was cut off by a limit to an error. The
+/// cell keeps its formula, so it is evaluated again by the next recalculation
+/// that reaches it.
+fn set_calc_error(spreadsheet: &Spreadsheet, cell: &str, message: String, timestamp: u64) {
+    let error = CellError::new(ErrorKind::Calc, message);
+    let expr = spreadsheet.get_cell_expr(cell);
+    spreadsheet.set_cell(cell, Value::Error(error), expr, timestamp);
+}
+
+/// Handles updating dependencies with the circular dependency error.
 fn handle_circular_dependency(spreadsheet: &Arc<Spreadsheet>, parent: &str, timestamp: u64) {
     spreadsheet.set_cell(
         parent,
-        CellValue::Error(format!("Cell {} is self-referential", parent)),
+        Value::Error(CellError::new(
+            ErrorKind::Cycle,
+            format!("Cell {} is self-referential", parent),
+        )),
         Some("Circular Dependency".to_string()),
         timestamp,
     );
@@ -188,9 +264,204 @@ fn handle_circular_dependency(spreadsheet: &Arc<Spreadsheet>, parent: &str, time
     for dep in dependencies {
         spreadsheet.set_cell(
             &dep,
-            CellValue::Error(format!("Cell {} is involved in a circular dependency", dep)),
+            Value::Error(CellError::new(
+                ErrorKind::Cycle,
+                format!("Cell {} is involved in a circular dependency", dep),
+            )),
             Some("Circular Dependency".to_string()),
             timestamp,
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::set::set;
+    use crate::formula::EngineKind;
+    use crate::limits::Limits;
+
+    fn spreadsheet(limits: Limits) -> Arc<Spreadsheet> {
+        Arc::new(Spreadsheet::with_limits(EngineKind::Native, limits))
+    }
+
+    fn set_cell(spreadsheet: &Arc<Spreadsheet>, cell: &str, expr: &str) -> Result<(), Reply> {
+        set(spreadsheet, vec!["set", cell, expr], 0)
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let spreadsheet = spreadsheet(Limits {
+            max_depth: 5,
+            ..Limits::default()
+        });
+        set_cell(&spreadsheet, "A1", "1").unwrap();
+        for row in 2..=8 {
+            let expr = format!("A{} + 1", row - 1);
+            set_cell(&spreadsheet, &format!("A{}", row), &expr).unwrap();
+        }
+
+        assert!(set_cell(&spreadsheet, "A1", "10").is_ok());
+        assert_eq!(spreadsheet.get_cell_val("A5"), Value::Int(14));
+        assert!(matches!(spreadsheet.get_cell_val("A6"), Value::Error(_)));
+
+        // The cell keeps its formula, so it recovers once the chain is short
+        // enough.
+        set_cell(&spreadsheet, "A5", "0").unwrap();
+        assert_eq!(spreadsheet.get_cell_val("A8"), Value::Int(3));
+    }
+
+    #[test]
+    fn test_default_depth_is_reached_without_overflowing() {
+        let spreadsheet = spreadsheet(Limits::default());
+        let depth = spreadsheet.limits().max_depth;
+
+        set_cell(&spreadsheet, "A1", "1").unwrap();
+        for row in 2..=depth + 10 {
+            let expr = format!("A{} + 1", row - 1);
+            set_cell(&spreadsheet, &format!("A{}", row), &expr).unwrap();
+        }
+        assert!(set_cell(&spreadsheet, "A1", "2").is_ok());
+        let last = format!("A{}", depth + 1);
+        assert!(matches!(spreadsheet.get_cell_val(&last), Value::Error(_)));
+    }
+
+    #[test]
+    fn test_max_fan_out() {
+        let spreadsheet = spreadsheet(Limits {
+            max_fan_out: 2,
+            ..Limits::default()
+        });
+        set_cell(&spreadsheet, "A1", "1").unwrap();
+        set_cell(&spreadsheet, "B1", "A1").unwrap();
+        set_cell(&spreadsheet, "B2", "A1").unwrap();
+        assert!(set_cell(&spreadsheet, "A1", "2").is_ok());
+
+        set_cell(&spreadsheet, "B3", "A1").unwrap();
+        assert!(set_cell(&spreadsheet, "A1", "3").is_ok());
+        for cell in ["B1", "B2", "B3"] {
+            assert!(matches!(spreadsheet.get_cell_val(cell), Value::Error(_)));
+        }
+
+        // The dependents keep their formulas, so they recover once there are
+        // few enough of them.
+        set_cell(&spreadsheet, "B3", "0").unwrap();
+        set_cell(&spreadsheet, "A1", "4").unwrap();
+        assert_eq!(spreadsheet.get_cell_val("B1"), Value::Int(4));
+    }
+
+    #[test]
+    fn test_limits_only_cut_off_their_own_branch() {
+        let spreadsheet = spreadsheet(Limits {
+            max_depth: 3,
+            max_fan_out: 2,
+            ..Limits::default()
+        });
+        set_cell(&spreadsheet, "A1", "1").unwrap();
+
+        // B1 starts a chain which is too deep, C1 one which is short enough.
+        for row in 1..=5 {
+            let parent = if row == 1 {
+                "A1".to_string()
+            } else {
+                format!("B{}", row - 1)
+            };
+            set_cell(
+                &spreadsheet,
+                &format!("B{}", row),
+                &format!("{} + 1", parent),
+            )
+            .unwrap();
+        }
+        set_cell(&spreadsheet, "C1", "A1 * 10").unwrap();
+        set_cell(&spreadsheet, "C2", "C1 + 1").unwrap();
+
+        // D1 is recalculated alongside C2, but has too many dependents.
+        set_cell(&spreadsheet, "D1", "C1").unwrap();
+        for cell in ["D2", "D3", "D4"] {
+            set_cell(&spreadsheet, cell, "D1").unwrap();
+        }
+
+        set_cell(&spreadsheet, "A1", "2").unwrap();
+        assert!(matches!(spreadsheet.get_cell_val("B3"), Value::Error(_)));
+        assert!(matches!(spreadsheet.get_cell_val("D2"), Value::Error(_)));
+        assert_eq!(spreadsheet.get_cell_val("B2"), Value::Int(4));
+        assert_eq!(spreadsheet.get_cell_val("C2"), Value::Int(21));
+        assert_eq!(spreadsheet.get_cell_val("D1"), Value::Int(20));
+    }
+
+    #[test]
+    fn test_max_formula_len() {
+        let spreadsheet = spreadsheet(Limits {
+            max_formula_len: 7,
+            ..Limits::default()
+        });
+        assert!(set_cell(&spreadsheet, "A1", "1 + 2 + 3").is_err());
+        assert!(set_cell(&spreadsheet, "A1", "1 + 2").is_ok());
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(3));
+    }
+
+    #[test]
+    fn test_max_range_cells() {
+        let spreadsheet = spreadsheet(Limits {
+            max_range_cells: 6,
+            ..Limits::default()
+        });
+        set_cell(&spreadsheet, "A1", "1").unwrap();
+
+        // A huge range is turned away without making any dependencies, and
+        // without touching the cell's old formula.
+        assert_eq!(
+            set_cell(&spreadsheet, "C1", "sum(A1_ZZZ999999)"),
+            Err(Reply::Error(
+                "Range A1_ZZZ999999 has more than 6 cells".to_string()
+            ))
+        );
+        assert!(set_cell(&spreadsheet, "C1", "A1 + sum(A1_B4)").is_err());
+        assert_eq!(spreadsheet.get_dependencies("A1"), None);
+
+        set_cell(&spreadsheet, "C1", "sum(A1_B3)").unwrap();
+        set_cell(&spreadsheet, "A1", "5").unwrap();
+        assert_eq!(spreadsheet.get_cell_val("C1"), Value::Int(5));
+    }
+
+    #[test]
+    fn test_handle_circular_dependency() {
+        let spreadsheet = spreadsheet(Limits::default());
+        spreadsheet.add_dependency("A1", "B1");
+        handle_circular_dependency(&spreadsheet, "A1", 0);
+
+        assert_eq!(
+            spreadsheet.get_cell_val("A1"),
+            Value::Error(CellError::new(
+                ErrorKind::Cycle,
+                "Cell A1 is self-referential".to_string()
+            ))
+        );
+        assert_eq!(
+            spreadsheet.get_cell_val("B1"),
+            Value::Error(CellError::new(
+                ErrorKind::Cycle,
+                "Cell B1 is involved in a circular dependency".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_cycle_error() {
+        let spreadsheet = spreadsheet(Limits::default());
+        set_cell(&spreadsheet, "B1", "1").unwrap();
+        set_cell(&spreadsheet, "A1", "B1 + 1").unwrap();
+        set_cell(&spreadsheet, "B1", "A1 + 1").unwrap();
+
+        for cell in ["A1", "B1"] {
+            assert!(matches!(
+                spreadsheet.get_cell_val(cell),
+                Value::Error(CellError {
+                    kind: ErrorKind::Cycle,
+                    ..
+                })
+            ));
+        }
+    }
+}
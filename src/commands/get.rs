@@ -1,29 +1,33 @@
 use std::sync::Arc;
 
-use rsheet_lib::{command_runner::CellValue, replies::Reply};
+use rsheet_lib::replies::Reply;
 
-use crate::{spreadsheet::Spreadsheet, utils::is_valid_cell};
+use crate::{
+    commands::recalc::evaluate_stale, spreadsheet::Spreadsheet, utils::is_valid_cell, value::Value,
+};
 
-/// Gets the value of a cell in the spreadsheet.
+use super::variables::{categorize_variable, create_cell_matrix, VariableType};
+
+/// Gets the value of a cell in the spreadsheet. In lazy calculation mode an
+/// out of date cell is evaluated first.
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::command_runner::CellValue;
-/// use rsheet_server::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::get::get;
-///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
+/// ```
+/// # use rsheet::commands::get::get;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
 ///
-/// let (cell, cell_val) = get(&spreadsheet, vec!["get", "A1"]).unwrap();
+/// let (cell, cell_val) = get(&spreadsheet, vec!["get", "A1"], 0).unwrap();
 /// assert_eq!(cell, "A1");
-/// assert_eq!(cell_val, CellValue::None);
+/// assert_eq!(cell_val, Value::None);
 /// ```
 pub fn get(
     spreadsheet: &Arc<Spreadsheet>,
     args: Vec<&str>,
-) -> Result<(String, CellValue), (String, Reply)> {
+    timestamp: u64,
+) -> Result<(String, Value), (String, Reply)> {
     // Check that number of arguments is correct
     if args.len() < 2 {
         return Err((
@@ -39,6 +43,8 @@ pub fn get(
         return Err((cell.to_string(), Reply::Error("Invalid cell".to_string())));
     }
 
+    evaluate_stale(spreadsheet, cell, timestamp).map_err(|e| (cell.to_string(), e))?;
+
     let cell_val = spreadsheet.get_cell_val(cell);
     let cell_expr = spreadsheet.get_cell_expr(cell);
 
@@ -51,11 +57,55 @@ pub fn get(
                 Reply::Error(format!("A dependent cell contained an error: {}", cell_val)),
             ));
         } else if s == "Circular Dependency" {
-            if let CellValue::Error(s) = cell_val {
-                return Err((cell.to_string(), Reply::Error(s)));
+            if let Value::Error(s) = cell_val {
+                return Err((cell.to_string(), Reply::Error(s.to_string())));
             }
         }
     }
 
     Ok((cell.to_string(), cell_val))
 }
+
+/// Gets the values of every cell in a range, such as `A1_C3`, row by row. A
+/// single cell is treated as a range with one row and one column. In lazy
+/// calculation mode out of date cells are evaluated first.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::{get::get_range, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
+/// set(&spreadsheet, vec!["set", "B1", "5"], 0).unwrap();
+///
+/// let values = get_range(&spreadsheet, "A1_B2", 0).unwrap();
+/// assert_eq!(values[0], vec![Value::None, Value::Int(5)]);
+/// assert_eq!(values[1], vec![Value::None, Value::None]);
+/// ```
+pub fn get_range(
+    spreadsheet: &Arc<Spreadsheet>,
+    range: &str,
+    timestamp: u64,
+) -> Result<Vec<Vec<Value>>, Reply> {
+    if !is_valid_cell(range) {
+        return Err(Reply::Error("Invalid range".to_string()));
+    }
+
+    evaluate_stale(spreadsheet, range, timestamp)?;
+
+    let values = match categorize_variable(range) {
+        VariableType::Scalar => vec![vec![spreadsheet.get_cell_val(range)]],
+        VariableType::VerticalVector(col, start_row, end_row) => {
+            create_cell_matrix(start_row, end_row, col, col, spreadsheet)
+        }
+        VariableType::HorizontalVector(row, start_col, end_col) => {
+            create_cell_matrix(row, row, start_col, end_col, spreadsheet)
+        }
+        VariableType::Matrix((start_col, start_row), (end_col, end_row)) => {
+            create_cell_matrix(start_row, end_row, start_col, end_col, spreadsheet)
+        }
+    };
+
+    Ok(values)
+}
@@ -0,0 +1,137 @@
+use std::sync::Arc;
+
+use rsheet_lib::replies::Reply;
+
+use crate::{spreadsheet::Spreadsheet, utils::is_valid_cell};
+
+/// Locks every cell in a range, so that `set` and `clear` fail on them until
+/// they are unlocked. A locked cell holding a formula is still recalculated
+/// when the cells it depends on change, and an array formula can't spill
+/// into a locked cell.
+///
+/// Locks are kept in memory with the cells, so neither survives a restart of
+/// the server.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::{lock::lock, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// let spreadsheet = new_shared_spreadsheet();
+/// lock(&spreadsheet, vec!["lock", "A1_A3"]).unwrap();
+/// assert!(set(&spreadsheet, vec!["set", "A2", "5"], 0).is_err());
+/// ```
+pub fn lock(spreadsheet: &Arc<Spreadsheet>, args: Vec<&str>) -> Result<(), Reply> {
+    set_locked(spreadsheet, args, true)
+}
+
+/// Unlocks every cell in a range. Cells of a locked range outside of it stay
+/// locked.
+pub fn unlock(spreadsheet: &Arc<Spreadsheet>, args: Vec<&str>) -> Result<(), Reply> {
+    set_locked(spreadsheet, args, false)
+}
+
+/// Fails if a cell is locked, for commands which change the contents of the
+/// cell.
+pub fn check_unlocked(spreadsheet: &Spreadsheet, cell: &str) -> Result<(), Reply> {
+    match spreadsheet.is_locked(cell) {
+        true => Err(Reply::Error(format!("Cell {} is locked", cell))),
+        false => Ok(()),
+    }
+}
+
+fn set_locked(spreadsheet: &Spreadsheet, args: Vec<&str>, locked: bool) -> Result<(), Reply> {
+    let range = match args[..] {
+        [_, range] => range,
+        _ => {
+            return Err(Reply::Error(format!(
+                "Invalid number of arguments supplied for {}",
+                args[0]
+            )))
+        }
+    };
+
+    if !is_valid_cell(range) {
+        return Err(Reply::Error("Invalid range provided.".to_string()));
+    }
+
+    spreadsheet.set_locked(range, locked);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{clear::clear, set::set};
+    use crate::formula::EngineKind;
+    use crate::value::Value;
+
+    fn spreadsheet() -> Arc<Spreadsheet> {
+        Arc::new(Spreadsheet::with_engine(EngineKind::Native))
+    }
+
+    #[test]
+    fn test_locked_cells_are_read_only() {
+        let spreadsheet = spreadsheet();
+        set(&spreadsheet, vec!["set", "A1", "0.2"], 0).unwrap();
+        set(&spreadsheet, vec!["set", "B1", "A1 * 100"], 1).unwrap();
+        lock(&spreadsheet, vec!["lock", "A1_A2"]).unwrap();
+
+        let error = Reply::Error("Cell A1 is locked".to_string());
+        assert_eq!(
+            set(&spreadsheet, vec!["set", "A1", "0.3"], 2),
+            Err(error.clone())
+        );
+        assert_eq!(clear(&spreadsheet, "A1", 2), Err(error));
+        assert!(set(&spreadsheet, vec!["set", "A2", "1"], 2).is_err());
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Float(0.2));
+
+        assert_eq!(spreadsheet.get_locked_ranges(), vec!["A1_A2".to_string()]);
+
+        unlock(&spreadsheet, vec!["unlock", "A1"]).unwrap();
+        set(&spreadsheet, vec!["set", "A1", "0.3"], 3).unwrap();
+        assert_eq!(spreadsheet.get_cell_val("B1"), Value::Float(30.0));
+        assert!(spreadsheet.is_locked("A2"));
+    }
+
+    #[test]
+    fn test_locked_cells_block_spills() {
+        let spreadsheet = spreadsheet();
+        for (cell, value) in [("B1", "1"), ("B2", "2"), ("B3", "3")] {
+            set(&spreadsheet, vec!["set", cell, value], 0).unwrap();
+        }
+        lock(&spreadsheet, vec!["lock", "A2"]).unwrap();
+        set(&spreadsheet, vec!["set", "A1", "B1_B3", "*", "10"], 0).unwrap();
+
+        assert!(matches!(spreadsheet.get_cell_val("A1"), Value::Error(_)));
+        assert_eq!(spreadsheet.get_cell_val("A2"), Value::None);
+    }
+
+    #[test]
+    fn test_lock_huge_range() {
+        let spreadsheet = spreadsheet();
+        lock(&spreadsheet, vec!["lock", "A1_ZZZ999999"]).unwrap();
+        unlock(&spreadsheet, vec!["unlock", "B2_C3"]).unwrap();
+
+        assert!(spreadsheet.is_locked("A1") && spreadsheet.is_locked("ZZZ999999"));
+        assert!(spreadsheet.is_locked("B4") && spreadsheet.is_locked("D2"));
+        assert!(!spreadsheet.is_locked("B2") && !spreadsheet.is_locked("C3"));
+        assert!(set(&spreadsheet, vec!["set", "C2", "1"], 0).is_ok());
+        assert_eq!(spreadsheet.get_locked_ranges().len(), 4);
+
+        // Locking it again doesn't overlap what is already locked.
+        lock(&spreadsheet, vec!["lock", "A1_ZZZ999999"]).unwrap();
+        assert_eq!(
+            spreadsheet.get_locked_ranges(),
+            vec!["A1_ZZZ999999".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_lock_invalid() {
+        let spreadsheet = spreadsheet();
+        assert!(lock(&spreadsheet, vec!["lock"]).is_err());
+        assert!(lock(&spreadsheet, vec!["lock", "a1"]).is_err());
+        assert!(unlock(&spreadsheet, vec!["unlock", "A1", "A2"]).is_err());
+    }
+}
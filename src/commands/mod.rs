@@ -1,4 +1,9 @@
-mod dependencies;
+pub mod calcmode;
+pub mod clear;
+pub mod dependencies;
 pub mod get;
+pub mod lock;
+pub mod recalc;
 pub mod set;
-mod variables;
+pub mod spill;
+pub mod variables;
@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use rsheet_lib::replies::Reply;
+
+use crate::{
+    commands::{
+        dependencies::{dependents, mark_dependents_dirty},
+        spill::store_result,
+        variables::variable_map_for_runner,
+    },
+    spreadsheet::{CalcMode, Spreadsheet},
+    utils::{cell_in_range, is_valid_cell, range_cells},
+    value::{CellError, ErrorKind, Value},
+};
+
+/// Evaluates formulas again, along with every cell that depends on them. With
+/// no range, every cell that is out of date is recalculated: cells marked as
+/// dirty in manual calculation mode, and cells calling volatile functions
+/// such as `NOW()`. Otherwise every formula inside the range, which can also
+/// be a single cell, is recalculated.
+///
+/// # Example
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use rsheet::commands::{recalc::recalc, set::set};
+/// # use rsheet::formula::EngineKind;
+/// # use rsheet::limits::Limits;
+/// # use rsheet::spreadsheet::Spreadsheet;
+/// let spreadsheet = Arc::new(Spreadsheet::with_limits(EngineKind::Native, Limits::default()));
+/// set(&spreadsheet, vec!["set", "A1", "RAND()"], 0).unwrap();
+///
+/// recalc(&spreadsheet, vec!["recalc", "A1_A3"], 1).unwrap();
+/// recalc(&spreadsheet, vec!["recalc"], 2).unwrap();
+/// ```
+pub fn recalc(
+    spreadsheet: &Arc<Spreadsheet>,
+    args: Vec<&str>,
+    timestamp: u64,
+) -> Result<(), Reply> {
+    let cells = match args[..] {
+        [_] => {
+            let mut cells = spreadsheet.get_dirty_cells();
+            cells.extend(spreadsheet.get_volatile_cells());
+            cells
+        }
+        [_, range] => {
+            let (start, end) = range.split_once('_').unwrap_or((range, range));
+            if !is_valid_cell(start) || !is_valid_cell(end) {
+                return Err(Reply::Error("Invalid range provided.".to_string()));
+            }
+
+            spreadsheet
+                .get_formula_cells()
+                .into_iter()
+                .filter(|cell| cell_in_range(cell, range))
+                .collect()
+        }
+        _ => {
+            return Err(Reply::Error(
+                "Invalid number of arguments supplied for recalc".to_string(),
+            ))
+        }
+    };
+
+    recalculate_all(spreadsheet, cells, timestamp)
+}
+
+/// Recalculates every cell holding a volatile formula, such as one calling
+/// `NOW()` or `RAND()`, along with the cells that depend on them.
+pub fn recalc_volatile(spreadsheet: &Arc<Spreadsheet>, timestamp: u64) -> Result<(), Reply> {
+    recalculate_all(spreadsheet, spreadsheet.get_volatile_cells(), timestamp)
+}
+
+/// Marks every cell holding a volatile formula, and the cells that depend on
+/// them, as dirty. In lazy calculation mode they are evaluated again the next
+/// time they are read.
+pub fn invalidate_volatile(spreadsheet: &Arc<Spreadsheet>) {
+    for cell in spreadsheet.get_volatile_cells() {
+        spreadsheet.mark_dirty(&cell);
+        mark_dependents_dirty(spreadsheet, &cell);
+    }
+}
+
+/// Brings the cells in a range, which can also be a single cell, up to date
+/// before they are read in lazy calculation mode. Every dirty cell they depend
+/// on, directly or through other cells, is evaluated first. In any other mode
+/// this does nothing.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::{calcmode::calcmode, recalc::evaluate_stale, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
+/// calcmode(&spreadsheet, vec!["calcmode", "lazy"], 0).unwrap();
+/// set(&spreadsheet, vec!["set", "A1", "1 + 1"], 0).unwrap();
+/// set(&spreadsheet, vec!["set", "B1", "A1 * 2"], 0).unwrap();
+///
+/// evaluate_stale(&spreadsheet, "B1", 0).unwrap();
+/// assert_eq!(spreadsheet.get_cell_val("B1"), Value::Int(4));
+/// ```
+pub fn evaluate_stale(
+    spreadsheet: &Arc<Spreadsheet>,
+    range: &str,
+    timestamp: u64,
+) -> Result<(), Reply> {
+    if spreadsheet.calc_mode() != CalcMode::Lazy {
+        return Ok(());
+    }
+
+    // Every dependent of a changed cell is marked dirty, so a clean cell never
+    // depends on a dirty one. Only the dirty cells inside the range, and the
+    // dirty array formulas spilling into it, have to be looked at rather than
+    // every cell in the range.
+    let mut queue: VecDeque<String> = spreadsheet
+        .get_dirty_cells()
+        .into_iter()
+        .filter(|cell| {
+            cell_in_range(cell, range)
+                || spreadsheet
+                    .get_spill(cell)
+                    .iter()
+                    .any(|spilled| cell_in_range(spilled, range))
+        })
+        .collect();
+
+    // A cell is only marked clean once everything it depends on is, so the
+    // search can stop at the first clean cell.
+    let mut stale = HashSet::new();
+    let mut seen = HashSet::new();
+    while let Some(cell) = queue.pop_front() {
+        if !seen.insert(cell.clone()) {
+            continue;
+        }
+
+        // A value spilled from an array formula is brought up to date by
+        // evaluating the formula.
+        queue.extend(spreadsheet.get_spilled_from(&cell));
+        if spreadsheet.is_dirty(&cell) {
+            queue.extend(precedents(spreadsheet, &cell));
+            stale.insert(cell);
+        }
+    }
+
+    evaluate_in_order(spreadsheet, stale, timestamp)
+}
+
+/// Recalculates `cells` and every cell that depends on them, directly or
+/// through other cells.
+fn recalculate_all(
+    spreadsheet: &Arc<Spreadsheet>,
+    cells: Vec<String>,
+    timestamp: u64,
+) -> Result<(), Reply> {
+    let mut affected = HashSet::new();
+    let mut queue: VecDeque<String> = cells.into();
+    while let Some(cell) = queue.pop_front() {
+        if affected.insert(cell.clone()) {
+            queue.extend(dependents(spreadsheet, &cell));
+        }
+    }
+
+    evaluate_in_order(spreadsheet, affected, timestamp)
+}
+
+/// Evaluates every cell in `cells` once, after all of the other cells in it
+/// that the cell depends on. A cell that depends on several changed cells
+/// isn't evaluated again for each of them. Cells in a cycle can't be ordered
+/// and are set to a `#CYCLE!` error.
+fn evaluate_in_order(
+    spreadsheet: &Arc<Spreadsheet>,
+    cells: HashSet<String>,
+    timestamp: u64,
+) -> Result<(), Reply> {
+    // Find the cells which have to be evaluated after each cell.
+    let children: HashMap<&str, Vec<String>> = cells
+        .iter()
+        .map(|cell| {
+            let mut deps = dependents(spreadsheet, cell);
+            deps.retain(|dep| cells.contains(dep));
+            (cell.as_str(), deps)
+        })
+        .collect();
+
+    // Count the parents of each cell, then evaluate the cells whose parents
+    // have all been evaluated until there are none left.
+    let mut parents: HashMap<&str, usize> = children.keys().map(|cell| (*cell, 0)).collect();
+    for dep in children.values().flatten() {
+        *parents.entry(dep.as_str()).or_default() += 1;
+    }
+
+    let mut ready: VecDeque<&str> = parents
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(cell, _)| *cell)
+        .collect();
+    let mut evaluated = HashSet::new();
+    while let Some(cell) = ready.pop_front() {
+        evaluate(spreadsheet, cell, timestamp)?;
+        evaluated.insert(cell);
+
+        for dep in &children[cell] {
+            let count = parents
+                .get_mut(dep.as_str())
+                .expect("Every child was counted.");
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(dep);
+            }
+        }
+    }
+
+    // Anything left over depends on itself.
+    for &cell in children.keys() {
+        if !evaluated.contains(cell) {
+            let message = format!("Cell {} is involved in a circular dependency", cell);
+            let error = Value::Error(CellError::new(ErrorKind::Cycle, message));
+            let expr = spreadsheet.get_cell_expr(cell);
+            spreadsheet.set_cell(cell, error, expr, timestamp);
+            spreadsheet.mark_clean(cell);
+        }
+    }
+
+    Ok(())
+}
+
+/// Gets the cells a cell's formula reads, and the cell holding the array
+/// formula it was spilled from.
+fn precedents(spreadsheet: &Spreadsheet, cell: &str) -> Vec<String> {
+    let mut precedents: Vec<String> = match spreadsheet.get_cell_expr(cell) {
+        Some(expr) => spreadsheet
+            .engine()
+            .find_variables(&expr)
+            .iter()
+            .flat_map(|var| range_cells(var))
+            .collect(),
+        None => Vec::new(),
+    };
+    precedents.extend(spreadsheet.get_spilled_from(cell));
+    precedents
+}
+
+/// Evaluates the formula in a cell, storing the result without recalculating
+/// its dependents.
+fn evaluate(spreadsheet: &Arc<Spreadsheet>, cell: &str, timestamp: u64) -> Result<(), Reply> {
+    spreadsheet.mark_clean(cell);
+
+    // "Dependent" and "Circular Dependency" mark a cell whose formula was
+    // replaced by an error, so there is nothing to evaluate.
+    let expr = match spreadsheet.get_cell_expr(cell) {
+        Some(expr) if expr != "Dependent" && expr != "Circular Dependency" => expr,
+        _ => return Ok(()),
+    };
+
+    let engine = spreadsheet.engine();
+    let vars = engine.find_variables(&expr);
+    let var_map = variable_map_for_runner(spreadsheet, &vars);
+    let result = engine.evaluate_array(&expr, &var_map);
+    store_result(spreadsheet, cell, result, Some(expr), &[], timestamp)?;
+
+    // Changing the cells an array formula spills into marks the formula as
+    // dirty again, although it was just evaluated.
+    spreadsheet.mark_clean(cell);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+    use crate::commands::{
+        calcmode::calcmode,
+        clear::clear,
+        get::{get, get_range},
+        set::set,
+    };
+    use crate::formula::EngineKind;
+
+    fn spreadsheet() -> Arc<Spreadsheet> {
+        Arc::new(Spreadsheet::with_engine(EngineKind::Native))
+    }
+
+    fn set_all(spreadsheet: &Arc<Spreadsheet>, commands: &[&str]) {
+        for command in commands {
+            let args = command.split_whitespace().collect();
+            set(spreadsheet, args, 0).unwrap();
+        }
+    }
+
+    /// Recalculates until a cell's value changes, as a random number can
+    /// come up twice.
+    fn changes(spreadsheet: &Arc<Spreadsheet>, cell: &str, recalc: impl Fn(u64)) -> bool {
+        let before = spreadsheet.get_cell_val(cell);
+        (1..=10).any(|timestamp| {
+            recalc(timestamp);
+            spreadsheet.get_cell_val(cell) != before
+        })
+    }
+
+    #[test]
+    fn test_volatile_cells() {
+        let spreadsheet = spreadsheet();
+        set_all(
+            &spreadsheet,
+            &["set A1 RAND()", "set A2 7", "set B1 A1 * 100"],
+        );
+
+        assert_eq!(spreadsheet.get_volatile_cells(), vec!["A1".to_string()]);
+        assert_eq!(spreadsheet.get_cell_expr("A1"), Some("RAND()".to_string()));
+
+        // Recalculating the volatile cells updates their dependents too.
+        assert!(changes(&spreadsheet, "B1", |timestamp| {
+            recalc_volatile(&spreadsheet, timestamp).unwrap()
+        }));
+
+        // A cell stops being volatile once its formula is replaced or cleared.
+        set_all(&spreadsheet, &["set A1 1"]);
+        assert!(spreadsheet.get_volatile_cells().is_empty());
+        set_all(&spreadsheet, &["set A2 NOW()"]);
+        clear(&spreadsheet, "A2", 0).unwrap();
+        assert!(spreadsheet.get_volatile_cells().is_empty());
+    }
+
+    #[test]
+    fn test_recalc_range() {
+        let spreadsheet = spreadsheet();
+        set_all(&spreadsheet, &["set A1 RAND()", "set B1 RAND()"]);
+
+        let a1 = spreadsheet.get_cell_val("A1");
+        assert!(changes(&spreadsheet, "B1", |timestamp| {
+            recalc(&spreadsheet, vec!["recalc", "B1_B3"], timestamp).unwrap()
+        }));
+        assert_eq!(spreadsheet.get_cell_val("A1"), a1);
+
+        assert!(changes(&spreadsheet, "A1", |timestamp| {
+            recalc(&spreadsheet, vec!["recalc"], timestamp).unwrap()
+        }));
+    }
+
+    #[test]
+    fn test_recalc_invalid() {
+        let spreadsheet = spreadsheet();
+        assert!(recalc(&spreadsheet, vec!["recalc", "A1_?"], 0).is_err());
+        assert!(recalc(&spreadsheet, vec!["recalc", "A1", "B1"], 0).is_err());
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::None);
+    }
+
+    #[test]
+    fn test_manual_mode() {
+        let spreadsheet = spreadsheet();
+        calcmode(&spreadsheet, vec!["calcmode", "manual"], 0).unwrap();
+        set_all(
+            &spreadsheet,
+            &[
+                "set A1 1",
+                "set B1 A1 + 1",
+                "set C1 B1 * 2",
+                "set D1 A1 + C1",
+            ],
+        );
+
+        // Only the literal is stored, the formulas wait for `recalc`.
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(1));
+        assert_eq!(spreadsheet.get_cell_val("D1"), Value::None);
+        assert!(!spreadsheet.is_stale("A1"));
+        assert!(["B1", "C1", "D1"]
+            .iter()
+            .all(|cell| spreadsheet.is_stale(cell)));
+
+        // Each cell is evaluated once, even though D1 depends on A1 twice.
+        let mut changes = spreadsheet.watch();
+        recalc(&spreadsheet, vec!["recalc"], 1).unwrap();
+        let changed: Vec<String> = iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| change.cell)
+            .collect();
+        assert_eq!(changed, vec!["B1", "C1", "D1"]);
+        assert_eq!(spreadsheet.get_cell_val("D1"), Value::Int(5));
+        assert!(spreadsheet.get_dirty_cells().is_empty());
+
+        // Changing a literal marks everything depending on it.
+        set_all(&spreadsheet, &["set A1 10"]);
+        assert_eq!(spreadsheet.get_cell_val("B1"), Value::Int(2));
+        assert!(spreadsheet.is_stale("D1"));
+
+        // Switching back to automatic mode brings everything up to date.
+        calcmode(&spreadsheet, vec!["calcmode", "auto"], 2).unwrap();
+        assert_eq!(spreadsheet.get_cell_val("D1"), Value::Int(32));
+        assert!(!spreadsheet.is_stale("D1"));
+    }
+
+    #[test]
+    fn test_manual_mode_cycle() {
+        let spreadsheet = spreadsheet();
+        calcmode(&spreadsheet, vec!["calcmode", "manual"], 0).unwrap();
+        set_all(&spreadsheet, &["set A1 B1 + 1", "set B1 A1 + 1"]);
+
+        recalc(&spreadsheet, vec!["recalc"], 1).unwrap();
+        for cell in ["A1", "B1"] {
+            let value = spreadsheet.get_cell_val(cell);
+            assert!(matches!(value, Value::Error(e) if e.kind == ErrorKind::Cycle));
+        }
+
+        // The formulas are kept, so the cycle can be broken.
+        set(&spreadsheet, vec!["set", "B1", "1"], 2).unwrap();
+        recalc(&spreadsheet, vec!["recalc"], 3).unwrap();
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(2));
+    }
+
+    #[test]
+    fn test_lazy_mode() {
+        let spreadsheet = spreadsheet();
+        calcmode(&spreadsheet, vec!["calcmode", "lazy"], 0).unwrap();
+        set_all(
+            &spreadsheet,
+            &[
+                "set A1 1",
+                "set A2 3",
+                "set B1 A1 + 1",
+                "set C1 B1 * 2",
+                "set D1 A1 * 100",
+                "set E1 A1_A2 * 2",
+            ],
+        );
+        assert!(spreadsheet.is_stale("C1"));
+
+        // Reading a cell evaluates it and the dirty cells it depends on, once.
+        let mut changes = spreadsheet.watch();
+        assert_eq!(
+            get(&spreadsheet, vec!["get", "C1"], 1).unwrap().1,
+            Value::Int(4)
+        );
+        assert_eq!(
+            get(&spreadsheet, vec!["get", "C1"], 1).unwrap().1,
+            Value::Int(4)
+        );
+        let changed: Vec<String> = iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| change.cell)
+            .collect();
+        assert_eq!(changed, vec!["B1", "C1"]);
+        assert!(spreadsheet.is_stale("D1"));
+
+        // Reading a spilled value evaluates the formula it came from.
+        assert_eq!(
+            get(&spreadsheet, vec!["get", "E1"], 1).unwrap().1,
+            Value::Int(2)
+        );
+        assert_eq!(
+            get(&spreadsheet, vec!["get", "E2"], 1).unwrap().1,
+            Value::Int(6)
+        );
+
+        // Changing a cell invalidates everything depending on it.
+        set(&spreadsheet, vec!["set", "A1", "5"], 2).unwrap();
+        assert!(spreadsheet.is_stale("B1") && spreadsheet.is_stale("C1"));
+        assert_eq!(
+            get_range(&spreadsheet, "B1_D1", 3).unwrap(),
+            vec![vec![Value::Int(6), Value::Int(12), Value::Int(500)]]
+        );
+        assert_eq!(spreadsheet.get_dirty_cells(), vec!["E1".to_string()]);
+
+        // A formula spilling into the range is evaluated even when it is
+        // outside of the range.
+        assert_eq!(
+            get_range(&spreadsheet, "E2", 3).unwrap(),
+            vec![vec![Value::Int(6)]]
+        );
+        assert_eq!(spreadsheet.get_cell_val("E1"), Value::Int(10));
+
+        // Only the dirty cells are looked at, not every cell in the range.
+        set(&spreadsheet, vec!["set", "A1", "6"], 4).unwrap();
+        evaluate_stale(&spreadsheet, "A1_ZZZ999999", 4).unwrap();
+        assert!(spreadsheet.get_dirty_cells().is_empty());
+        assert_eq!(spreadsheet.get_cell_val("D1"), Value::Int(600));
+    }
+
+    #[test]
+    fn test_calcmode_invalid() {
+        let spreadsheet = spreadsheet();
+        assert!(calcmode(&spreadsheet, vec!["calcmode", "sometimes"], 0).is_err());
+        assert!(calcmode(&spreadsheet, vec!["calcmode"], 0).is_err());
+        assert_eq!(spreadsheet.calc_mode(), CalcMode::Auto);
+    }
+}
@@ -1,17 +1,17 @@
 use std::sync::Arc;
 
-use rsheet_lib::{
-    command_runner::{CellValue, CommandRunner},
-    replies::Reply,
-};
+use rsheet_lib::replies::Reply;
 
 use crate::{
     commands::{
         dependencies::{add_dependencies, remove_all_dependencies, update_dependency},
+        lock::check_unlocked,
+        spill::{respill, store_result},
         variables::variable_map_for_runner,
     },
     spreadsheet::Spreadsheet,
-    utils::is_valid_cell,
+    utils::{is_valid_cell, range_size},
+    value::{Argument, Value},
 };
 
 use super::variables::{categorize_variable, VariableType};
@@ -20,20 +20,17 @@ use super::variables::{categorize_variable, VariableType};
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::sync::Arc;
-/// use rsheet_lib::command_runner::CellValue;
-/// use rsheet_server::spreadsheet::Spreadsheet;
-/// use rsheet_server::commands::set::set;
-/// use rsheet_server::commands::get::get;
-///
-/// let spreadsheet = spreadsheet::new_shared_spreadsheet();
+/// ```
+/// # use rsheet::commands::{get::get, set::set};
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = new_shared_spreadsheet();
 /// let result = set(&spreadsheet, vec!["set", "A1", "5"], 0);
 /// assert!(result.is_ok());
 ///
-/// let (cell, cell_val) = get(&spreadsheet, vec!["get", "A1"]).unwrap();
+/// let (cell, cell_val) = get(&spreadsheet, vec!["get", "A1"], 0).unwrap();
 /// assert_eq!(cell, "A1");
-/// assert_eq!(cell_val, CellValue::Int(5));
+/// assert_eq!(cell_val, Value::Int(5));
 /// ```
 pub fn set(spreadsheet: &Arc<Spreadsheet>, args: Vec<&str>, timestamp: u64) -> Result<(), Reply> {
     // Set should have at least 3 arguments: <set> <cell> <expression>
@@ -48,25 +45,63 @@ pub fn set(spreadsheet: &Arc<Spreadsheet>, args: Vec<&str>, timestamp: u64) -> R
     if !is_valid_cell(cell) {
         return Err(Reply::Error("Invalid cell provided.".to_string()));
     }
+    check_unlocked(spreadsheet, cell)?;
 
-    // Merge the rest of the arguments into a single expression for CommandRunner
+    // Merge the rest of the arguments into a single expression for the engine
     let expr = args[2..].join(" ");
-    let runner = CommandRunner::new(&expr);
+    let max_len = spreadsheet.limits().max_formula_len;
+    if expr.chars().count() > max_len {
+        return Err(Reply::Error(format!(
+            "Formula is longer than {} characters",
+            max_len
+        )));
+    }
+    let engine = spreadsheet.engine();
+
+    // Every cell of a range becomes a dependency and a value, so a huge range
+    // is turned away before any of them are made.
+    let vars = engine.find_variables(&expr);
+    let max_cells = spreadsheet.limits().max_range_cells;
+    let too_large = |var: &&String| range_size(var).is_some_and(|size| size > max_cells as u64);
+    if let Some(var) = vars.iter().find(too_large) {
+        return Err(Reply::Error(format!(
+            "Range {} has more than {} cells",
+            var, max_cells
+        )));
+    }
+
+    // The old formula may have been volatile or out of date. The new one is
+    // checked once it is known to be evaluated.
+    spreadsheet.set_volatile(cell, false);
+    spreadsheet.mark_clean(cell);
+    let deferred = spreadsheet.calc_mode().defers();
+
+    // An ISO-8601 date is stored as it is. Otherwise the engine would
+    // evaluate `2024-01-31` as a subtraction.
+    if let Some(date) = Value::parse_date(&expr) {
+        remove_all_dependencies(spreadsheet, cell, &expr);
+        let date = Argument::Value(date);
+        store_result(spreadsheet, cell, date, None, &[], timestamp)?;
+        update_dependency(spreadsheet, cell, &mut Vec::new(), timestamp)?;
+        return respill(spreadsheet, cell, timestamp);
+    }
 
     // When we set the cell again, we remove all dependencies associated with
     // the old expression.
     remove_all_dependencies(spreadsheet, cell, &expr);
 
-    let vars = runner.find_variables();
-
     for var in &vars {
         // If the variable's value is an error, we set the cell's value to be
         // an error as well, but we set the expression to "Dependent" to
         // signal that the cell is dependent on an error cell.
+        // In manual and lazy calculation modes the variable's value may be
+        // out of date, so the expression is kept to be evaluated later.
         let var_val = spreadsheet.get_cell_val(var);
-        if let CellValue::Error(_) = var_val {
-            spreadsheet.set_cell(cell, var_val, Some("Dependent".to_string()), timestamp);
-            return Ok(());
+        if !deferred && matches!(var_val, Value::Error(_)) {
+            let expr = Some("Dependent".to_string());
+            let var_val = Argument::Value(var_val);
+            store_result(spreadsheet, cell, var_val, expr, &[], timestamp)?;
+            return respill(spreadsheet, cell, timestamp);
         }
 
         // Otherwise, we add the cell as a dependent to the variables in it's
@@ -86,14 +121,28 @@ pub fn set(spreadsheet: &Arc<Spreadsheet>, args: Vec<&str>, timestamp: u64) -> R
         }
     }
 
-    let var_map = variable_map_for_runner(spreadsheet, &vars);
-    let cell_val = runner.run(&var_map);
+    // A volatile formula keeps its expression even if it doesn't reference
+    // any cells, so that it can be evaluated again by `recalc`.
+    let volatile = engine.is_volatile(&expr);
+    spreadsheet.set_volatile(cell, volatile);
 
-    match vars.is_empty() {
-        true => spreadsheet.set_cell(cell, cell_val, None, timestamp),
-        false => spreadsheet.set_cell(cell, cell_val, Some(expr), timestamp),
+    // In manual and lazy calculation modes a formula is only stored, keeping
+    // the cell's old value until it is evaluated later.
+    if deferred && (!vars.is_empty() || volatile) {
+        let value = spreadsheet.get_cell_val(cell);
+        spreadsheet.set_cell(cell, value, Some(expr), timestamp);
+        spreadsheet.mark_dirty(cell);
+        return update_dependency(spreadsheet, cell, &mut Vec::new(), timestamp);
     }
 
+    let var_map = variable_map_for_runner(spreadsheet, &vars);
+    let result = engine.evaluate_array(&expr, &var_map);
+    let expr = (!vars.is_empty() || volatile).then_some(expr);
+
+    // A result with more than one value spills into the neighbouring cells.
+    store_result(spreadsheet, cell, result, expr, &[], timestamp)?;
     update_dependency(spreadsheet, cell, &mut Vec::new(), timestamp)?;
-    Ok(())
+
+    // Setting a cell may block, or unblock, an array formula spilling into it.
+    respill(spreadsheet, cell, timestamp)
 }
@@ -0,0 +1,296 @@
+use std::sync::Arc;
+
+use rsheet_lib::{cells::column_number_to_name, replies::Reply};
+
+use crate::{
+    commands::{dependencies::update_dependency, variables::variable_map_for_runner},
+    spreadsheet::Spreadsheet,
+    utils::cell_position,
+    value::{Argument, CellError, ErrorKind, Value},
+};
+
+/// Stores the result of a cell's formula. A single value is stored in the
+/// cell. A range spills into the cells below and to the right, with its
+/// first value in the cell itself. The spilled cells are owned by the cell
+/// holding the formula, and are cleared when its result shrinks.
+///
+/// If any of the cells a range would spill into already hold something, or
+/// can't be written to by the user who set the formula, the formula's cell is
+/// set to a `#SPILL!` error instead.
+///
+/// Cells that depend on the spilled cells are recalculated, but the cell
+/// holding the formula is left for the caller to recalculate its dependents.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::commands::spill::store_result;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// # use rsheet::value::{Argument, Value};
+/// let spreadsheet = new_shared_spreadsheet();
+/// let result = Argument::Vector(vec![Value::Int(1), Value::Int(2)]);
+/// store_result(&spreadsheet, "B1", result, None, &[], 0).unwrap();
+///
+/// assert_eq!(spreadsheet.get_cell_val("B2"), Value::Int(2));
+/// assert_eq!(spreadsheet.get_spilled_from("B2"), Some("B1".to_string()));
+/// ```
+pub fn store_result(
+    spreadsheet: &Arc<Spreadsheet>,
+    cell: &str,
+    result: Argument,
+    expr: Option<String>,
+    path: &[String],
+    timestamp: u64,
+) -> Result<(), Reply> {
+    let region = spill_region(cell, result.into_rows());
+    let cells: Vec<String> = region.iter().map(|(cell, _)| cell.clone()).collect();
+    let old_cells = spreadsheet.replace_spill(cell, cells.clone());
+
+    let author = spreadsheet.get_author(cell);
+    let blocker = cells
+        .iter()
+        .skip(1)
+        .find(|other| spreadsheet.blocks_spill(other, cell, author.as_deref()));
+    if let Some(blocker) = blocker {
+        release(spreadsheet, cell, &old_cells, timestamp)?;
+        let message = format!("{} is in the way of the result", blocker);
+        let error = CellError::new(ErrorKind::Spill, message);
+        spreadsheet.set_cell(cell, Value::Error(error), expr, timestamp);
+        return Ok(());
+    }
+
+    let stale: Vec<String> = old_cells
+        .into_iter()
+        .filter(|old| !cells.contains(old))
+        .collect();
+    release(spreadsheet, cell, &stale, timestamp)?;
+
+    let mut region = region.into_iter();
+    if let Some((_, value)) = region.next() {
+        spreadsheet.set_cell(cell, value, expr, timestamp);
+    }
+    for (spilled, value) in region {
+        spreadsheet.set_spilled_cell(&spilled, value, cell, timestamp);
+        update_dependency(spreadsheet, &spilled, &mut path.to_vec(), timestamp)?;
+    }
+
+    Ok(())
+}
+
+/// Recalculates the array formulas that spill, or are blocked from spilling,
+/// into a cell whose contents were just changed by a command. This is how a
+/// formula reports a `#SPILL!` error when a cell is set in its way, and how
+/// it spills again when that cell is cleared.
+///
+/// In manual and lazy calculation modes the formulas are only marked as dirty,
+/// which `update_dependency` has already done.
+pub fn respill(spreadsheet: &Arc<Spreadsheet>, cell: &str, timestamp: u64) -> Result<(), Reply> {
+    if spreadsheet.calc_mode().defers() {
+        return Ok(());
+    }
+
+    for anchor in spreadsheet.get_spills_over(cell) {
+        recalculate(spreadsheet, &anchor, timestamp)?;
+    }
+
+    Ok(())
+}
+
+/// Evaluates the formula in `anchor` again, spilling its result and updating
+/// its dependents.
+fn recalculate(spreadsheet: &Arc<Spreadsheet>, anchor: &str, timestamp: u64) -> Result<(), Reply> {
+    if let Some(expr) = spreadsheet.get_cell_expr(anchor) {
+        let engine = spreadsheet.engine();
+        let vars = engine.find_variables(&expr);
+        let var_map = variable_map_for_runner(spreadsheet, &vars);
+        let result = engine.evaluate_array(&expr, &var_map);
+
+        store_result(spreadsheet, anchor, result, Some(expr), &[], timestamp)?;
+        update_dependency(spreadsheet, anchor, &mut Vec::new(), timestamp)?;
+    }
+
+    Ok(())
+}
+
+/// Pairs each value in the rows of a result with the cell it is stored in,
+/// starting from `anchor`.
+fn spill_region(anchor: &str, rows: Vec<Vec<Value>>) -> Vec<(String, Value)> {
+    let (start_col, start_row) = cell_position(anchor).unwrap_or_default();
+
+    let mut region = Vec::new();
+    for (row, values) in (start_row..).zip(rows) {
+        for (col, value) in (start_col..).zip(values) {
+            region.push((format!("{}{}", column_number_to_name(col), row), value));
+        }
+    }
+    region
+}
+
+/// Empties the cells that `anchor` no longer spills into. Cells that have
+/// since been set to something else are left alone.
+fn release(
+    spreadsheet: &Arc<Spreadsheet>,
+    anchor: &str,
+    cells: &[String],
+    timestamp: u64,
+) -> Result<(), Reply> {
+    for cell in cells {
+        if cell == anchor || spreadsheet.get_spilled_from(cell).as_deref() != Some(anchor) {
+            continue;
+        }
+
+        spreadsheet.set_cell(cell, Value::None, None, timestamp);
+        update_dependency(spreadsheet, cell, &mut Vec::new(), timestamp)?;
+
+        // Another formula may have been blocked by this one.
+        for other in spreadsheet.get_spills_over(cell) {
+            if other != anchor {
+                recalculate(spreadsheet, &other, timestamp)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Acl;
+    use crate::commands::{clear::clear, set::set};
+    use crate::formula::EngineKind;
+
+    fn spreadsheet() -> Arc<Spreadsheet> {
+        Arc::new(Spreadsheet::with_engine(EngineKind::Native))
+    }
+
+    fn set_all(spreadsheet: &Arc<Spreadsheet>, commands: &[&str]) {
+        for command in commands {
+            let args = command.split_whitespace().collect();
+            set(spreadsheet, args, 0).unwrap();
+        }
+    }
+
+    fn values(spreadsheet: &Spreadsheet, cells: &[&str]) -> Vec<Value> {
+        cells
+            .iter()
+            .map(|cell| spreadsheet.get_cell_val(cell))
+            .collect()
+    }
+
+    fn is_spill_error(value: Value) -> bool {
+        matches!(value, Value::Error(e) if e.kind == ErrorKind::Spill)
+    }
+
+    #[test]
+    fn test_spill_and_recalculate() {
+        let spreadsheet = spreadsheet();
+        set_all(
+            &spreadsheet,
+            &[
+                "set A1 1",
+                "set A2 2",
+                "set A3 3",
+                "set B1 A1_A3 * 10",
+                "set C2 B2 + 1",
+            ],
+        );
+
+        assert_eq!(
+            values(&spreadsheet, &["B1", "B2", "B3", "C2"]),
+            vec![
+                Value::Int(10),
+                Value::Int(20),
+                Value::Int(30),
+                Value::Int(21)
+            ]
+        );
+        assert_eq!(spreadsheet.get_spilled_from("B3"), Some("B1".to_string()));
+
+        // Changing an input recalculates the whole range and its dependents.
+        set_all(&spreadsheet, &["set A2 5"]);
+        assert_eq!(
+            values(&spreadsheet, &["B2", "C2"]),
+            vec![Value::Int(50), Value::Int(51)]
+        );
+
+        // A smaller result releases the cells it no longer covers.
+        set_all(&spreadsheet, &["set B1 FILTER(A1_A3, A1_A3 > 1)"]);
+        assert_eq!(
+            values(&spreadsheet, &["B1", "B2", "B3"]),
+            vec![Value::Int(5), Value::Int(3), Value::None]
+        );
+        assert_eq!(spreadsheet.get_spilled_from("B3"), None);
+    }
+
+    #[test]
+    fn test_spill_blocked() {
+        let spreadsheet = spreadsheet();
+        set_all(
+            &spreadsheet,
+            &[
+                "set A1 3",
+                "set A2 1",
+                "set A3 2",
+                "set C2 7",
+                "set C1 SORT(A1_A3)",
+            ],
+        );
+
+        assert!(is_spill_error(spreadsheet.get_cell_val("C1")));
+        assert_eq!(spreadsheet.get_cell_val("C3"), Value::None);
+
+        // Clearing the cell in the way lets the formula spill.
+        clear(&spreadsheet, "C2", 0).unwrap();
+        assert_eq!(
+            values(&spreadsheet, &["C1", "C2", "C3"]),
+            vec![Value::Int(1), Value::Int(2), Value::Int(3)]
+        );
+
+        // Setting a spilled cell blocks the formula again.
+        set_all(&spreadsheet, &["set C3 9"]);
+        assert!(is_spill_error(spreadsheet.get_cell_val("C1")));
+        assert_eq!(
+            values(&spreadsheet, &["C2", "C3"]),
+            vec![Value::None, Value::Int(9)]
+        );
+    }
+
+    #[test]
+    fn test_clear_anchor() {
+        let spreadsheet = spreadsheet();
+        set_all(&spreadsheet, &["set A1 1", "set B1 2", "set A2 A1_B1 * 2"]);
+        assert_eq!(
+            values(&spreadsheet, &["A2", "B2"]),
+            vec![Value::Int(2), Value::Int(4)]
+        );
+
+        clear(&spreadsheet, "A2", 0).unwrap();
+        assert_eq!(
+            values(&spreadsheet, &["A2", "B2"]),
+            vec![Value::None, Value::None]
+        );
+    }
+
+    #[test]
+    fn test_spill_needs_write_access() {
+        let mut spreadsheet = Spreadsheet::with_engine(EngineKind::Native);
+        let rules = "allow alice write A1_B2\nallow bob write A1_B9";
+        spreadsheet.set_acl(Some(Acl::parse(rules).unwrap()));
+        let spreadsheet = Arc::new(spreadsheet);
+        set_all(&spreadsheet, &["set A1 1", "set A2 2", "set A3 3"]);
+
+        // Alice can't write to B3, so her formula can't spill into it.
+        spreadsheet.set_author("B1", Some("alice"));
+        set_all(&spreadsheet, &["set B1 A1_A3"]);
+        assert!(is_spill_error(spreadsheet.get_cell_val("B1")));
+        assert_eq!(spreadsheet.get_cell_val("B3"), Value::None);
+
+        spreadsheet.set_author("B1", Some("bob"));
+        set_all(&spreadsheet, &["set B1 A1_A3"]);
+        assert_eq!(
+            values(&spreadsheet, &["B1", "B2", "B3"]),
+            vec![Value::Int(1), Value::Int(2), Value::Int(3)]
+        );
+    }
+}
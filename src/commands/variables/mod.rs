@@ -1,11 +1,11 @@
 use std::collections::HashMap;
 
-use rsheet_lib::{
-    cells::{column_name_to_number, column_number_to_name},
-    command_runner::{CellArgument, CellValue},
-};
+use rsheet_lib::cells::{column_name_to_number, column_number_to_name};
 
-use crate::spreadsheet::Spreadsheet;
+use crate::{
+    spreadsheet::Spreadsheet,
+    value::{Argument, Value},
+};
 
 /// Type aliases for the start and end columns and rows for a cell for
 /// easier understanding.
@@ -38,17 +38,27 @@ pub enum VariableType<'a> {
 /// # Example
 ///
 /// ```
+/// # use rsheet::commands::variables::{categorize_variable, VariableType};
 /// let scalar = "A1";
 /// let horizontal_vector = "A1_C1";
 /// let vertical_vector = "A1_A3";
 /// let matrix = "A1_C3";
 ///
 /// assert_eq!(categorize_variable(scalar), VariableType::Scalar);
-/// assert_eq!(horizontal_vector), VariableType::HorizontalVector("1", "A", "C"));
-/// assert_eq!(vertical_vector), VariableType::VerticalVector("A", "1", "3"));
-/// assert_eq!(matrix), VariableType::Matrix(("A", "1"), ("C", "3")));
-/// ````
-pub fn categorize_variable(variable: &str) -> VariableType {
+/// assert_eq!(
+///     categorize_variable(horizontal_vector),
+///     VariableType::HorizontalVector("1", "A", "C")
+/// );
+/// assert_eq!(
+///     categorize_variable(vertical_vector),
+///     VariableType::VerticalVector("A", "1", "3")
+/// );
+/// assert_eq!(
+///     categorize_variable(matrix),
+///     VariableType::Matrix(("A", "1"), ("C", "3"))
+/// );
+/// ```
+pub fn categorize_variable(variable: &str) -> VariableType<'_> {
     let cells: Vec<&str> = variable.split('_').collect();
 
     if cells.len() == 1 {
@@ -72,10 +82,10 @@ pub fn categorize_variable(variable: &str) -> VariableType {
 pub fn variable_map_for_runner(
     spreadsheet: &Spreadsheet,
     variables: &Vec<String>,
-) -> HashMap<String, CellArgument> {
+) -> HashMap<String, Argument> {
     // We need to get the values of the variables in the expression and
-    // store them into the variables hashmap for the CommandRunner.
-    let mut var_map: HashMap<String, CellArgument> = HashMap::new();
+    // store them into the variables hashmap for the formula engine.
+    let mut var_map: HashMap<String, Argument> = HashMap::new();
     for var in variables {
         let var_type: VariableType = categorize_variable(var);
         let var = var.to_string();
@@ -83,38 +93,29 @@ pub fn variable_map_for_runner(
         match var_type {
             VariableType::Scalar => {
                 let cell_val = spreadsheet.get_cell_val(&var);
-                var_map.insert(var, CellArgument::Value(cell_val));
+                var_map.insert(var, Argument::Value(cell_val));
             }
             VariableType::VerticalVector(start_col, start_row, end_row) => {
                 let cell_vec =
                     create_cell_vec(start_row, end_row, start_col, start_col, spreadsheet);
-                var_map.insert(var, CellArgument::Vector(cell_vec));
+                var_map.insert(var, Argument::Vector(cell_vec));
             }
             VariableType::HorizontalVector(start_row, start_col, end_col) => {
                 let cell_vec =
                     create_cell_vec(start_row, start_row, start_col, end_col, spreadsheet);
-                var_map.insert(var, CellArgument::Vector(cell_vec));
+                var_map.insert(var, Argument::Vector(cell_vec));
             }
             VariableType::Matrix((start_col, start_row), (end_col, end_row)) => {
                 let cell_matrix =
                     create_cell_matrix(start_row, end_row, start_col, end_col, spreadsheet);
-                var_map.insert(var, CellArgument::Matrix(cell_matrix));
+                var_map.insert(var, Argument::Matrix(cell_matrix));
             }
         }
     }
     var_map
 }
 
-/// Splits the cell into its row and column.
-///
-/// # Example
-///
-/// ```
-/// let cell = "A1";
-/// let (col, row) = get_row_col(cell);
-/// assert_eq!(col, "A");
-/// assert_eq!(row, "1");
-/// ```
+/// Splits the cell into its column and row.
 fn get_row_col(cell: &str) -> (&str, &str) {
     let (col, row) = cell
         .split_at(cell.find(|c: char| c.is_ascii_digit()).expect(
@@ -123,24 +124,14 @@ fn get_row_col(cell: &str) -> (&str, &str) {
     (col, row)
 }
 
-/// Creates a vector of cell values.
-///
-/// # Example
-///
-/// ```
-/// let (start_col, start_row) = get_row_col("A1");
-/// let (end_col, end_row) = get_row_col("A3");
-///
-/// let cell_vec = create_cell_vec(start_row, end_row, start_col, start_col, &spreadsheet);
-/// assert_eq!(cell_vec.len(), 3);
-/// ```
+/// Creates a vector of cell values, row by row.
 fn create_cell_vec(
     start_row: &str,
     end_row: &str,
     start_col: &str,
     end_col: &str,
     spreadsheet: &Spreadsheet,
-) -> Vec<CellValue> {
+) -> Vec<Value> {
     let start_row: u32 = start_row.parse().unwrap();
     let end_row: u32 = end_row.parse().unwrap();
     let start_col = column_name_to_number(start_col);
@@ -165,21 +156,24 @@ fn create_cell_vec(
 /// # Example
 ///
 /// ```
-/// let (start_col, start_row) = get_row_col("A1");
-/// let (end_col, end_row) = get_row_col("C3");
+/// # use rsheet::commands::variables::create_cell_matrix;
+/// # use rsheet::spreadsheet::Spreadsheet;
+/// # use rsheet::value::Value;
+/// let spreadsheet = Spreadsheet::new();
+/// spreadsheet.set_cell("B2", Value::Int(5), None, 0);
 ///
-/// let cell_matrix = create_cell_matrix(start_row, end_row, start_col, end_col, &spreadsheet);
+/// let cell_matrix = create_cell_matrix("1", "3", "A", "C", &spreadsheet);
 ///
 /// assert_eq!(cell_matrix.len(), 3);
-/// assert_eq!(cell_matrix[0].len(), 3);
+/// assert_eq!(cell_matrix[1], vec![Value::None, Value::Int(5), Value::None]);
 /// ```
-fn create_cell_matrix(
+pub fn create_cell_matrix(
     start_row: &str,
     end_row: &str,
     start_col: &str,
     end_col: &str,
     spreadsheet: &Spreadsheet,
-) -> Vec<Vec<CellValue>> {
+) -> Vec<Vec<Value>> {
     let start_row: u32 = start_row.parse().unwrap();
     let end_row: u32 = end_row.parse().unwrap();
     let start_col = column_name_to_number(start_col);
@@ -203,10 +197,24 @@ fn create_cell_matrix(
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_get_row_col() {
+        assert_eq!(get_row_col("A1"), ("A", "1"));
+        assert_eq!(get_row_col("ZZ123"), ("ZZ", "123"));
+    }
+
+    #[test]
+    fn test_create_cell_vec() {
+        let spreadsheet = Spreadsheet::new();
+        spreadsheet.set_cell("A2", Value::Int(2), None, 0);
+
+        let cell_vec = create_cell_vec("1", "3", "A", "A", &spreadsheet);
+        assert_eq!(cell_vec, vec![Value::None, Value::Int(2), Value::None]);
+    }
+
     #[test]
     fn test_categorize_variable() {
         let scalar = "A1";
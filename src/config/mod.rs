@@ -0,0 +1,454 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::LevelFilter;
+use rsheet_lib::connect::resolve_address;
+use serde::Deserialize;
+
+use crate::formula::EngineKind;
+use crate::protocol::Protocol;
+
+/// Settings for the server, loaded from a TOML file. Every setting is
+/// optional, and has a matching command line option which takes precedence
+/// over the file. Unknown settings are rejected, so that a typo isn't
+/// silently ignored.
+///
+/// ```toml
+/// [server]
+/// addr = "127.0.0.1:5000"
+/// http = "127.0.0.1:8080"
+/// compute_threads = 8
+/// max_connections = 1000
+/// idle_timeout_secs = 300
+/// log_level = "info"
+///
+/// [formulas]
+/// engine = "native"
+/// timeout_ms = 500
+///
+/// [quotas]
+/// rate_limit = 50
+///
+/// [protocol]
+/// default = "json"
+///
+/// [security]
+/// auth_file = "users.txt"
+/// audit_log = "audit.log"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub formulas: FormulaSection,
+    pub quotas: QuotaSection,
+    pub protocol: ProtocolSection,
+    pub security: SecuritySection,
+}
+
+/// Where the server listens, and how it handles connections.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub addr: Option<String>,
+    pub http: Option<String>,
+    pub ws: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Threads running commands and HTTP requests.
+    pub compute_threads: Option<usize>,
+    /// Threads waiting on TCP connections.
+    pub io_threads: Option<usize>,
+    pub max_connections: Option<usize>,
+    /// 0 never disconnects idle clients.
+    pub idle_timeout_secs: Option<u64>,
+    pub drain_timeout_secs: Option<u64>,
+    /// One of off, error, warn, info, debug or trace.
+    pub log_level: Option<String>,
+}
+
+/// How formulas are evaluated.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormulaSection {
+    pub engine: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub max_steps: Option<u64>,
+    pub max_depth: Option<usize>,
+    pub max_fan_out: Option<usize>,
+    pub max_formula_len: Option<usize>,
+    pub max_nesting: Option<usize>,
+    pub max_range_cells: Option<usize>,
+    /// 0 disables scheduled recalculation.
+    pub recalc_interval_ms: Option<u64>,
+}
+
+/// How much each client can do.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaSection {
+    pub rate_limit: Option<f64>,
+    pub user_rate_limit: Option<f64>,
+    pub rate_burst: Option<f64>,
+    pub max_cells: Option<usize>,
+}
+
+/// How clients talk to the server. The HTTP API and WebSockets are turned on
+/// by giving them an address in `[server]`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocolSection {
+    /// The protocol connections start with, text or json. Either way
+    /// clients can switch with the `protocol` command.
+    pub default: Option<String>,
+}
+
+/// Who can connect, what they can access, and where changes are recorded.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecuritySection {
+    pub auth_file: Option<PathBuf>,
+    pub acl_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Config {
+    /// Loads the config file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Self::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Parses the contents of a config file.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// Combines two configs, preferring the settings of `overrides`. This is
+    /// used to apply the command line options over the config file.
+    pub fn merge(self, overrides: Config) -> Config {
+        let (server, o) = (self.server, overrides.server);
+        let (formulas, f) = (self.formulas, overrides.formulas);
+        let (quotas, q) = (self.quotas, overrides.quotas);
+        let (protocol, p) = (self.protocol, overrides.protocol);
+        let (security, s) = (self.security, overrides.security);
+
+        Config {
+            server: ServerSection {
+                addr: o.addr.or(server.addr),
+                http: o.http.or(server.http),
+                ws: o.ws.or(server.ws),
+                tls_cert: o.tls_cert.or(server.tls_cert),
+                tls_key: o.tls_key.or(server.tls_key),
+                compute_threads: o.compute_threads.or(server.compute_threads),
+                io_threads: o.io_threads.or(server.io_threads),
+                max_connections: o.max_connections.or(server.max_connections),
+                idle_timeout_secs: o.idle_timeout_secs.or(server.idle_timeout_secs),
+                drain_timeout_secs: o.drain_timeout_secs.or(server.drain_timeout_secs),
+                log_level: o.log_level.or(server.log_level),
+            },
+            formulas: FormulaSection {
+                engine: f.engine.or(formulas.engine),
+                timeout_ms: f.timeout_ms.or(formulas.timeout_ms),
+                max_steps: f.max_steps.or(formulas.max_steps),
+                max_depth: f.max_depth.or(formulas.max_depth),
+                max_fan_out: f.max_fan_out.or(formulas.max_fan_out),
+                max_formula_len: f.max_formula_len.or(formulas.max_formula_len),
+                max_nesting: f.max_nesting.or(formulas.max_nesting),
+                max_range_cells: f.max_range_cells.or(formulas.max_range_cells),
+                recalc_interval_ms: f.recalc_interval_ms.or(formulas.recalc_interval_ms),
+            },
+            quotas: QuotaSection {
+                rate_limit: q.rate_limit.or(quotas.rate_limit),
+                user_rate_limit: q.user_rate_limit.or(quotas.user_rate_limit),
+                rate_burst: q.rate_burst.or(quotas.rate_burst),
+                max_cells: q.max_cells.or(quotas.max_cells),
+            },
+            protocol: ProtocolSection {
+                default: p.default.or(protocol.default),
+            },
+            security: SecuritySection {
+                auth_file: s.auth_file.or(security.auth_file),
+                acl_file: s.acl_file.or(security.acl_file),
+                audit_log: s.audit_log.or(security.audit_log),
+            },
+        }
+    }
+
+    /// The engine to evaluate formulas with. Only valid once the config has
+    /// been validated.
+    pub fn engine(&self) -> EngineKind {
+        self.formulas
+            .engine
+            .as_deref()
+            .and_then(|engine| engine.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// The protocol connections start with. Only valid once the config has
+    /// been validated.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+            .default
+            .as_deref()
+            .and_then(Protocol::from_name)
+            .unwrap_or_default()
+    }
+
+    /// The level to log at. Only valid once the config has been validated.
+    pub fn log_level(&self) -> LevelFilter {
+        self.server
+            .log_level
+            .as_deref()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(LevelFilter::Error)
+    }
+
+    /// Checks that every setting makes sense, so that the server doesn't fail
+    /// part of the way through starting. Every problem found is reported, one
+    /// per line, named by its setting in the config file.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::config::Config;
+    /// let config = Config::parse("[server]\nio_threads = 0").unwrap();
+    /// assert_eq!(config.validate(), Err("server.io_threads must be at least 1".to_string()));
+    /// ```
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let (server, formulas, quotas, security) =
+            (&self.server, &self.formulas, &self.quotas, &self.security);
+
+        for (name, addr) in [
+            ("server.addr", &server.addr),
+            ("server.http", &server.http),
+            ("server.ws", &server.ws),
+        ] {
+            if let Some(addr) = addr {
+                if resolve_address(addr).is_err() {
+                    errors.push(format!("{} is not a valid address: {}", name, addr));
+                }
+            }
+        }
+
+        match (&server.tls_cert, &server.tls_key) {
+            (Some(_), None) => errors.push("server.tls_cert needs server.tls_key".to_string()),
+            (None, Some(_)) => errors.push("server.tls_key needs server.tls_cert".to_string()),
+            (Some(_), Some(_)) if server.addr.is_none() => {
+                errors.push("server.tls_cert needs server.addr to listen on".to_string())
+            }
+            _ => {}
+        }
+
+        for (name, value) in [
+            ("server.compute_threads", server.compute_threads),
+            ("server.io_threads", server.io_threads),
+            ("server.max_connections", server.max_connections),
+            ("formulas.max_depth", formulas.max_depth),
+            ("formulas.max_fan_out", formulas.max_fan_out),
+            ("formulas.max_formula_len", formulas.max_formula_len),
+            ("formulas.max_nesting", formulas.max_nesting),
+            ("formulas.max_range_cells", formulas.max_range_cells),
+            ("quotas.max_cells", quotas.max_cells),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("{} must be at least 1", name));
+            }
+        }
+        for (name, value) in [
+            ("formulas.timeout_ms", formulas.timeout_ms),
+            ("formulas.max_steps", formulas.max_steps),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("{} must be at least 1", name));
+            }
+        }
+
+        for (name, value) in [
+            ("quotas.rate_limit", quotas.rate_limit),
+            ("quotas.user_rate_limit", quotas.user_rate_limit),
+        ] {
+            if value.is_some_and(|rate| !(rate.is_finite() && rate > 0.0)) {
+                errors.push(format!("{} must be more than 0", name));
+            }
+        }
+        if quotas
+            .rate_burst
+            .is_some_and(|burst| !(burst.is_finite() && burst >= 1.0))
+        {
+            errors.push("quotas.rate_burst must be at least 1".to_string());
+        }
+
+        if let Some(Err(e)) = formulas.engine.as_deref().map(str::parse::<EngineKind>) {
+            errors.push(format!("formulas.engine: {}", e));
+        }
+        if let Some(name) = &self.protocol.default {
+            if Protocol::from_name(name).is_none() {
+                errors.push(format!(
+                    "protocol.default must be text or json, not {}",
+                    name
+                ));
+            }
+        }
+        if let Some(level) = &server.log_level {
+            if level.parse::<LevelFilter>().is_err() {
+                errors.push(format!(
+                    "server.log_level must be one of off, error, warn, info, debug or trace, not {}",
+                    level
+                ));
+            }
+        }
+
+        for (name, path) in [
+            ("server.tls_cert", &server.tls_cert),
+            ("server.tls_key", &server.tls_key),
+            ("security.auth_file", &security.auth_file),
+            ("security.acl_file", &security.acl_file),
+        ] {
+            if let Some(path) = path.as_deref().filter(|path| !path.is_file()) {
+                errors.push(format!("{} doesn't exist: {}", name, path.display()));
+            }
+        }
+        if let Some(path) = &security.audit_log {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            if dir.is_some_and(|dir| !dir.is_dir()) {
+                errors.push(format!(
+                    "security.audit_log is in a directory which doesn't exist: {}",
+                    path.display()
+                ));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("\n")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [server]
+        addr = "127.0.0.1:5000"
+        compute_threads = 4
+        idle_timeout_secs = 300
+        log_level = "debug"
+
+        [formulas]
+        engine = "native"
+        timeout_ms = 500
+
+        [quotas]
+        rate_limit = 2.5
+
+        [protocol]
+        default = "json"
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.server.compute_threads, Some(4));
+        assert_eq!(config.server.io_threads, None);
+        assert_eq!(config.formulas.timeout_ms, Some(500));
+        assert_eq!(config.quotas.rate_limit, Some(2.5));
+        assert_eq!(config.engine(), EngineKind::Native);
+        assert_eq!(config.log_level(), LevelFilter::Debug);
+        assert_eq!(config.protocol(), Protocol::Json);
+        assert_eq!(config.validate(), Ok(()));
+
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert_eq!(Config::default().protocol(), Protocol::Text);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // Typos aren't silently ignored.
+        let error = Config::parse("[server]\ncompute_thread = 4").unwrap_err();
+        assert!(
+            error.contains("unknown field `compute_thread`"),
+            "{}",
+            error
+        );
+        assert!(Config::parse("[server]\ncompute_threads = \"four\"").is_err());
+        assert!(Config::parse("[servers]").is_err());
+    }
+
+    #[test]
+    fn test_command_line_takes_precedence() {
+        let file = Config::parse(CONFIG).unwrap();
+        let command_line = Config {
+            server: ServerSection {
+                compute_threads: Some(16),
+                ..ServerSection::default()
+            },
+            protocol: ProtocolSection {
+                default: Some("text".to_string()),
+            },
+            ..Config::default()
+        };
+
+        let config = file.merge(command_line);
+        assert_eq!(config.server.compute_threads, Some(16));
+        assert_eq!(config.server.idle_timeout_secs, Some(300));
+        assert_eq!(config.protocol(), Protocol::Text);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::parse(
+            r#"
+            [server]
+            http = "not an address"
+            tls_cert = "cert.pem"
+            io_threads = 0
+            log_level = "loud"
+
+            [formulas]
+            engine = "excel"
+
+            [quotas]
+            rate_limit = -1
+            rate_burst = 0.5
+
+            [protocol]
+            default = "xml"
+
+            [security]
+            acl_file = "/does/not/exist.txt"
+            audit_log = "/does/not/exist/audit.log"
+            "#,
+        )
+        .unwrap();
+
+        let errors = config.validate().unwrap_err();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(
+            errors,
+            vec![
+                "server.http is not a valid address: not an address",
+                "server.tls_cert needs server.tls_key",
+                "server.io_threads must be at least 1",
+                "quotas.rate_limit must be more than 0",
+                "quotas.rate_burst must be at least 1",
+                "formulas.engine: Unknown engine 'excel', expected rhai or native",
+                "protocol.default must be text or json, not xml",
+                "server.log_level must be one of off, error, warn, info, debug or trace, not loud",
+                "server.tls_cert doesn't exist: cert.pem",
+                "security.acl_file doesn't exist: /does/not/exist.txt",
+                "security.audit_log is in a directory which doesn't exist: /does/not/exist/audit.log",
+            ]
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rsheet_lib::connect::{ConnectionError, Manager, Reader, ReaderWriter, TerminalWriter, Writer};
+use rsheet_lib::replies::Reply;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::protocol::CommandReply;
+use crate::shutdown::{Shutdown, POLL_INTERVAL};
+use crate::value::Value;
+
+/// The longest message we are willing to buffer before giving up on the
+/// client. This matches the buffer size used by `rsheet_lib`.
+pub(crate) const MAX_MESSAGE_LEN: usize = 512;
+
+/// A `Writer` which can also send a pre-formatted line. This is needed for
+/// the JSON protocol as its replies can't be expressed as a `Reply`.
+pub trait LineWriter: Writer {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError>;
+
+    /// Writes the reply to a command in the text protocol. By default this is
+    /// written as a `Reply`, which shows floats and decimals as strings.
+    fn write_reply(&mut self, reply: CommandReply) -> Result<(), ConnectionError> {
+        self.write_message(reply.into())
+    }
+}
+
+impl LineWriter for TerminalWriter {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+        println!("{}", line);
+        Ok(())
+    }
+
+    /// Values are printed the same way as `TerminalWriter` prints a `Reply`,
+    /// so that a float is shown as `A1 = 1.5`. Errors are left to
+    /// `TerminalWriter`, which knows whether to hide them in mark mode.
+    fn write_reply(&mut self, reply: CommandReply) -> Result<(), ConnectionError> {
+        match reply {
+            CommandReply::Value(cell, value) if !matches!(value, Value::Error(_)) => {
+                println!("{cell} = {value}");
+                Ok(())
+            }
+            CommandReply::Stale(cell, value) => {
+                println!("{cell} = {value} (stale)");
+                Ok(())
+            }
+            reply => self.write_message(reply.into()),
+        }
+    }
+}
+
+/// Counts the open connections so that new ones can be turned away once
+/// there are too many. Every clone shares the same count.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::connect::ConnectionLimit;
+/// let limit = ConnectionLimit::new(Some(1));
+/// let slot = limit.try_acquire().unwrap();
+/// assert!(limit.try_acquire().is_none());
+///
+/// drop(slot);
+/// assert!(limit.try_acquire().is_some());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    /// Allows up to `max` connections at once, or any number if `max` is
+    /// `None`.
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            open: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a slot for a new connection, which is given back when the slot
+    /// is dropped. Returns `None` if every slot is taken.
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < max).then_some(open + 1)
+            })
+            .ok()?;
+
+        Some(ConnectionSlot {
+            open: self.open.clone(),
+        })
+    }
+
+    /// The number of connections currently open.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+/// A connection's place in a `ConnectionLimit`.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts TCP connections. This behaves the same as `rsheet_lib`'s
+/// `ConnectionManager`, but our own reader and writer give us access to the
+/// underlying socket. Once a shutdown is requested, no more connections are
+/// accepted and readers stop waiting for new messages.
+///
+/// If a TLS config is given, every connection is wrapped in TLS before any
+/// messages are exchanged.
+pub struct TcpManager {
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    shutdown: Shutdown,
+    idle_timeout: Option<Duration>,
+}
+
+impl TcpManager {
+    pub fn launch(
+        address: impl Into<IpAddr>,
+        port: u16,
+        tls: Option<Arc<ServerConfig>>,
+        shutdown: Shutdown,
+    ) -> Self {
+        let address = address.into();
+        let listener = TcpListener::bind((address, port))
+            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+
+        // The listener is polled so that it can notice a shutdown.
+        listener
+            .set_nonblocking(true)
+            .expect("failed to make the listener non-blocking");
+
+        Self {
+            listener,
+            tls,
+            shutdown,
+            idle_timeout: None,
+        }
+    }
+
+    /// Closes connections which haven't sent anything for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// The socket under a connection, which is either plain TCP or wrapped in
+/// TLS. A TLS session can't be split into a reading and a writing half, so
+/// the reader and writer share it and take turns. As the reader only holds it
+/// for one timed out read at a time, a writer waits at most `POLL_INTERVAL`.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Plain(socket) => socket.try_clone().map(Stream::Plain),
+            Stream::Tls(stream) => Ok(Stream::Tls(stream.clone())),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.read(buf),
+            Stream::Tls(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(socket) => socket.write(buf),
+            Stream::Tls(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(socket) => socket.flush(),
+            Stream::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
+pub struct TcpReaderWriter;
+
+impl ReaderWriter for TcpReaderWriter {
+    type Reader = TcpReader;
+    type Writer = TcpWriter;
+}
+
+impl Manager for TcpManager {
+    type ReaderWriter = TcpReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Result<(TcpReader, TcpWriter), ()> {
+        let (socket, addr) = loop {
+            match self.listener.accept() {
+                Ok(connection) => break connection,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if self.shutdown.is_requested() {
+                        return Err(());
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(_) => return Err(()),
+            }
+        };
+
+        // Reads time out so that the reader can check for a shutdown while it
+        // waits for the next message.
+        socket.set_nonblocking(false).map_err(|_| ())?;
+        socket
+            .set_read_timeout(Some(POLL_INTERVAL))
+            .map_err(|_| ())?;
+
+        // The TLS handshake happens on the first read or write, so a slow
+        // client doesn't hold up accepting other connections.
+        let socket = match &self.tls {
+            Some(config) => {
+                let connection = ServerConnection::new(config.clone()).map_err(|_| ())?;
+                Stream::Tls(Arc::new(Mutex::new(StreamOwned::new(connection, socket))))
+            }
+            None => Stream::Plain(socket),
+        };
+        let socket_read = socket.try_clone().map_err(|_| ())?;
+
+        Ok((
+            TcpReader::from_socket(socket_read, addr, self.shutdown.clone(), self.idle_timeout),
+            TcpWriter::from_socket(socket, addr),
+        ))
+    }
+}
+
+pub struct TcpReader {
+    reader: BufReader<Stream>,
+    socket_addr: SocketAddr,
+    shutdown: Shutdown,
+    idle_timeout: Option<Duration>,
+
+    /// When the client last sent anything, including part of a message, or
+    /// when we started waiting for its next message.
+    last_active: Instant,
+
+    /// The start of a message which hasn't been completely received yet.
+    partial: Vec<u8>,
+}
+
+impl TcpReader {
+    fn from_socket(
+        socket: Stream,
+        socket_addr: SocketAddr,
+        shutdown: Shutdown,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            reader: BufReader::new(socket),
+            socket_addr,
+            shutdown,
+            idle_timeout,
+            last_active: Instant::now(),
+            partial: Vec::new(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.idle_timeout
+            .is_some_and(|timeout| self.last_active.elapsed() >= timeout)
+    }
+}
+
+impl Reader for TcpReader {
+    fn read_message(&mut self) -> Result<String, ConnectionError> {
+        // The client is only idle while we are waiting on it, not while its
+        // last command runs.
+        self.last_active = Instant::now();
+        loop {
+            // Only read one byte past the limit so that we can tell if the
+            // message was too long without buffering the entire thing.
+            let limit = (MAX_MESSAGE_LEN + 1).saturating_sub(self.partial.len());
+            let received = self.partial.len();
+            let result = (&mut self.reader)
+                .take(limit as u64)
+                .read_until(b'\n', &mut self.partial);
+            if self.partial.len() > received {
+                self.last_active = Instant::now();
+            }
+
+            match result {
+                Ok(_) => break,
+                Err(e) => match e.kind() {
+                    // The read timed out. Anything read so far is kept in
+                    // `partial` for the next attempt. A client which has gone
+                    // quiet for too long is disconnected.
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        if self.shutdown.is_requested() || self.is_idle() {
+                            return Err(ConnectionError::ConnectionClosed);
+                        }
+                    }
+                    ErrorKind::Interrupted => {}
+                    _ => return Err(ConnectionError::ConnectionLost),
+                },
+            }
+        }
+
+        let mut bytes = std::mem::take(&mut self.partial);
+
+        if bytes.last() != Some(&b'\n') {
+            // We either hit the limit or the end of the stream without
+            // finding the end of the message.
+            return match bytes.len() > MAX_MESSAGE_LEN {
+                true => Err(ConnectionError::MessageTooLong),
+                false => Err(ConnectionError::ConnectionClosed),
+            };
+        }
+
+        bytes.pop();
+        if bytes.last() == Some(&b'\r') {
+            bytes.pop();
+        }
+
+        String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)
+    }
+
+    fn id(&self) -> String {
+        self.socket_addr.to_string()
+    }
+}
+
+pub struct TcpWriter {
+    socket: Stream,
+    socket_addr: SocketAddr,
+}
+
+impl TcpWriter {
+    fn from_socket(socket: Stream, socket_addr: SocketAddr) -> Self {
+        Self {
+            socket,
+            socket_addr,
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), ConnectionError> {
+        self.socket
+            .write_all(bytes)
+            .map_err(|_| ConnectionError::ConnectionClosed)?;
+        let _ = self.socket.flush();
+
+        Ok(())
+    }
+}
+
+impl Writer for TcpWriter {
+    fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+        // Replies are sent in the same format as `rsheet_lib` so that existing
+        // clients continue to work.
+        let message =
+            serde_json::to_string(&message).map_err(|_| ConnectionError::CouldNotConvertToJson)?;
+        self.write_bytes(message.as_bytes())
+    }
+
+    fn id(&self) -> String {
+        self.socket_addr.to_string()
+    }
+}
+
+impl LineWriter for TcpWriter {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+        self.write_bytes(format!("{}\n", line).as_bytes())
+    }
+}
@@ -0,0 +1,119 @@
+use rust_decimal::Decimal;
+
+/// The abstract syntax tree of a formula.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// An integer literal, e.g. `42`.
+    Number(i64),
+
+    /// A number with a fractional part, e.g. `1.5`.
+    Float(f64),
+
+    /// A fixed precision decimal, written with an `m` suffix, e.g. `19.99m`.
+    Decimal(Decimal),
+
+    /// A boolean literal, `TRUE` or `FALSE` in any case.
+    Bool(bool),
+
+    /// A string literal, e.g. `"hello"`.
+    Text(String),
+
+    /// The empty value, written as `()`.
+    Empty,
+
+    /// A reference to a cell or a range of cells, e.g. `A1` or `A1_B3`.
+    Reference(String),
+
+    /// Any other name, which is an error to evaluate.
+    Name(String),
+
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+
+    /// A function call, e.g. `sum(A1_A3)`.
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+
+    /// String concatenation, written as `&`.
+    Concat,
+
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Expr {
+    /// Collects every cell and range reference in the expression, in the order
+    /// that they appear.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::formula::parser::parse;
+    /// let expr = parse("A1 + sum(B1_B3)", 64).unwrap();
+    /// assert_eq!(expr.references(), vec!["A1", "B1_B3"]);
+    /// ```
+    pub fn references(&self) -> Vec<String> {
+        let mut references = Vec::new();
+        self.collect_references(&mut references);
+        references
+    }
+
+    /// Checks if any function called in the expression matches `predicate`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::formula::parser::parse;
+    /// let expr = parse("A1 + sum(B1_B3)", 64).unwrap();
+    /// assert!(expr.calls(|name| name.eq_ignore_ascii_case("SUM")));
+    /// ```
+    pub fn calls(&self, predicate: impl Fn(&str) -> bool + Copy) -> bool {
+        match self {
+            Expr::Unary(_, expr) => expr.calls(predicate),
+            Expr::Binary(_, lhs, rhs) => lhs.calls(predicate) || rhs.calls(predicate),
+            Expr::Call(name, args) => {
+                predicate(name) || args.iter().any(|arg| arg.calls(predicate))
+            }
+            _ => false,
+        }
+    }
+
+    fn collect_references(&self, references: &mut Vec<String>) {
+        match self {
+            Expr::Reference(reference) => references.push(reference.clone()),
+            Expr::Unary(_, expr) => expr.collect_references(references),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_references(references);
+                rhs.collect_references(references);
+            }
+            Expr::Call(_, args) => args
+                .iter()
+                .for_each(|arg| arg.collect_references(references)),
+            Expr::Number(_)
+            | Expr::Float(_)
+            | Expr::Decimal(_)
+            | Expr::Bool(_)
+            | Expr::Text(_)
+            | Expr::Empty
+            | Expr::Name(_) => {}
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{NaiveDateTime, TimeDelta};
+
+use crate::value::{CellError, ErrorKind, Value};
+
+use super::ast::BinaryOp;
+use super::number::Number;
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Adds to or subtracts from dates. A number added to a date is a number of
+/// days, where a fraction of a day adds a time, and subtracting two dates
+/// gives the number of days between them.
+///
+/// Returns `None` if neither value is a date, so that the caller can carry on
+/// treating them as numbers.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::formula::{ast::BinaryOp, dates::arithmetic};
+/// # use rsheet::value::Value;
+/// let date = Value::parse_date("2024-02-28").unwrap();
+/// let result = arithmetic(BinaryOp::Add, &date, &Value::Int(2));
+/// assert_eq!(result, Some(Ok(Value::parse_date("2024-03-01").unwrap())));
+/// ```
+pub fn arithmetic(op: BinaryOp, lhs: &Value, rhs: &Value) -> Option<Result<Value, CellError>> {
+    if !is_date(lhs) && !is_date(rhs) {
+        return None;
+    }
+
+    let result = match (op, Number::from_value(lhs), Number::from_value(rhs)) {
+        (BinaryOp::Add, None, Some(days)) => add_days(lhs, days),
+        (BinaryOp::Add, Some(days), None) => add_days(rhs, days),
+        (BinaryOp::Subtract, None, Some(days)) => days.neg().and_then(|days| add_days(lhs, days)),
+        (BinaryOp::Subtract, None, None) => match (lhs, rhs) {
+            (Value::Date(a), Value::Date(b)) => Ok(Value::Int((*a - *b).num_days())),
+            _ => match (to_datetime(lhs), to_datetime(rhs)) {
+                (Some(a), Some(b)) => days_between(a, b),
+                _ => Err(cant_apply(op, lhs, rhs)),
+            },
+        },
+        _ => Err(cant_apply(op, lhs, rhs)),
+    };
+    Some(result)
+}
+
+pub fn is_date(value: &Value) -> bool {
+    matches!(value, Value::Date(_) | Value::DateTime(_))
+}
+
+/// A date or datetime as a datetime, where a date is at midnight.
+pub fn to_datetime(value: &Value) -> Option<NaiveDateTime> {
+    match value {
+        Value::Date(date) => date.and_hms_opt(0, 0, 0),
+        Value::DateTime(datetime) => Some(*datetime),
+        _ => None,
+    }
+}
+
+/// Adding whole days to a date gives a date, anything else gives a datetime.
+fn add_days(date: &Value, days: Number) -> Result<Value, CellError> {
+    let days = days.to_f64();
+    let out_of_range = || CellError::new(ErrorKind::Number, "Date is out of range");
+
+    if let (Value::Date(date), true) = (date, days.fract() == 0.0) {
+        return TimeDelta::try_days(days as i64)
+            .and_then(|days| date.checked_add_signed(days))
+            .map(Value::Date)
+            .ok_or_else(out_of_range);
+    }
+
+    let millis = (days * MILLIS_PER_DAY as f64).round();
+    if !millis.is_finite() || millis.abs() >= i64::MAX as f64 {
+        return Err(out_of_range());
+    }
+
+    to_datetime(date)
+        .zip(TimeDelta::try_milliseconds(millis as i64))
+        .and_then(|(datetime, delta)| datetime.checked_add_signed(delta))
+        .map(Value::DateTime)
+        .ok_or_else(out_of_range)
+}
+
+/// The days between two datetimes, which is a float unless they are a whole
+/// number of days apart.
+fn days_between(a: NaiveDateTime, b: NaiveDateTime) -> Result<Value, CellError> {
+    let millis = (a - b).num_milliseconds();
+    Number::Int(millis)
+        .div(Number::Int(MILLIS_PER_DAY))?
+        .into_value()
+}
+
+fn cant_apply(op: BinaryOp, lhs: &Value, rhs: &Value) -> CellError {
+    CellError::value(format!("Can't apply {:?} to {} and {}", op, lhs, rhs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> Value {
+        Value::parse_date(text).unwrap()
+    }
+
+    #[test]
+    fn test_add_days() {
+        let add = |lhs: &Value, rhs: &Value| arithmetic(BinaryOp::Add, lhs, rhs).unwrap();
+
+        assert_eq!(
+            add(&date("2024-02-28"), &Value::Int(2)),
+            Ok(date("2024-03-01"))
+        );
+        assert_eq!(
+            add(&Value::Int(-1), &date("2024-01-01")),
+            Ok(date("2023-12-31"))
+        );
+        assert_eq!(
+            add(&date("2024-01-01"), &Value::Float(0.5)),
+            Ok(date("2024-01-01T12:00"))
+        );
+        assert_eq!(
+            add(&date("2024-01-01T18:00"), &Value::Int(1)),
+            Ok(date("2024-01-02T18:00"))
+        );
+        assert!(add(&date("2024-01-01"), &Value::Int(i64::MAX)).is_err());
+        assert!(add(&date("2024-01-01"), &date("2024-01-01")).is_err());
+    }
+
+    #[test]
+    fn test_subtract() {
+        let sub = |lhs: &Value, rhs: &Value| arithmetic(BinaryOp::Subtract, lhs, rhs).unwrap();
+
+        assert_eq!(
+            sub(&date("2024-03-01"), &Value::Int(1)),
+            Ok(date("2024-02-29"))
+        );
+        assert_eq!(
+            sub(&date("2024-03-01"), &date("2024-02-01")),
+            Ok(Value::Int(29))
+        );
+        assert_eq!(
+            sub(&date("2024-01-02T06:00"), &date("2024-01-01")),
+            Ok(Value::Float(1.25))
+        );
+        assert!(sub(&Value::Int(1), &date("2024-01-01")).is_err());
+    }
+
+    #[test]
+    fn test_not_dates() {
+        assert_eq!(
+            arithmetic(BinaryOp::Add, &Value::Int(1), &Value::Int(2)),
+            None
+        );
+        assert!(
+            arithmetic(BinaryOp::Multiply, &date("2024-01-01"), &Value::Int(2))
+                .unwrap()
+                .is_err()
+        );
+    }
+}
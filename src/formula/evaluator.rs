@@ -0,0 +1,464 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::limits::Budget;
+use crate::utils;
+use crate::value::{Argument, CellError, ErrorKind, Value};
+
+use super::ast::{BinaryOp, Expr, UnaryOp};
+use super::dates;
+use super::functions;
+use super::number::Number;
+
+/// Evaluates expression trees against the values of the cells they reference.
+/// Errors are returned as `Err` so that they short-circuit the evaluation,
+/// and are turned into a `Value::Error` by the caller.
+///
+/// Evaluation stops with an error once it runs out of time or steps in its
+/// budget.
+pub struct Evaluator<'a> {
+    variables: &'a HashMap<String, Argument>,
+    budget: &'a Budget,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(variables: &'a HashMap<String, Argument>, budget: &'a Budget) -> Self {
+        Self { variables, budget }
+    }
+
+    /// Evaluates an expression. References to ranges evaluate to a vector or
+    /// a matrix, as do operators applied to ranges and functions such as
+    /// `FILTER`. Everything else evaluates to a single value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use rsheet::formula::{evaluator::Evaluator, parser::parse};
+    /// # use rsheet::limits::Budget;
+    /// # use rsheet::value::{Argument, Value};
+    /// let variables = HashMap::from([("A1".to_string(), Argument::Value(Value::Int(2)))]);
+    /// let budget = Budget::default();
+    /// let result = Evaluator::new(&variables, &budget).evaluate(&parse("A1 * 3", 64).unwrap());
+    /// assert_eq!(result, Ok(Argument::Value(Value::Int(6))));
+    /// ```
+    pub fn evaluate(&self, expr: &Expr) -> Result<Argument, CellError> {
+        self.budget.spend(1)?;
+
+        match expr {
+            Expr::Number(number) => Ok(Argument::Value(Value::Int(*number))),
+            Expr::Float(number) => Ok(Argument::Value(Value::Float(*number))),
+            Expr::Decimal(number) => Ok(Argument::Value(Value::Decimal(*number))),
+            Expr::Bool(value) => Ok(Argument::Value(Value::Bool(*value))),
+            Expr::Text(text) => Ok(Argument::Value(Value::String(text.clone()))),
+            Expr::Empty => Ok(Argument::Value(Value::None)),
+            Expr::Reference(reference) => self.resolve(reference),
+            Expr::Name(name) => Err(CellError::new(
+                ErrorKind::Name,
+                format!("Variable not found: {}", name),
+            )),
+            Expr::Unary(op, expr) => match self.evaluate(expr)? {
+                Argument::Value(value) => unary(*op, value).map(Argument::Value),
+                range => elementwise(
+                    self.budget,
+                    range,
+                    Argument::Value(Value::None),
+                    |value, _| unary(*op, value),
+                ),
+            },
+            Expr::Binary(op, lhs, rhs) => match (self.evaluate(lhs)?, self.evaluate(rhs)?) {
+                (Argument::Value(lhs), Argument::Value(rhs)) => {
+                    binary(*op, lhs, rhs).map(Argument::Value)
+                }
+                (lhs, rhs) => elementwise(self.budget, lhs, rhs, |lhs, rhs| binary(*op, lhs, rhs)),
+            },
+            Expr::Call(name, args) => match name.to_ascii_uppercase().as_str() {
+                "IF" => self.evaluate_if(args),
+                "IFERROR" => self.evaluate_iferror(args),
+                "ISERROR" => self.evaluate_iserror(args),
+                _ => {
+                    let args = args
+                        .iter()
+                        .map(|arg| self.evaluate(arg))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    // Functions work through every value they are given.
+                    self.budget.spend(args.iter().map(size).sum())?;
+                    functions::call(name, args, self.budget)
+                }
+            },
+        }
+    }
+
+    /// `IF(condition, then, [else])`. Only the branch that is chosen is
+    /// evaluated, so an error in the other branch doesn't matter. Without an
+    /// `else` branch a false condition produces `FALSE`.
+    fn evaluate_if(&self, args: &[Expr]) -> Result<Argument, CellError> {
+        if !(2..=3).contains(&args.len()) {
+            return Err(CellError::value(format!(
+                "IF expects 2 to 3 arguments but got {}",
+                args.len()
+            )));
+        }
+
+        let condition = self.evaluate_value(&args[0])?;
+        match (functions::is_truthy(&condition)?, args.get(2)) {
+            (true, _) => self.evaluate(&args[1]),
+            (false, Some(otherwise)) => self.evaluate(otherwise),
+            (false, None) => Ok(Argument::Value(Value::Bool(false))),
+        }
+    }
+
+    /// `IFERROR(value, fallback)`. The fallback is only evaluated if the value
+    /// is an error.
+    fn evaluate_iferror(&self, args: &[Expr]) -> Result<Argument, CellError> {
+        if args.len() != 2 {
+            return Err(CellError::value(format!(
+                "IFERROR expects 2 arguments but got {}",
+                args.len()
+            )));
+        }
+
+        match self.evaluate(&args[0]) {
+            // Running out of time or steps can't be caught.
+            Err(e) if e.kind == ErrorKind::Calc => Err(e),
+            Ok(Argument::Value(Value::Error(_))) | Err(_) => self.evaluate(&args[1]),
+            Ok(value) => Ok(value),
+        }
+    }
+
+    /// `ISERROR(value)`. Checks if a value is an error rather than letting the
+    /// error spread. A range is checked value by value.
+    fn evaluate_iserror(&self, args: &[Expr]) -> Result<Argument, CellError> {
+        if args.len() != 1 {
+            return Err(CellError::value(format!(
+                "ISERROR expects 1 argument but got {}",
+                args.len()
+            )));
+        }
+
+        // Running out of time or steps isn't an error in the value.
+        let result = match self.evaluate(&args[0]) {
+            Err(e) if e.kind == ErrorKind::Calc => return Err(e),
+            Err(_) => Argument::Value(Value::Bool(true)),
+            Ok(argument) => {
+                let rows = argument.into_rows().into_iter().map(|row| {
+                    row.into_iter()
+                        .map(|value| Value::Bool(matches!(value, Value::Error(_))))
+                        .collect()
+                });
+                Argument::from_rows(rows.collect())
+            }
+        };
+        Ok(result)
+    }
+
+    /// Evaluates an expression which must produce a single value, such as an
+    /// operand of an arithmetic operator.
+    fn evaluate_value(&self, expr: &Expr) -> Result<Value, CellError> {
+        match self.evaluate(expr)? {
+            Argument::Value(value) => Ok(value),
+            Argument::Vector(_) | Argument::Matrix(_) => {
+                Err(CellError::value("A range can't be used as a single value"))
+            }
+        }
+    }
+
+    /// The value of a cell or range. Vectors don't say whether they are a row
+    /// or a column, so a row is turned into a matrix with one row.
+    fn resolve(&self, reference: &str) -> Result<Argument, CellError> {
+        match self.variables.get(reference) {
+            // A cell containing an error makes the whole formula an error.
+            Some(Argument::Value(Value::Error(e))) => Err(e.clone()),
+            Some(Argument::Vector(vector)) if is_row(reference) => {
+                Ok(Argument::Matrix(vec![vector.clone()]))
+            }
+            Some(argument) => Ok(argument.clone()),
+            None => Err(CellError::new(
+                ErrorKind::Reference,
+                format!("Reference not found: {}", reference),
+            )),
+        }
+    }
+}
+
+/// The number of values in an argument.
+fn size(argument: &Argument) -> u64 {
+    let len = match argument {
+        Argument::Value(_) => 1,
+        Argument::Vector(vector) => vector.len(),
+        Argument::Matrix(matrix) => matrix.iter().map(|row| row.len()).sum(),
+    };
+    len as u64
+}
+
+fn is_row(reference: &str) -> bool {
+    let position = |cell| utils::cell_position(cell).unwrap_or_default();
+    match reference.split_once('_') {
+        Some((start, end)) => {
+            let ((start_col, start_row), (end_col, end_row)) = (position(start), position(end));
+            start_row == end_row && start_col != end_col
+        }
+        None => false,
+    }
+}
+
+/// Applies an operation to each pair of values in two ranges, producing a
+/// range of the results. A single value, row or column is repeated to match
+/// the size of the other operand, so `A1_A3 * 2` doubles each cell and
+/// `A1_A3 * B1_D1` is a 3 by 3 table.
+///
+/// An error only affects the value it is in, rather than the whole result.
+fn elementwise<F>(
+    budget: &Budget,
+    lhs: Argument,
+    rhs: Argument,
+    op: F,
+) -> Result<Argument, CellError>
+where
+    F: Fn(Value, Value) -> Result<Value, CellError>,
+{
+    let (lhs, rhs) = (lhs.into_rows(), rhs.into_rows());
+    let size = |rows: &Vec<Vec<Value>>| (rows.len(), rows.first().map_or(0, |row| row.len()));
+    let ((lhs_height, lhs_width), (rhs_height, rhs_width)) = (size(&lhs), size(&rhs));
+
+    let broadcast = |a: usize, b: usize| match (a, b) {
+        _ if a == b => Ok(a),
+        (1, n) | (n, 1) => Ok(n),
+        _ => Err(CellError::value(
+            "Ranges of different sizes can't be combined",
+        )),
+    };
+    let height = broadcast(lhs_height, rhs_height)?;
+    let width = broadcast(lhs_width, rhs_width)?;
+    if height == 0 || width == 0 {
+        return Err(CellError::value("A range can't be empty"));
+    }
+    budget.spend((height * width) as u64)?;
+
+    let at = |rows: &Vec<Vec<Value>>, row: usize, col: usize| {
+        rows[row.min(rows.len() - 1)][col.min(rows[0].len() - 1)].clone()
+    };
+    let rows = (0..height)
+        .map(|row| {
+            (0..width)
+                .map(|col| match (at(&lhs, row, col), at(&rhs, row, col)) {
+                    (error @ Value::Error(_), _) | (_, error @ Value::Error(_)) => error,
+                    (lhs, rhs) => op(lhs, rhs).unwrap_or_else(Value::Error),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Argument::from_rows(rows))
+}
+
+fn unary(op: UnaryOp, value: Value) -> Result<Value, CellError> {
+    match (op, Number::from_value(&value)) {
+        (UnaryOp::Negate, Some(number)) => number.neg()?.into_value(),
+        (UnaryOp::Negate, None) => Err(CellError::value(format!("Can't negate {}", value))),
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Value, rhs: Value) -> Result<Value, CellError> {
+    use BinaryOp::*;
+
+    match (op, lhs, rhs) {
+        (Concat, lhs, rhs) => concat(lhs, rhs),
+
+        // Like Rhai, adding anything to a string concatenates them.
+        (Add, lhs @ Value::String(_), rhs) | (Add, lhs, rhs @ Value::String(_)) => concat(lhs, rhs),
+
+        (Equal | NotEqual, lhs, rhs) => {
+            let equal = match compare(&lhs, &rhs) {
+                Some(ordering) => ordering == Ordering::Equal,
+                None => lhs == rhs,
+            };
+            Ok(Value::Bool(if op == Equal { equal } else { !equal }))
+        }
+
+        (Less | LessEqual | Greater | GreaterEqual, lhs, rhs) => {
+            let ordering = compare(&lhs, &rhs)
+                .ok_or_else(|| CellError::value(format!("Can't compare {} and {}", lhs, rhs)))?;
+            Ok(Value::Bool(match op {
+                Less => ordering == Ordering::Less,
+                LessEqual => ordering != Ordering::Greater,
+                Greater => ordering == Ordering::Greater,
+                _ => ordering != Ordering::Less,
+            }))
+        }
+
+        (op, lhs, rhs) => {
+            if let Some(result) = dates::arithmetic(op, &lhs, &rhs) {
+                return result;
+            }
+
+            let (a, b) = match (Number::from_value(&lhs), Number::from_value(&rhs)) {
+                (Some(a), Some(b)) => (a, b),
+                _ => {
+                    let message = format!("Can't apply {:?} to {} and {}", op, lhs, rhs);
+                    return Err(CellError::value(message));
+                }
+            };
+            let result = match op {
+                Add => a.add(b),
+                Subtract => a.sub(b),
+                Multiply => a.mul(b),
+                Divide => a.div(b),
+                Remainder => a.rem(b),
+                Power => a.pow(b),
+                _ => unreachable!("Every other operator is handled above."),
+            };
+            result?.into_value()
+        }
+    }
+}
+
+/// Orders two values of the same kind. Numbers of different kinds are
+/// compared by their value, as are dates and datetimes. Anything else can't
+/// be ordered.
+pub fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ if dates::is_date(lhs) && dates::is_date(rhs) => {
+            Some(dates::to_datetime(lhs)?.cmp(&dates::to_datetime(rhs)?))
+        }
+        _ => Some(Number::from_value(lhs)?.compare(Number::from_value(rhs)?)),
+    }
+}
+
+fn concat(lhs: Value, rhs: Value) -> Result<Value, CellError> {
+    Ok(Value::String(format!("{}{}", to_text(lhs)?, to_text(rhs)?)))
+}
+
+fn to_text(value: Value) -> Result<String, CellError> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::None => Ok(String::new()),
+        Value::Error(e) => Err(e),
+        value => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formula::parser;
+    use crate::limits::Limits;
+
+    fn parse(formula: &str) -> Result<Expr, String> {
+        parser::parse(formula, Limits::default().max_nesting)
+    }
+
+    fn evaluate(formula: &str) -> Result<Argument, CellError> {
+        let variables = HashMap::from([
+            ("A1".to_string(), Argument::Value(Value::Int(5))),
+            (
+                "B1".to_string(),
+                Argument::Value(Value::Error(CellError::value("Broken"))),
+            ),
+            (
+                "A1_A3".to_string(),
+                Argument::Vector(vec![
+                    Value::Int(1),
+                    Value::Error(CellError::value("Broken")),
+                    Value::Int(3),
+                ]),
+            ),
+            (
+                "A1_B1".to_string(),
+                Argument::Vector(vec![Value::Int(10), Value::Int(20)]),
+            ),
+            (
+                "C1_C2".to_string(),
+                Argument::Vector(vec![Value::Int(1), Value::Int(2)]),
+            ),
+        ]);
+        Evaluator::new(&variables, &Budget::default()).evaluate(&parse(formula).unwrap())
+    }
+
+    fn int(i: i64) -> Result<Argument, CellError> {
+        Ok(Argument::Value(Value::Int(i)))
+    }
+
+    #[test]
+    fn test_if() {
+        assert_eq!(evaluate("IF(A1 > 3, 1, 2)"), int(1));
+        assert_eq!(evaluate("IF(A1 > 9, 1, 2)"), int(2));
+        assert_eq!(
+            evaluate("IF(0, 1)"),
+            Ok(Argument::Value(Value::Bool(false)))
+        );
+        assert_eq!(evaluate("IF(A1 == 5.0, 1, 2)"), int(1));
+
+        // The branch that isn't taken isn't evaluated.
+        assert_eq!(evaluate("IF(1, A1, B1)"), int(5));
+        assert_eq!(evaluate("IF(0, 1 / 0, 3)"), int(3));
+
+        assert!(evaluate("IF(B1, 1, 2)").is_err());
+        assert!(evaluate("IF(\"yes\", 1, 2)").is_err());
+        assert!(evaluate("IF(1)").is_err());
+    }
+
+    #[test]
+    fn test_iferror() {
+        assert_eq!(evaluate("IFERROR(B1, 7)"), int(7));
+        assert_eq!(evaluate("IFERROR(1 / 0, A1)"), int(5));
+        assert_eq!(evaluate("IFERROR(A1, 1 / 0)"), int(5));
+        assert!(evaluate("IFERROR(B1, B1)").is_err());
+        assert!(evaluate("IFERROR(A1)").is_err());
+    }
+
+    #[test]
+    fn test_elementwise() {
+        let broken = Value::Error(CellError::value("Broken"));
+
+        assert_eq!(
+            evaluate("A1_A3 * 2"),
+            Ok(Argument::Vector(vec![
+                Value::Int(2),
+                broken.clone(),
+                Value::Int(6)
+            ]))
+        );
+        assert_eq!(
+            evaluate("-A1_B1"),
+            Ok(Argument::Matrix(vec![vec![
+                Value::Int(-10),
+                Value::Int(-20)
+            ]]))
+        );
+        assert_eq!(
+            evaluate("A1_A3 + A1_B1"),
+            Ok(Argument::Matrix(vec![
+                vec![Value::Int(11), Value::Int(21)],
+                vec![broken.clone(), broken],
+                vec![Value::Int(13), Value::Int(23)],
+            ]))
+        );
+        assert_eq!(evaluate("SUM(A1_B1 / 10)"), int(3));
+        assert!(evaluate("A1_A3 + C1_C2").is_err());
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let variables = HashMap::from([(
+            "A1_A3".to_string(),
+            Argument::Vector(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+        )]);
+        let evaluate = |formula: &str, max_steps: u64| {
+            let budget = Budget::new(&Limits {
+                max_steps,
+                ..Limits::default()
+            });
+            Evaluator::new(&variables, &budget).evaluate(&parse(formula).unwrap())
+        };
+
+        assert!(evaluate("1 + 2", 3).is_ok());
+        assert!(evaluate("1 + 2 + 3", 3).is_err());
+
+        // Every value in a range counts.
+        assert!(evaluate("SUM(A1_A3)", 5).is_ok());
+        assert!(evaluate("SUM(A1_A3 * 2)", 8).is_err());
+    }
+}
@@ -0,0 +1,1084 @@
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use chrono::{Datelike, Local, Months, NaiveDate};
+
+use crate::limits::Budget;
+use crate::value::{Argument, CellError, ErrorKind, Value};
+
+use super::evaluator;
+use super::number::Number;
+
+/// Calls a built-in function. Function names are case insensitive, so `sum`
+/// and `SUM` are the same function.
+///
+/// `IF` and `IFERROR` are not here, as they need their arguments before they
+/// are evaluated. They are handled by the evaluator.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::formula::functions::call;
+/// # use rsheet::limits::Budget;
+/// # use rsheet::value::{Argument, Value};
+/// let vector = Argument::Vector(vec![Value::Int(1), Value::Int(2)]);
+/// let result = call("sum", vec![vector], &Budget::default());
+/// assert_eq!(result, Ok(Argument::Value(Value::Int(3))));
+/// ```
+pub fn call(name: &str, args: Vec<Argument>, budget: &Budget) -> Result<Argument, CellError> {
+    let value = match name.to_ascii_uppercase().as_str() {
+        // Statistical
+        "SUM" => sum(&args)?,
+        "AVERAGE" => average(&args)?,
+        "MIN" => min(&args)?,
+        "MAX" => max(&args)?,
+        "COUNT" => count(&args)?,
+        "MEDIAN" => median(&args)?,
+        "STDEV" => stdev(&args)?,
+
+        // Math
+        "RAND" => random(&args)?,
+
+        // Logical
+        "AND" => and(&args)?,
+        "OR" => or(&args)?,
+
+        // Text
+        "CONCAT" => concat(&args)?,
+        "LEFT" => left(&args)?,
+        "RIGHT" => right(&args)?,
+        "LEN" => len(&args)?,
+        "UPPER" => upper(&args)?,
+
+        // Lookup
+        "VLOOKUP" => vlookup(&args)?,
+        "HLOOKUP" => hlookup(&args)?,
+        "INDEX" => index(&args)?,
+        "MATCH" => match_(&args)?,
+
+        // Array
+        "FILTER" => return filter(&args),
+        "SORT" => return sort(&args),
+
+        // Date
+        "NOW" => now(&args)?,
+        "TODAY" => today(&args)?,
+        "DATE" => date(&args)?,
+        "DATEDIF" => datedif(&args)?,
+        "EDATE" => edate(&args)?,
+
+        "SLEEP_THEN" => return sleep_then(args, budget),
+        _ => {
+            let message = format!("Function not found: {}", name);
+            return Err(CellError::new(ErrorKind::Name, message));
+        }
+    };
+    Ok(Argument::Value(value))
+}
+
+/// Functions whose result changes over time, even when their arguments don't.
+/// Cells calling them are recalculated regularly.
+const VOLATILE: &[&str] = &["NOW", "RAND", "TODAY"];
+
+pub fn is_volatile(name: &str) -> bool {
+    VOLATILE
+        .iter()
+        .any(|volatile| volatile.eq_ignore_ascii_case(name))
+}
+
+/// Checks if a value counts as true. Numbers are true unless they are zero,
+/// and empty cells are false.
+pub fn is_truthy(value: &Value) -> Result<bool, CellError> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        Value::Int(_) | Value::Float(_) | Value::Decimal(_) => {
+            Ok(!Number::from_value(value).is_some_and(Number::is_zero))
+        }
+        Value::None => Ok(false),
+        Value::Error(e) => Err(e.clone()),
+        value => Err(CellError::value(format!(
+            "Expected a condition but got {}",
+            value
+        ))),
+    }
+}
+
+/// Every value in the arguments, with vectors and matrices flattened.
+fn values(args: &[Argument]) -> impl Iterator<Item = &Value> {
+    args.iter()
+        .flat_map(|arg| -> Box<dyn Iterator<Item = &Value>> {
+            match arg {
+                Argument::Value(value) => Box::new(std::iter::once(value)),
+                Argument::Vector(vector) => Box::new(vector.iter()),
+                Argument::Matrix(matrix) => Box::new(matrix.iter().flatten()),
+            }
+        })
+}
+
+/// Every number in the arguments. Like in other spreadsheets, empty cells and
+/// text are skipped, but an error in any cell is an error.
+fn numbers(args: &[Argument]) -> Result<Vec<Number>, CellError> {
+    let mut numbers = Vec::new();
+    for value in values(args) {
+        match value {
+            Value::Error(e) => return Err(e.clone()),
+            value => numbers.extend(Number::from_value(value)),
+        }
+    }
+    Ok(numbers)
+}
+
+fn expect_args(name: &str, args: &[Argument], min: usize, max: usize) -> Result<(), CellError> {
+    if (min..=max).contains(&args.len()) {
+        return Ok(());
+    }
+
+    let expected = match min == max {
+        true => min.to_string(),
+        false => format!("{} to {}", min, max),
+    };
+    Err(CellError::value(format!(
+        "{} expects {} arguments but got {}",
+        name,
+        expected,
+        args.len()
+    )))
+}
+
+/// The argument at `index` as a single value, or `None` if it wasn't given.
+fn value_arg(args: &[Argument], index: usize) -> Result<Option<&Value>, CellError> {
+    match args.get(index) {
+        Some(Argument::Value(Value::Error(e))) => Err(e.clone()),
+        Some(Argument::Value(value)) => Ok(Some(value)),
+        Some(_) => Err(CellError::value(format!(
+            "Argument {} must be a single value",
+            index + 1
+        ))),
+        None => Ok(None),
+    }
+}
+
+fn int_arg(args: &[Argument], index: usize, default: Option<i64>) -> Result<i64, CellError> {
+    match (value_arg(args, index)?, default) {
+        (Some(Value::Int(i)), _) => Ok(*i),
+        (None, Some(default)) => Ok(default),
+        (Some(value), _) => Err(CellError::value(format!(
+            "Argument {} must be a number, not {}",
+            index + 1,
+            value
+        ))),
+        (None, None) => Err(missing(index)),
+    }
+}
+
+fn text_arg(args: &[Argument], index: usize) -> Result<String, CellError> {
+    match value_arg(args, index)? {
+        Some(value) => to_text(value),
+        None => Err(missing(index)),
+    }
+}
+
+fn missing(index: usize) -> CellError {
+    CellError::value(format!("Argument {} is missing", index + 1))
+}
+
+fn to_text(value: &Value) -> Result<String, CellError> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::None => Ok(String::new()),
+        Value::Error(e) => Err(e.clone()),
+        value => Ok(value.to_string()),
+    }
+}
+
+/// The argument at `index` as a date. The time of a datetime is ignored.
+fn date_arg(args: &[Argument], index: usize) -> Result<NaiveDate, CellError> {
+    match value_arg(args, index)? {
+        Some(Value::Date(date)) => Ok(*date),
+        Some(Value::DateTime(datetime)) => Ok(datetime.date()),
+        Some(value) => Err(CellError::value(format!(
+            "Argument {} must be a date, not {}",
+            index + 1,
+            value
+        ))),
+        None => Err(missing(index)),
+    }
+}
+
+fn total(numbers: &[Number]) -> Result<Number, CellError> {
+    numbers
+        .iter()
+        .try_fold(Number::Int(0), |total, number| total.add(*number))
+}
+
+/// Sums every number in the arguments. Unlike the `sum` provided to Rhai,
+/// empty cells and text are skipped rather than being an error.
+fn sum(args: &[Argument]) -> Result<Value, CellError> {
+    total(&numbers(args)?)?.into_value()
+}
+
+fn average(args: &[Argument]) -> Result<Value, CellError> {
+    let numbers = numbers(args)?;
+    let count = Number::Int(numbers.len() as i64);
+    total(&numbers)?.div(count)?.into_value()
+}
+
+/// The smallest number, or 0 if there are no numbers.
+fn min(args: &[Argument]) -> Result<Value, CellError> {
+    numbers(args)?
+        .into_iter()
+        .min_by(|a, b| a.compare(*b))
+        .unwrap_or(Number::Int(0))
+        .into_value()
+}
+
+/// The largest number, or 0 if there are no numbers.
+fn max(args: &[Argument]) -> Result<Value, CellError> {
+    numbers(args)?
+        .into_iter()
+        .max_by(|a, b| a.compare(*b))
+        .unwrap_or(Number::Int(0))
+        .into_value()
+}
+
+/// The number of cells containing a number.
+fn count(args: &[Argument]) -> Result<Value, CellError> {
+    let count = values(args)
+        .filter(|value| Number::from_value(value).is_some())
+        .count();
+    Ok(Value::Int(count as i64))
+}
+
+fn median(args: &[Argument]) -> Result<Value, CellError> {
+    let mut numbers = numbers(args)?;
+    if numbers.is_empty() {
+        return Err(CellError::value("MEDIAN needs at least one number"));
+    }
+
+    numbers.sort_by(|a, b| a.compare(*b));
+    let middle = numbers.len() / 2;
+    match numbers.len() % 2 {
+        1 => numbers[middle].into_value(),
+        _ => numbers[middle - 1]
+            .add(numbers[middle])?
+            .div(Number::Int(2))?
+            .into_value(),
+    }
+}
+
+/// The sample standard deviation.
+fn stdev(args: &[Argument]) -> Result<Value, CellError> {
+    let numbers: Vec<f64> = numbers(args)?.into_iter().map(Number::to_f64).collect();
+    if numbers.len() < 2 {
+        return Err(CellError::new(
+            ErrorKind::DivideByZero,
+            "STDEV needs at least two numbers",
+        ));
+    }
+
+    let n = numbers.len() as f64;
+    let mean = numbers.iter().sum::<f64>() / n;
+    let variance = numbers.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    Number::Float(variance.sqrt()).into_value()
+}
+
+fn and(args: &[Argument]) -> Result<Value, CellError> {
+    if args.is_empty() {
+        return Err(CellError::value("AND expects at least 1 argument"));
+    }
+    for value in values(args) {
+        if !is_truthy(value)? {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+fn or(args: &[Argument]) -> Result<Value, CellError> {
+    if args.is_empty() {
+        return Err(CellError::value("OR expects at least 1 argument"));
+    }
+    for value in values(args) {
+        if is_truthy(value)? {
+            return Ok(Value::Bool(true));
+        }
+    }
+    Ok(Value::Bool(false))
+}
+
+/// Joins every value in the arguments together.
+fn concat(args: &[Argument]) -> Result<Value, CellError> {
+    let text = values(args)
+        .map(to_text)
+        .collect::<Result<Vec<_>, _>>()?
+        .concat();
+    Ok(Value::String(text))
+}
+
+/// The first `n` characters of the text, where `n` defaults to 1.
+fn left(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("LEFT", args, 1, 2)?;
+    let text = text_arg(args, 0)?;
+    let n = usize::try_from(int_arg(args, 1, Some(1))?)
+        .map_err(|_| CellError::value("LEFT can't take a negative number of characters"))?;
+
+    Ok(Value::String(text.chars().take(n).collect()))
+}
+
+/// The last `n` characters of the text, where `n` defaults to 1.
+fn right(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("RIGHT", args, 1, 2)?;
+    let text = text_arg(args, 0)?;
+    let n = usize::try_from(int_arg(args, 1, Some(1))?)
+        .map_err(|_| CellError::value("RIGHT can't take a negative number of characters"))?;
+
+    let skip = text.chars().count().saturating_sub(n);
+    Ok(Value::String(text.chars().skip(skip).collect()))
+}
+
+fn len(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("LEN", args, 1, 1)?;
+    Ok(Value::Int(text_arg(args, 0)?.chars().count() as i64))
+}
+
+fn upper(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("UPPER", args, 1, 1)?;
+    Ok(Value::String(text_arg(args, 0)?.to_uppercase()))
+}
+
+/// Orders two values for lookups. Text is compared case insensitively, and
+/// values of different types can't be ordered.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => Some(a.to_lowercase().cmp(&b.to_lowercase())),
+        _ => evaluator::compare(a, b),
+    }
+}
+
+/// Finds the position of `key` in `values`. With `match_type` 0 the key must
+/// be found exactly. With 1 the values are assumed to be sorted in ascending
+/// order and the position of the largest value less than or equal to the key
+/// is found. With -1 they are assumed to be sorted in descending order and the
+/// position of the smallest value greater than or equal to the key is found.
+fn find(key: &Value, values: &[Value], match_type: i64) -> Option<usize> {
+    let ordering = |value| compare(value, key);
+    match match_type {
+        0 => values
+            .iter()
+            .position(|value| ordering(value) == Some(Ordering::Equal)),
+        1 => values
+            .iter()
+            .take_while(|value| ordering(value) != Some(Ordering::Greater))
+            .enumerate()
+            .filter(|(_, value)| ordering(value).is_some())
+            .last()
+            .map(|(i, _)| i),
+        _ => values
+            .iter()
+            .take_while(|value| ordering(value) != Some(Ordering::Less))
+            .enumerate()
+            .filter(|(_, value)| ordering(value).is_some())
+            .last()
+            .map(|(i, _)| i),
+    }
+}
+
+/// The table argument of a lookup as rows of values.
+fn table_arg(arg: Option<&Argument>) -> Result<Vec<Vec<Value>>, CellError> {
+    match arg {
+        Some(arg) => Ok(arg.clone().into_rows()),
+        None => Err(CellError::value("The table argument is missing")),
+    }
+}
+
+/// Converts a 1-based index from a formula into a 0-based index, checking it
+/// is within `len`.
+fn position(index: i64, len: usize) -> Result<usize, CellError> {
+    match usize::try_from(index) {
+        Ok(index) if (1..=len).contains(&index) => Ok(index - 1),
+        _ => Err(CellError::new(
+            ErrorKind::Reference,
+            format!("Index {} is out of range", index),
+        )),
+    }
+}
+
+/// Looks up a key in the first column of a table and returns the value in the
+/// same row of another column. `VLOOKUP(key, table, column, [approximate])`.
+fn vlookup(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("VLOOKUP", args, 3, 4)?;
+    let table = table_arg(args.get(1))?;
+    lookup("VLOOKUP", args, table)
+}
+
+/// Looks up a key in the first row of a table and returns the value in the
+/// same column of another row. `HLOOKUP(key, table, row, [approximate])`.
+fn hlookup(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("HLOOKUP", args, 3, 4)?;
+    let table = table_arg(args.get(1))?;
+
+    // Transposing the table lets HLOOKUP share the implementation of VLOOKUP.
+    let width = table.first().map(|row| row.len()).unwrap_or_default();
+    let transposed = (0..width)
+        .map(|col| table.iter().map(|row| row[col].clone()).collect())
+        .collect();
+    lookup("HLOOKUP", args, transposed)
+}
+
+fn lookup(name: &str, args: &[Argument], rows: Vec<Vec<Value>>) -> Result<Value, CellError> {
+    let key = value_arg(args, 0)?.cloned().unwrap_or_default();
+    let width = rows.first().map(|row| row.len()).unwrap_or_default();
+    let column = position(int_arg(args, 2, None)?, width)?;
+    let match_type = match is_truthy(value_arg(args, 3)?.unwrap_or(&Value::Int(1)))? {
+        true => 1,
+        false => 0,
+    };
+
+    let keys: Vec<Value> = rows.iter().map(|row| row[0].clone()).collect();
+    match find(&key, &keys, match_type) {
+        Some(row) => Ok(rows[row][column].clone()),
+        None => Err(CellError::new(
+            ErrorKind::NotAvailable,
+            format!("{} couldn't find {}", name, key),
+        )),
+    }
+}
+
+/// The value at a position in a range. `INDEX(range, row, [column])`, where
+/// the position is 1-based.
+fn index(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("INDEX", args, 2, 3)?;
+    let row = int_arg(args, 1, None)?;
+    let column = int_arg(args, 2, Some(1))?;
+
+    match &args[0] {
+        Argument::Vector(vector) => {
+            position(column, 1)?;
+            Ok(vector[position(row, vector.len())?].clone())
+        }
+        // With a single row, the only index given is the column.
+        Argument::Matrix(matrix) if matrix.len() == 1 && args.len() == 2 => {
+            Ok(matrix[0][position(row, matrix[0].len())?].clone())
+        }
+        Argument::Matrix(matrix) => {
+            let row = &matrix[position(row, matrix.len())?];
+            Ok(row[position(column, row.len())?].clone())
+        }
+        Argument::Value(value) => {
+            position(row, 1)?;
+            position(column, 1)?;
+            Ok(value.clone())
+        }
+    }
+}
+
+/// The 1-based position of a value in a vector.
+/// `MATCH(key, range, [match_type])`, see `find` for the match types.
+fn match_(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("MATCH", args, 2, 3)?;
+    let key = value_arg(args, 0)?.cloned().unwrap_or_default();
+    let match_type = int_arg(args, 2, Some(1))?;
+    let values: Vec<Value> = values(&args[1..2]).cloned().collect();
+
+    match find(&key, &values, match_type) {
+        Some(index) => Ok(Value::Int(index as i64 + 1)),
+        None => Err(CellError::new(
+            ErrorKind::NotAvailable,
+            format!("MATCH couldn't find {}", key),
+        )),
+    }
+}
+
+/// The rows of a range where a condition is true, or the columns if the range
+/// is a single row. `FILTER(range, include)`, where `include` is a range of
+/// conditions with one per row or column.
+fn filter(args: &[Argument]) -> Result<Argument, CellError> {
+    expect_args("FILTER", args, 2, 2)?;
+    let rows = args[0].clone().into_rows();
+    let include = values(&args[1..])
+        .map(is_truthy)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let filtered: Vec<Vec<Value>> = if rows.len() == include.len() {
+        rows.into_iter()
+            .zip(&include)
+            .filter(|(_, include)| **include)
+            .map(|(row, _)| row)
+            .collect()
+    } else if rows.len() == 1 && rows[0].len() == include.len() {
+        let row = rows[0]
+            .iter()
+            .zip(&include)
+            .filter(|(_, include)| **include);
+        vec![row.map(|(value, _)| value.clone()).collect()]
+    } else {
+        return Err(CellError::value(
+            "FILTER expects a condition for each row or column",
+        ));
+    };
+
+    match filtered.iter().all(|row| row.is_empty()) {
+        true => Err(CellError::new(
+            ErrorKind::Calc,
+            "FILTER found no matching values",
+        )),
+        false => Ok(Argument::from_rows(filtered)),
+    }
+}
+
+/// Sorts the rows of a range by one of its columns.
+/// `SORT(range, [column], [order])`, where the column defaults to the first
+/// and the order is 1 for ascending or -1 for descending.
+fn sort(args: &[Argument]) -> Result<Argument, CellError> {
+    expect_args("SORT", args, 1, 3)?;
+    let column = int_arg(args, 1, Some(1))?;
+    let descending = match int_arg(args, 2, Some(1))? {
+        1 => false,
+        -1 => true,
+        order => {
+            let message = format!("SORT order must be 1 or -1 but got {}", order);
+            return Err(CellError::value(message));
+        }
+    };
+
+    let mut rows = args[0].clone().into_rows();
+    let width = rows.first().map(|row| row.len()).unwrap_or_default();
+    let column = position(column, width)?;
+
+    // Values that can't be compared, such as a number and a string, are left
+    // in the order they were in.
+    rows.sort_by(|a, b| {
+        let ordering = compare(&a[column], &b[column]).unwrap_or(Ordering::Equal);
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    Ok(Argument::from_rows(rows))
+}
+
+/// A random number between 0 and 1, including 0 but not 1.
+fn random(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("RAND", args, 0, 0)?;
+    Ok(Value::Float(rand::random()))
+}
+
+/// The current date and time in the server's time zone.
+fn now(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("NOW", args, 0, 0)?;
+    Ok(Value::DateTime(Local::now().naive_local()))
+}
+
+/// Today's date in the server's time zone.
+fn today(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("TODAY", args, 0, 0)?;
+    Ok(Value::Date(Local::now().date_naive()))
+}
+
+/// `DATE(year, month, day)`.
+fn date(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("DATE", args, 3, 3)?;
+    let (year, month, day) = (
+        int_arg(args, 0, None)?,
+        int_arg(args, 1, None)?,
+        int_arg(args, 2, None)?,
+    );
+
+    i32::try_from(year)
+        .ok()
+        .zip(u32::try_from(month).ok().zip(u32::try_from(day).ok()))
+        .and_then(|(year, (month, day))| NaiveDate::from_ymd_opt(year, month, day))
+        .map(Value::Date)
+        .ok_or_else(|| {
+            let message = format!("{}-{}-{} is not a valid date", year, month, day);
+            CellError::new(ErrorKind::Number, message)
+        })
+}
+
+/// The difference between two dates. `DATEDIF(start, end, unit)`, where the
+/// unit is `"Y"` for complete years, `"M"` for complete months or `"D"` for
+/// days.
+fn datedif(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("DATEDIF", args, 3, 3)?;
+    let start = date_arg(args, 0)?;
+    let end = date_arg(args, 1)?;
+    if start > end {
+        return Err(CellError::new(
+            ErrorKind::Number,
+            "DATEDIF's start date must not be after its end date",
+        ));
+    }
+
+    // A month is only complete once the end date reaches the day of the
+    // month that the start date is on.
+    let mut months =
+        (end.year() - start.year()) as i64 * 12 + end.month() as i64 - start.month() as i64;
+    if end.day() < start.day() {
+        months -= 1;
+    }
+
+    match text_arg(args, 2)?.to_uppercase().as_str() {
+        "Y" => Ok(Value::Int(months / 12)),
+        "M" => Ok(Value::Int(months)),
+        "D" => Ok(Value::Int((end - start).num_days())),
+        unit => Err(CellError::new(
+            ErrorKind::Number,
+            format!("Unknown DATEDIF unit \"{}\", expected Y, M or D", unit),
+        )),
+    }
+}
+
+/// The date a number of months before or after another date.
+/// `EDATE(start, months)`. The day is moved back to the end of the month if
+/// the month is too short, so a month after January 31st is the end of
+/// February.
+fn edate(args: &[Argument]) -> Result<Value, CellError> {
+    expect_args("EDATE", args, 2, 2)?;
+    let start = date_arg(args, 0)?;
+    let months = int_arg(args, 1, None)?;
+
+    let shifted = match u32::try_from(months.unsigned_abs()) {
+        Ok(n) if months >= 0 => start.checked_add_months(Months::new(n)),
+        Ok(n) => start.checked_sub_months(Months::new(n)),
+        Err(_) => None,
+    };
+    shifted
+        .map(Value::Date)
+        .ok_or_else(|| CellError::new(ErrorKind::Number, "Date is out of range"))
+}
+
+/// Sleeps for the given number of milliseconds, then returns the value.
+fn sleep_then(mut args: Vec<Argument>, budget: &Budget) -> Result<Argument, CellError> {
+    expect_args("sleep_then", &args, 2, 2)?;
+
+    let value = args.pop().expect("Checked the number of arguments above.");
+    let millis = match args.pop() {
+        Some(Argument::Value(Value::Int(millis))) if millis >= 0 => millis as u64,
+        _ => {
+            return Err(CellError::value(
+                "sleep_then expects a number of milliseconds",
+            ))
+        }
+    };
+
+    budget.sleep(Duration::from_millis(millis))?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+
+    fn int(i: i64) -> Value {
+        Value::Int(i)
+    }
+
+    fn text(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    fn value(value: Value) -> Argument {
+        Argument::Value(value)
+    }
+
+    fn vector(values: &[Value]) -> Argument {
+        Argument::Vector(values.to_vec())
+    }
+
+    /// A table of fruit with their price and stock:
+    ///
+    /// | apple  | 3 | 10 |
+    /// | banana | 1 | 20 |
+    /// | cherry | 7 | 30 |
+    fn table() -> Argument {
+        Argument::Matrix(vec![
+            vec![text("apple"), int(3), int(10)],
+            vec![text("banana"), int(1), int(20)],
+            vec![text("cherry"), int(7), int(30)],
+        ])
+    }
+
+    fn call(name: &str, args: Vec<Argument>) -> Result<Argument, CellError> {
+        super::call(name, args, &Budget::default())
+    }
+
+    fn run(name: &str, args: Vec<Argument>) -> Result<Value, CellError> {
+        match call(name, args)? {
+            Argument::Value(value) => Ok(value),
+            _ => panic!("{} returned a range", name),
+        }
+    }
+
+    #[test]
+    fn test_sum() {
+        let args = vec![
+            vector(&[int(1), Value::None, text("x"), int(2)]),
+            value(int(3)),
+        ];
+        assert_eq!(run("SUM", args), Ok(int(6)));
+        assert_eq!(run("sum", vec![]), Ok(int(0)));
+        assert_eq!(
+            run(
+                "SUM",
+                vec![
+                    value(int(1)),
+                    value(Value::Decimal("0.25".parse().unwrap()))
+                ]
+            ),
+            Ok(Value::Decimal("1.25".parse().unwrap()))
+        );
+        assert!(run(
+            "SUM",
+            vec![vector(&[Value::Error(CellError::value("bad"))])]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_average() {
+        assert_eq!(
+            run("AVERAGE", vec![vector(&[int(2), int(4), int(9)])]),
+            Ok(int(5))
+        );
+        assert_eq!(
+            run("AVERAGE", vec![vector(&[int(1), int(2)])]),
+            Ok(Value::Float(1.5))
+        );
+        assert!(run("AVERAGE", vec![vector(&[Value::None])]).is_err());
+    }
+
+    #[test]
+    fn test_min_and_max() {
+        let args = vec![table()];
+        assert_eq!(run("MIN", args.clone()), Ok(int(1)));
+        assert_eq!(run("MAX", args), Ok(int(30)));
+        assert_eq!(run("MAX", vec![vector(&[text("x")])]), Ok(int(0)));
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(run("COUNT", vec![table()]), Ok(int(6)));
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(
+            run("MEDIAN", vec![vector(&[int(5), int(1), int(3)])]),
+            Ok(int(3))
+        );
+        assert_eq!(
+            run("MEDIAN", vec![vector(&[int(4), int(1), int(3), int(8)])]),
+            Ok(Value::Float(3.5))
+        );
+        assert!(run("MEDIAN", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_stdev() {
+        let args = vec![vector(&[
+            int(2),
+            int(4),
+            int(4),
+            int(4),
+            int(5),
+            int(5),
+            int(7),
+            int(9),
+        ])];
+        match run("STDEV", args) {
+            Ok(Value::Float(stdev)) => assert!((stdev - 2.138).abs() < 0.001),
+            result => panic!("Unexpected STDEV result: {:?}", result),
+        }
+        assert!(run("STDEV", vec![value(int(1))]).is_err());
+    }
+
+    fn iso(text: &str) -> Value {
+        Value::parse_date(text).unwrap()
+    }
+
+    #[test]
+    fn test_and() {
+        assert_eq!(
+            run(
+                "AND",
+                vec![value(int(1)), vector(&[int(2), Value::Bool(true)])]
+            ),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            run("AND", vec![value(int(1)), value(int(0))]),
+            Ok(Value::Bool(false))
+        );
+        assert!(run("AND", vec![]).is_err());
+        assert!(run("AND", vec![value(text("yes"))]).is_err());
+    }
+
+    #[test]
+    fn test_or() {
+        assert_eq!(
+            run("OR", vec![value(int(0)), value(Value::None), value(int(5))]),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(
+            run("OR", vec![vector(&[int(0), Value::Bool(false)])]),
+            Ok(Value::Bool(false))
+        );
+    }
+
+    #[test]
+    fn test_concat() {
+        let args = vec![value(text("a")), vector(&[int(1), Value::None, text("b")])];
+        assert_eq!(run("CONCAT", args), Ok(text("a1b")));
+    }
+
+    #[test]
+    fn test_left() {
+        assert_eq!(
+            run("LEFT", vec![value(text("héllo")), value(int(2))]),
+            Ok(text("hé"))
+        );
+        assert_eq!(run("LEFT", vec![value(text("hello"))]), Ok(text("h")));
+        assert_eq!(
+            run("LEFT", vec![value(text("hi")), value(int(10))]),
+            Ok(text("hi"))
+        );
+        assert!(run("LEFT", vec![value(text("hi")), value(int(-1))]).is_err());
+    }
+
+    #[test]
+    fn test_right() {
+        assert_eq!(
+            run("RIGHT", vec![value(text("hello")), value(int(3))]),
+            Ok(text("llo"))
+        );
+        assert_eq!(run("RIGHT", vec![value(int(1234))]), Ok(text("4")));
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(run("LEN", vec![value(text("héllo"))]), Ok(int(5)));
+        assert_eq!(run("LEN", vec![value(Value::None)]), Ok(int(0)));
+        assert!(run("LEN", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_upper() {
+        assert_eq!(
+            run("UPPER", vec![value(text("MiXed 1"))]),
+            Ok(text("MIXED 1"))
+        );
+    }
+
+    #[test]
+    fn test_vlookup() {
+        let exact = |key: &str, column| {
+            run(
+                "VLOOKUP",
+                vec![value(text(key)), table(), value(int(column)), value(int(0))],
+            )
+        };
+        assert_eq!(exact("banana", 2), Ok(int(1)));
+        assert_eq!(exact("CHERRY", 3), Ok(int(30)));
+        assert!(exact("durian", 2).is_err());
+        assert!(exact("apple", 4).is_err());
+
+        // Approximate matches find the last key that isn't greater than the
+        // one being looked up.
+        let approximate = run(
+            "VLOOKUP",
+            vec![value(text("blueberry")), table(), value(int(3))],
+        );
+        assert_eq!(approximate, Ok(int(20)));
+    }
+
+    #[test]
+    fn test_hlookup() {
+        let table = Argument::Matrix(vec![
+            vec![int(10), int(20), int(30)],
+            vec![text("low"), text("mid"), text("high")],
+        ]);
+        let args = vec![value(int(25)), table.clone(), value(int(2))];
+        assert_eq!(run("HLOOKUP", args), Ok(text("mid")));
+
+        let args = vec![value(int(25)), table, value(int(2)), value(int(0))];
+        assert!(run("HLOOKUP", args).is_err());
+    }
+
+    #[test]
+    fn test_index() {
+        assert_eq!(
+            run("INDEX", vec![table(), value(int(3)), value(int(2))]),
+            Ok(int(7))
+        );
+        assert_eq!(
+            run("INDEX", vec![vector(&[int(4), int(5)]), value(int(2))]),
+            Ok(int(5))
+        );
+        let row = Argument::Matrix(vec![vec![int(4), int(5)]]);
+        assert_eq!(run("INDEX", vec![row, value(int(2))]), Ok(int(5)));
+        assert!(run("INDEX", vec![table(), value(int(4)), value(int(1))]).is_err());
+        assert!(run("INDEX", vec![table(), value(int(0))]).is_err());
+    }
+
+    #[test]
+    fn test_match() {
+        let ascending = vector(&[int(10), int(20), int(30)]);
+        let descending = vector(&[int(30), int(20), int(10)]);
+
+        assert_eq!(
+            run(
+                "MATCH",
+                vec![value(int(20)), ascending.clone(), value(int(0))]
+            ),
+            Ok(int(2))
+        );
+        assert_eq!(
+            run("MATCH", vec![value(int(25)), ascending.clone()]),
+            Ok(int(2))
+        );
+        assert_eq!(
+            run("MATCH", vec![value(int(25)), descending, value(int(-1))]),
+            Ok(int(1))
+        );
+        assert!(run("MATCH", vec![value(int(5)), ascending]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let table = table();
+        let in_stock = call(
+            "FILTER",
+            vec![table.clone(), vector(&[int(1), int(0), int(1)])],
+        );
+        let rows = table.clone().into_rows();
+        assert_eq!(
+            in_stock,
+            Ok(Argument::Matrix(vec![rows[0].clone(), rows[2].clone()]))
+        );
+
+        let row = Argument::Matrix(vec![vec![int(1), int(2), int(3)]]);
+        assert_eq!(
+            call(
+                "FILTER",
+                vec![row, Argument::Matrix(vec![vec![int(0), int(1), int(1)]])]
+            ),
+            Ok(Argument::Matrix(vec![vec![int(2), int(3)]]))
+        );
+
+        assert!(call(
+            "FILTER",
+            vec![table.clone(), vector(&[int(0), int(0), int(0)])]
+        )
+        .is_err());
+        assert!(call("FILTER", vec![table, vector(&[int(1)])]).is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let column = vector(&[int(3), int(1), int(2)]);
+        assert_eq!(
+            call("SORT", vec![column.clone()]),
+            Ok(vector(&[int(1), int(2), int(3)]))
+        );
+        assert_eq!(
+            call("SORT", vec![column.clone(), value(int(1)), value(int(-1))]),
+            Ok(vector(&[int(3), int(2), int(1)]))
+        );
+
+        let rows = table().into_rows();
+        assert_eq!(
+            call("SORT", vec![table(), value(int(2))]),
+            Ok(Argument::Matrix(vec![
+                rows[1].clone(),
+                rows[0].clone(),
+                rows[2].clone()
+            ]))
+        );
+
+        assert!(call("SORT", vec![column.clone(), value(int(2))]).is_err());
+        assert!(call("SORT", vec![column, value(int(1)), value(int(0))]).is_err());
+    }
+
+    #[test]
+    fn test_rand() {
+        for _ in 0..100 {
+            match run("RAND", vec![]) {
+                Ok(Value::Float(f)) => assert!((0.0..1.0).contains(&f)),
+                other => panic!("RAND returned {:?}", other),
+            }
+        }
+        assert!(run("RAND", vec![value(int(1))]).is_err());
+    }
+
+    #[test]
+    fn test_now() {
+        let now = run("NOW", vec![]).unwrap();
+        let today = run("TODAY", vec![]).unwrap();
+        assert!(matches!(now, Value::DateTime(_)));
+        assert_ne!(evaluator::compare(&today, &now), Some(Ordering::Greater));
+        assert!(is_volatile("now") && !is_volatile("SUM"));
+    }
+
+    #[test]
+    fn test_today() {
+        assert_eq!(
+            run("TODAY", vec![]),
+            Ok(Value::Date(Local::now().date_naive()))
+        );
+        assert!(run("TODAY", vec![value(int(1))]).is_err());
+    }
+
+    #[test]
+    fn test_date() {
+        let args = vec![value(int(2024)), value(int(2)), value(int(29))];
+        assert_eq!(run("DATE", args), Ok(iso("2024-02-29")));
+
+        let args = vec![value(int(2023)), value(int(2)), value(int(29))];
+        assert!(run("DATE", args).is_err());
+    }
+
+    #[test]
+    fn test_datedif() {
+        let datedif = |start: &str, end: &str, unit: &str| {
+            run(
+                "DATEDIF",
+                vec![value(iso(start)), value(iso(end)), value(text(unit))],
+            )
+        };
+
+        assert_eq!(datedif("2020-03-15", "2024-03-14", "Y"), Ok(int(3)));
+        assert_eq!(datedif("2020-03-15", "2024-03-15", "y"), Ok(int(4)));
+        assert_eq!(datedif("2024-01-31", "2024-02-29", "M"), Ok(int(0)));
+        assert_eq!(datedif("2024-01-31", "2024-03-01", "M"), Ok(int(1)));
+        assert_eq!(datedif("2024-01-01T23:00", "2024-03-01", "D"), Ok(int(60)));
+        assert!(datedif("2024-03-01", "2024-01-01", "D").is_err());
+        assert!(datedif("2024-01-01", "2024-03-01", "W").is_err());
+    }
+
+    #[test]
+    fn test_edate() {
+        let edate = |start: &str, months| run("EDATE", vec![value(iso(start)), value(int(months))]);
+
+        assert_eq!(edate("2024-01-31", 1), Ok(iso("2024-02-29")));
+        assert_eq!(edate("2024-03-15", -14), Ok(iso("2023-01-15")));
+        assert!(edate("2024-03-15", i64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_sleep_then() {
+        assert_eq!(
+            run("sleep_then", vec![value(int(1)), value(text("done"))]),
+            Ok(text("done"))
+        );
+        assert!(run("sleep_then", vec![value(int(-1)), value(int(1))]).is_err());
+
+        let budget = Budget::new(&Limits {
+            timeout: Duration::from_millis(20),
+            ..Limits::default()
+        });
+        let args = vec![value(int(60_000)), value(int(1))];
+        assert!(super::call("sleep_then", args, &budget).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+pub mod ast;
+pub mod dates;
+pub mod evaluator;
+pub mod functions;
+mod number;
+pub mod parser;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rhai::{Dynamic, Engine, EvalAltResult, Position, Scope};
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command_runner::{CellArgument, CommandRunner};
+
+use crate::limits::{self, Budget, Limits};
+use crate::value::{Argument, CellError, ErrorKind, Value};
+use evaluator::Evaluator;
+use parser::parse;
+
+/// Something which can evaluate the expression stored in a cell. The
+/// spreadsheet holds one engine, which is used by every `set` and every
+/// recalculation of a dependency.
+pub trait FormulaEngine: Debug + Send + Sync {
+    /// Finds the cells and ranges referenced in a formula, e.g. `A1` and
+    /// `B1_B3` in `A1 + sum(B1_B3)`.
+    fn find_variables(&self, formula: &str) -> Vec<String>;
+
+    /// Evaluates a formula. `variables` holds the value of every cell or
+    /// range returned by `find_variables`.
+    fn evaluate(&self, formula: &str, variables: &HashMap<String, Argument>) -> Value;
+
+    /// Evaluates a formula which may produce a range rather than a single
+    /// value, such as `A1_A3 * 2`. The range spills into the cells below and
+    /// to the right of the formula's cell. Engines which only produce single
+    /// values can rely on the default.
+    fn evaluate_array(&self, formula: &str, variables: &HashMap<String, Argument>) -> Argument {
+        Argument::Value(self.evaluate(formula, variables))
+    }
+
+    /// Checks if a formula calls a function whose result changes over time,
+    /// such as `NOW()` or `RAND()`. Cells holding such a formula are
+    /// recalculated on a schedule rather than only when a cell they reference
+    /// changes.
+    fn is_volatile(&self, _formula: &str) -> bool {
+        false
+    }
+}
+
+/// Evaluates formulas as Rhai scripts, in the same way as `rsheet_lib`'s
+/// `CommandRunner`. Only integers and strings can be passed to Rhai, so a
+/// formula referencing any other kind of value is an error.
+///
+/// Rhai checks in as it evaluates, so a formula that runs out of time is
+/// stopped where it is, and `sleep_then` only sleeps until then. Rhai
+/// expressions can't loop, so there is no step limit.
+#[derive(Debug)]
+pub struct RhaiEngine {
+    limits: Limits,
+}
+
+impl RhaiEngine {
+    pub fn new(limits: &Limits) -> Self {
+        Self { limits: *limits }
+    }
+
+    /// Creates a Rhai engine with the functions `CommandRunner` provides,
+    /// which gives up once `budget` runs out of time.
+    fn engine(budget: Rc<Budget>) -> Engine {
+        let mut engine = Engine::new();
+
+        let progress = budget.clone();
+        engine.on_progress(move |_| progress.spend(0).is_err().then_some(Dynamic::UNIT));
+
+        engine.register_fn("sum", sum);
+        engine.register_fn(
+            "sleep_then",
+            move |millis: i64, value: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+                let duration = Duration::from_millis(millis.max(0) as u64);
+                match budget.sleep(duration) {
+                    Ok(_) => Ok(value),
+                    Err(_) => {
+                        Err(EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into())
+                    }
+                }
+            },
+        );
+        engine
+    }
+}
+
+/// Adds up every integer in a range, as `CommandRunner` does.
+fn sum(values: Vec<Dynamic>) -> Result<i64, Box<EvalAltResult>> {
+    let mut total = 0;
+    for value in values {
+        if let Ok(i) = value.as_int() {
+            total += i;
+        } else if let Ok(values) = value.clone().into_array() {
+            total += sum(values)?;
+        } else {
+            return Err(format!("Unknown value: {:?}", value).into());
+        }
+    }
+    Ok(total)
+}
+
+impl Default for RhaiEngine {
+    fn default() -> Self {
+        Self::new(&Limits::default())
+    }
+}
+
+impl FormulaEngine for RhaiEngine {
+    fn find_variables(&self, formula: &str) -> Vec<String> {
+        CommandRunner::new(formula).find_variables()
+    }
+
+    fn evaluate(&self, formula: &str, variables: &HashMap<String, Argument>) -> Value {
+        let mut scope = Scope::new();
+        for (name, argument) in variables {
+            if !is_supported_by_rhai(argument) {
+                return Value::Error(CellError::value(format!(
+                    "{} holds a value which is only supported by the native engine",
+                    name
+                )));
+            }
+            match rhai::serde::to_dynamic(CellArgument::from(argument.clone())) {
+                Ok(value) => scope.push(name.clone(), value),
+                Err(_) => {
+                    let message = format!("Unable to convert {} to Rhai", name);
+                    return Value::Error(CellError::value(message));
+                }
+            };
+        }
+
+        // Errors are passed back as text, as they are from `CommandRunner`,
+        // so that they are classified the same way.
+        let engine = Self::engine(Rc::new(Budget::new(&self.limits)));
+        let result = match engine.compile_expression(formula) {
+            Ok(ast) => engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast),
+            Err(e) => return CellValue::Error(e.to_string()).into(),
+        };
+        match result {
+            Ok(value) => match rhai::serde::from_dynamic::<CellValue>(&value) {
+                Ok(value) => value.into(),
+                Err(_) => {
+                    CellValue::Error("Could not cast Rhai return back to Cell Value.".into()).into()
+                }
+            },
+            Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => {
+                Value::Error(limits::timed_out(self.limits.timeout))
+            }
+            Err(e) => CellValue::Error(e.to_string()).into(),
+        }
+    }
+}
+
+fn is_supported_by_rhai(argument: &Argument) -> bool {
+    let is_supported = |value: &Value| {
+        matches!(
+            value,
+            Value::Int(_) | Value::String(_) | Value::Error(_) | Value::None
+        )
+    };
+    match argument {
+        Argument::Value(value) => is_supported(value),
+        Argument::Vector(vector) => vector.iter().all(is_supported),
+        Argument::Matrix(matrix) => matrix.iter().flatten().all(is_supported),
+    }
+}
+
+/// Evaluates formulas with the parser and evaluator in this module. The
+/// evaluator checks the time and steps it has used as it goes, so a formula
+/// that exceeds the limits is stopped straight away.
+#[derive(Debug, Default)]
+pub struct NativeEngine {
+    limits: Limits,
+}
+
+impl NativeEngine {
+    pub fn new(limits: &Limits) -> Self {
+        Self { limits: *limits }
+    }
+}
+
+impl FormulaEngine for NativeEngine {
+    fn find_variables(&self, formula: &str) -> Vec<String> {
+        // A formula that doesn't parse can't reference anything. The parse
+        // error is reported when it is evaluated.
+        parse(formula, self.limits.max_nesting)
+            .map(|expr| expr.references())
+            .unwrap_or_default()
+    }
+
+    fn evaluate(&self, formula: &str, variables: &HashMap<String, Argument>) -> Value {
+        match self.evaluate_array(formula, variables) {
+            Argument::Value(value) => value,
+            _ => Value::Error(CellError::value(
+                "Formula evaluated to a range, not a value",
+            )),
+        }
+    }
+
+    fn evaluate_array(&self, formula: &str, variables: &HashMap<String, Argument>) -> Argument {
+        let expr = match parse(formula, self.limits.max_nesting) {
+            Ok(expr) => expr,
+            Err(e) => {
+                let error = CellError::new(ErrorKind::Syntax, format!("Syntax error: {}", e));
+                return Argument::Value(Value::Error(error));
+            }
+        };
+
+        let budget = Budget::new(&self.limits);
+        match Evaluator::new(variables, &budget).evaluate(&expr) {
+            Ok(argument) => argument,
+            Err(e) => Argument::Value(Value::Error(e)),
+        }
+    }
+
+    fn is_volatile(&self, formula: &str) -> bool {
+        parse(formula, self.limits.max_nesting)
+            .map(|expr| expr.calls(functions::is_volatile))
+            .unwrap_or(false)
+    }
+}
+
+/// The engines that can be chosen when starting the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EngineKind {
+    #[default]
+    Rhai,
+    Native,
+}
+
+impl EngineKind {
+    pub fn engine(self, limits: &Limits) -> Box<dyn FormulaEngine> {
+        match self {
+            EngineKind::Rhai => Box::new(RhaiEngine::new(limits)),
+            EngineKind::Native => Box::new(NativeEngine::new(limits)),
+        }
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rhai" => Ok(EngineKind::Rhai),
+            "native" => Ok(EngineKind::Native),
+            _ => Err(format!("Unknown engine '{}', expected rhai or native", s)),
+        }
+    }
+}
+
+/// The conformance suite. Every formula here is evaluated by both engines,
+/// which must agree on the result. Error messages differ between the engines,
+/// so only the fact that an error occurred is compared.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: i64) -> Argument {
+        Argument::Value(Value::Int(i))
+    }
+
+    fn string(s: &str) -> Argument {
+        Argument::Value(Value::String(s.to_string()))
+    }
+
+    fn variables() -> HashMap<String, Argument> {
+        HashMap::from([
+            ("A1".to_string(), int(6)),
+            ("A2".to_string(), int(-4)),
+            ("B1".to_string(), string("hello")),
+            ("C1".to_string(), Argument::Value(Value::None)),
+            (
+                "A1_A3".to_string(),
+                Argument::Vector(vec![Value::Int(1), Value::Int(2), Value::Int(3)]),
+            ),
+            (
+                "A1_B2".to_string(),
+                Argument::Matrix(vec![
+                    vec![Value::Int(1), Value::Int(2)],
+                    vec![Value::Int(3), Value::Int(4)],
+                ]),
+            ),
+        ])
+    }
+
+    const FORMULAS: &[&str] = &[
+        "5",
+        "-5",
+        "()",
+        "\"a string\"",
+        "\"with \\\"quotes\\\"\"",
+        "1 + 2 * 3",
+        "(1 + 2) * 3",
+        "10 - 4 - 3",
+        "8 / 2",
+        "-8 / 2",
+        "7 % 3",
+        "2 ** 10",
+        "1 / 0",
+        "9223372036854775807 + 1",
+        "A1 + A2",
+        "A1 * A2 - A1",
+        "-A2",
+        "B1 + \" world\"",
+        "B1 + A1",
+        "A1 + B1",
+        "A1 - B1",
+        "C1 + 1",
+        "sum(A1_A3)",
+        "sum(A1_B2)",
+        "sum(A1_A3) * A1",
+        "A1_A3 + 1",
+        "sleep_then(10, A1)",
+        "sleep_then(10, B1)",
+        "unknown",
+        "nothere(1)",
+        "1 +",
+        "(1",
+    ];
+
+    #[test]
+    fn test_native_engine_matches_rhai() {
+        let variables = variables();
+
+        for formula in FORMULAS {
+            let rhai = RhaiEngine::default().evaluate(formula, &variables);
+            let native = NativeEngine::default().evaluate(formula, &variables);
+
+            match (&rhai, &native) {
+                (Value::Error(_), Value::Error(_)) => {}
+                _ => assert_eq!(rhai, native, "engines disagree on {}", formula),
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_engine_finds_same_variables() {
+        for formula in ["A1 + A2", "sum(A1_A3) * B1", "sleep_then(10, A1_B2)", "5"] {
+            assert_eq!(
+                RhaiEngine::default().find_variables(formula),
+                NativeEngine::default().find_variables(formula),
+                "engines disagree on the variables in {}",
+                formula
+            );
+        }
+    }
+
+    #[test]
+    fn test_native_only_syntax() {
+        let variables = variables();
+
+        assert_eq!(
+            NativeEngine::default().evaluate("B1 & \" \" & A1", &variables),
+            Value::String("hello 6".to_string())
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("A1 > A2", &variables),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("SUM(A1_A3)", &variables),
+            Value::Int(6)
+        );
+    }
+
+    #[test]
+    fn test_deeply_nested_formulas() {
+        let engine = NativeEngine::default();
+        let variables = variables();
+        let nested = |open: &str, close: &str, levels: usize| {
+            format!("{}1{}", open.repeat(levels), close.repeat(levels))
+        };
+
+        // Each of these would overflow the stack if the parser kept going.
+        for formula in [
+            nested("(", ")", 5000),
+            nested("sum(", ")", 5000),
+            nested("-", "", 5000),
+            nested("2 ** ", "", 5000),
+        ] {
+            match engine.evaluate(&formula, &variables) {
+                Value::Error(e) => {
+                    assert_eq!(e.kind, ErrorKind::Syntax);
+                    assert!(e.message.contains("nested more than 64 levels"));
+                }
+                value => panic!("Expected a syntax error, got {:?}", value),
+            }
+            assert!(engine.find_variables(&formula).is_empty());
+            assert!(!engine.is_volatile(&formula));
+        }
+
+        // Long chains of operators count towards the limit too, as each
+        // operator nests the ones before it.
+        let chain = |terms: usize| vec!["1"; terms].join(" + ");
+        assert!(matches!(
+            engine.evaluate(&chain(5000), &variables),
+            Value::Error(e) if e.kind == ErrorKind::Syntax
+        ));
+
+        let max_nesting = Limits::default().max_nesting;
+        assert_eq!(
+            engine.evaluate(&nested("(", ")", max_nesting), &variables),
+            Value::Int(1)
+        );
+        assert_eq!(
+            engine.evaluate(&chain(max_nesting + 1), &variables),
+            Value::Int(max_nesting as i64 + 1)
+        );
+    }
+
+    #[test]
+    fn test_fractional_numbers() {
+        let mut variables = variables();
+        variables.insert(
+            "D1".to_string(),
+            Argument::Value(Value::Decimal("19.99".parse().unwrap())),
+        );
+
+        assert_eq!(
+            NativeEngine::default().evaluate("7 / 2", &variables),
+            Value::Float(3.5)
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("D1 * 3", &variables),
+            Value::Decimal("59.97".parse().unwrap())
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("0.5 + A1", &variables),
+            Value::Float(6.5)
+        );
+        assert!(matches!(
+            RhaiEngine::default().evaluate("D1 * 3", &variables),
+            Value::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_dates() {
+        let mut variables = variables();
+        variables.insert(
+            "D1".to_string(),
+            Argument::Value(Value::parse_date("2024-01-31").unwrap()),
+        );
+
+        assert_eq!(
+            NativeEngine::default().evaluate("D1 + 30", &variables),
+            Value::parse_date("2024-03-01").unwrap()
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("DATEDIF(D1, EDATE(D1, 13), \"M\")", &variables),
+            Value::Int(12)
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("(D1 < DATE(2024, 2, 1)) & \" \" & D1", &variables),
+            Value::String("TRUE 2024-01-31".to_string())
+        );
+        assert!(matches!(
+            RhaiEngine::default().evaluate("D1", &variables),
+            Value::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_native_function_library() {
+        let variables = variables();
+
+        assert_eq!(
+            NativeEngine::default()
+                .evaluate("IF(AVERAGE(A1_A3) >= 2, UPPER(B1), \"low\")", &variables),
+            Value::String("HELLO".to_string())
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("INDEX(A1_B2, MATCH(2, A1_A3, 0), 2)", &variables),
+            Value::Int(4)
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate("IFERROR(VLOOKUP(9, A1_B2, 2, 0), -1)", &variables),
+            Value::Int(-1)
+        );
+    }
+
+    #[test]
+    fn test_array_formulas() {
+        let variables = variables();
+
+        assert_eq!(
+            NativeEngine::default().evaluate_array("A1_A3 * 2", &variables),
+            Argument::Vector(vec![Value::Int(2), Value::Int(4), Value::Int(6)])
+        );
+        assert_eq!(
+            NativeEngine::default().evaluate_array("SORT(A1_B2, 1, -1)", &variables),
+            Argument::Matrix(vec![
+                vec![Value::Int(3), Value::Int(4)],
+                vec![Value::Int(1), Value::Int(2)],
+            ])
+        );
+        assert!(matches!(
+            NativeEngine::default().evaluate("A1_A3 * 2", &variables),
+            Value::Error(_)
+        ));
+        assert_eq!(
+            RhaiEngine::default().evaluate_array("sum(A1_A3)", &variables),
+            Argument::Value(Value::Int(6))
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let limits = Limits {
+            timeout: Duration::from_millis(50),
+            ..Limits::default()
+        };
+        let variables = variables();
+
+        for engine in [EngineKind::Rhai, EngineKind::Native] {
+            let engine = engine.engine(&limits);
+            assert_eq!(
+                engine.evaluate("sleep_then(100000000, 1)", &variables),
+                Value::Error(limits::timed_out(limits.timeout))
+            );
+            assert_eq!(
+                engine.evaluate("sleep_then(1, 1)", &variables),
+                Value::Int(1)
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let engine = NativeEngine::new(&Limits {
+            max_steps: 5,
+            ..Limits::default()
+        });
+        let variables = variables();
+
+        assert_eq!(engine.evaluate("1 + 2", &variables), Value::Int(3));
+        assert!(matches!(
+            engine.evaluate("SUM(A1_B2) + 1", &variables),
+            Value::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let variables = variables();
+        let kind = |engine: &dyn FormulaEngine, formula: &str| match engine
+            .evaluate(formula, &variables)
+        {
+            Value::Error(e) => Some(e.kind),
+            _ => None,
+        };
+
+        let native = NativeEngine::default();
+        let cases = [
+            ("1 / 0", ErrorKind::DivideByZero),
+            ("9223372036854775807 + 1", ErrorKind::Number),
+            ("unknown", ErrorKind::Name),
+            ("nothere(1)", ErrorKind::Name),
+            ("\"a\" * 2", ErrorKind::Value),
+            ("INDEX(A1_A3, 9)", ErrorKind::Reference),
+            ("MATCH(9, A1_A3, 0)", ErrorKind::NotAvailable),
+            ("1 +", ErrorKind::Syntax),
+        ];
+        for (formula, expected) in cases {
+            assert_eq!(kind(&native, formula), Some(expected), "{}", formula);
+        }
+
+        let rhai = RhaiEngine::default();
+        assert_eq!(kind(&rhai, "1 / 0"), Some(ErrorKind::DivideByZero));
+        assert_eq!(kind(&rhai, "unknown"), Some(ErrorKind::Name));
+    }
+
+    #[test]
+    fn test_catching_errors() {
+        let variables = variables();
+        let evaluate = |formula| NativeEngine::default().evaluate(formula, &variables);
+
+        assert_eq!(evaluate("ISERROR(1 / 0)"), Value::Bool(true));
+        assert_eq!(evaluate("ISERROR(A1)"), Value::Bool(false));
+        assert_eq!(evaluate("IFERROR(MATCH(9, A1_A3, 0), 0)"), Value::Int(0));
+        assert_eq!(
+            NativeEngine::default().evaluate_array("ISERROR(1 / (A1_A3 - 2))", &variables),
+            Argument::Vector(vec![
+                Value::Bool(false),
+                Value::Bool(true),
+                Value::Bool(false)
+            ])
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::cmp::Ordering;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+use crate::value::{CellError, ErrorKind, Value};
+
+/// A number in a formula. When two different kinds of numbers are combined
+/// they are promoted to a common kind: an integer and a decimal become
+/// decimals, and anything combined with a float becomes a float.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Decimal(Decimal),
+    Float(f64),
+}
+
+/// Two numbers after promotion.
+enum Pair {
+    Int(i64, i64),
+    Decimal(Decimal, Decimal),
+    Float(f64, f64),
+}
+
+impl Number {
+    /// The number held by a value, if it holds one.
+    pub fn from_value(value: &Value) -> Option<Number> {
+        match value {
+            Value::Int(i) => Some(Number::Int(*i)),
+            Value::Decimal(d) => Some(Number::Decimal(*d)),
+            Value::Float(f) => Some(Number::Float(*f)),
+            _ => None,
+        }
+    }
+
+    /// Converts the number back into a value. Cells can't hold infinite
+    /// floats or NaN, so those are an error.
+    pub fn into_value(self) -> Result<Value, CellError> {
+        match self {
+            Number::Int(i) => Ok(Value::Int(i)),
+            Number::Decimal(d) => Ok(Value::Decimal(d)),
+            Number::Float(f) if f.is_finite() => Ok(Value::Float(f)),
+            Number::Float(_) => Err(CellError::new(
+                ErrorKind::Number,
+                "Result is not a finite number",
+            )),
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(i) => i as f64,
+            Number::Decimal(d) => d.to_f64().unwrap_or(f64::NAN),
+            Number::Float(f) => f,
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        match self {
+            Number::Int(i) => i == 0,
+            Number::Decimal(d) => d.is_zero(),
+            Number::Float(f) => f == 0.0,
+        }
+    }
+
+    fn promote(self, rhs: Number) -> Pair {
+        match (self, rhs) {
+            (Number::Int(a), Number::Int(b)) => Pair::Int(a, b),
+            (Number::Float(_), _) | (_, Number::Float(_)) => {
+                Pair::Float(self.to_f64(), rhs.to_f64())
+            }
+            (Number::Decimal(a), Number::Int(b)) => Pair::Decimal(a, Decimal::from(b)),
+            (Number::Int(a), Number::Decimal(b)) => Pair::Decimal(Decimal::from(a), b),
+            (Number::Decimal(a), Number::Decimal(b)) => Pair::Decimal(a, b),
+        }
+    }
+
+    pub fn neg(self) -> Result<Number, CellError> {
+        match self {
+            Number::Int(i) => i.checked_neg().map(Number::Int).ok_or_else(overflow),
+            Number::Decimal(d) => Ok(Number::Decimal(-d)),
+            Number::Float(f) => Ok(Number::Float(-f)),
+        }
+    }
+
+    pub fn add(self, rhs: Number) -> Result<Number, CellError> {
+        match self.promote(rhs) {
+            Pair::Int(a, b) => a.checked_add(b).map(Number::Int).ok_or_else(overflow),
+            Pair::Decimal(a, b) => a.checked_add(b).map(Number::Decimal).ok_or_else(overflow),
+            Pair::Float(a, b) => Ok(Number::Float(a + b)),
+        }
+    }
+
+    pub fn sub(self, rhs: Number) -> Result<Number, CellError> {
+        match self.promote(rhs) {
+            Pair::Int(a, b) => a.checked_sub(b).map(Number::Int).ok_or_else(overflow),
+            Pair::Decimal(a, b) => a.checked_sub(b).map(Number::Decimal).ok_or_else(overflow),
+            Pair::Float(a, b) => Ok(Number::Float(a - b)),
+        }
+    }
+
+    pub fn mul(self, rhs: Number) -> Result<Number, CellError> {
+        match self.promote(rhs) {
+            Pair::Int(a, b) => a.checked_mul(b).map(Number::Int).ok_or_else(overflow),
+            Pair::Decimal(a, b) => a.checked_mul(b).map(Number::Decimal).ok_or_else(overflow),
+            Pair::Float(a, b) => Ok(Number::Float(a * b)),
+        }
+    }
+
+    /// Divides two numbers. Dividing integers only gives an integer when the
+    /// division is exact, otherwise the result is a float, so `7 / 2` is
+    /// `3.5`.
+    pub fn div(self, rhs: Number) -> Result<Number, CellError> {
+        if rhs.is_zero() {
+            return Err(CellError::new(ErrorKind::DivideByZero, "Division by zero"));
+        }
+
+        match self.promote(rhs) {
+            Pair::Int(a, b) => match a.checked_rem(b) {
+                Some(0) => a.checked_div(b).map(Number::Int).ok_or_else(overflow),
+                _ => Ok(Number::Float(a as f64 / b as f64)),
+            },
+            Pair::Decimal(a, b) => a.checked_div(b).map(Number::Decimal).ok_or_else(overflow),
+            Pair::Float(a, b) => Ok(Number::Float(a / b)),
+        }
+    }
+
+    pub fn rem(self, rhs: Number) -> Result<Number, CellError> {
+        if rhs.is_zero() {
+            return Err(CellError::new(ErrorKind::DivideByZero, "Division by zero"));
+        }
+
+        match self.promote(rhs) {
+            Pair::Int(a, b) => a.checked_rem(b).map(Number::Int).ok_or_else(overflow),
+            Pair::Decimal(a, b) => a.checked_rem(b).map(Number::Decimal).ok_or_else(overflow),
+            Pair::Float(a, b) => Ok(Number::Float(a % b)),
+        }
+    }
+
+    /// Raises a number to a power. Integers and decimals stay exact when the
+    /// exponent is a non-negative integer, anything else is calculated with
+    /// floats.
+    pub fn pow(self, rhs: Number) -> Result<Number, CellError> {
+        match (self, rhs) {
+            (Number::Int(a), Number::Int(b)) if b >= 0 => u32::try_from(b)
+                .ok()
+                .and_then(|b| a.checked_pow(b))
+                .map(Number::Int)
+                .ok_or_else(overflow),
+            (Number::Decimal(a), Number::Int(b)) if b >= 0 => decimal_pow(a, b as u64)
+                .map(Number::Decimal)
+                .ok_or_else(overflow),
+            _ => Ok(Number::Float(self.to_f64().powf(rhs.to_f64()))),
+        }
+    }
+
+    /// Orders two numbers of any kind by their value.
+    pub fn compare(self, rhs: Number) -> Ordering {
+        match self.promote(rhs) {
+            Pair::Int(a, b) => a.cmp(&b),
+            Pair::Decimal(a, b) => a.cmp(&b),
+            Pair::Float(a, b) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+/// Exponentiation by squaring, returning `None` on overflow.
+fn decimal_pow(mut base: Decimal, mut exponent: u64) -> Option<Decimal> {
+    let mut result = Decimal::ONE;
+    while exponent > 0 {
+        if exponent % 2 == 1 {
+            result = result.checked_mul(base)?;
+        }
+        exponent /= 2;
+        if exponent > 0 {
+            base = base.checked_mul(base)?;
+        }
+    }
+    Some(result)
+}
+
+fn overflow() -> CellError {
+    CellError::new(ErrorKind::Number, "Number is too large")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(s: &str) -> Number {
+        Number::Decimal(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_promotion() {
+        assert_eq!(Number::Int(2).add(Number::Int(3)), Ok(Number::Int(5)));
+        assert_eq!(Number::Int(2).add(decimal("0.50")), Ok(decimal("2.50")));
+        assert_eq!(
+            decimal("0.5").mul(Number::Float(0.5)),
+            Ok(Number::Float(0.25))
+        );
+        assert_eq!(
+            Number::Int(1).sub(Number::Float(0.5)),
+            Ok(Number::Float(0.5))
+        );
+    }
+
+    #[test]
+    fn test_division() {
+        assert_eq!(Number::Int(8).div(Number::Int(2)), Ok(Number::Int(4)));
+        assert_eq!(Number::Int(7).div(Number::Int(2)), Ok(Number::Float(3.5)));
+        assert_eq!(decimal("10.00").div(Number::Int(4)), Ok(decimal("2.50")));
+        assert!(Number::Float(1.0).div(decimal("0.00")).is_err());
+        assert!(matches!(
+            Number::Int(i64::MIN).div(Number::Int(-1)),
+            Ok(Number::Float(_))
+        ));
+    }
+
+    #[test]
+    fn test_decimals_are_exact() {
+        let sum = decimal("0.1").add(decimal("0.2")).unwrap();
+        assert_eq!(sum.compare(decimal("0.3")), Ordering::Equal);
+        assert_eq!(sum.into_value().unwrap().to_string(), "0.3");
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(Number::Int(2).pow(Number::Int(10)), Ok(Number::Int(1024)));
+        assert_eq!(Number::Int(2).pow(Number::Int(-1)), Ok(Number::Float(0.5)));
+        assert_eq!(decimal("1.1").pow(Number::Int(2)), Ok(decimal("1.21")));
+        assert!(Number::Int(10).pow(Number::Int(100)).is_err());
+        assert!(Number::Float(-1.0)
+            .pow(Number::Float(0.5))
+            .unwrap()
+            .into_value()
+            .is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(Number::Int(1).compare(Number::Float(1.5)), Ordering::Less);
+        assert_eq!(decimal("2.0").compare(Number::Int(2)), Ordering::Equal);
+    }
+}
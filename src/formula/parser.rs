@@ -0,0 +1,454 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use rust_decimal::Decimal;
+
+use crate::utils::is_valid_cell;
+
+use super::ast::{BinaryOp, Expr, UnaryOp};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Float(f64),
+    Decimal(Decimal),
+    Text(String),
+    Ident(String),
+    LParen,
+    RParen,
+    Comma,
+    Op(BinaryOp),
+    Minus,
+}
+
+/// Parses a formula into an expression tree, which can be at most
+/// `max_nesting` levels deep.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::formula::ast::{BinaryOp, Expr};
+/// # use rsheet::formula::parser::parse;
+/// let expr = parse("A1 * 2", 64).unwrap();
+/// assert_eq!(
+///     expr,
+///     Expr::Binary(
+///         BinaryOp::Multiply,
+///         Box::new(Expr::Reference("A1".to_string())),
+///         Box::new(Expr::Number(2)),
+///     )
+/// );
+/// ```
+pub fn parse(formula: &str, max_nesting: usize) -> Result<Expr, String> {
+    let tokens = tokenize(formula)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+        max_nesting,
+    };
+
+    let expr = parser.parse_expr()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?} after expression", token)),
+    }
+}
+
+fn tokenize(formula: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = formula.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '0'..='9' => take_number(&mut chars)?,
+            c if c.is_alphabetic() || c == '_' => {
+                Token::Ident(take_while(&mut chars, |c| c.is_alphanumeric() || c == '_'))
+            }
+            '"' => {
+                chars.next();
+                Token::Text(take_string(&mut chars)?)
+            }
+            _ => {
+                chars.next();
+                match (c, chars.peek()) {
+                    ('*', Some('*')) => {
+                        chars.next();
+                        Token::Op(BinaryOp::Power)
+                    }
+                    ('=', Some('=')) => {
+                        chars.next();
+                        Token::Op(BinaryOp::Equal)
+                    }
+                    ('!', Some('=')) => {
+                        chars.next();
+                        Token::Op(BinaryOp::NotEqual)
+                    }
+                    ('<', Some('=')) => {
+                        chars.next();
+                        Token::Op(BinaryOp::LessEqual)
+                    }
+                    ('>', Some('=')) => {
+                        chars.next();
+                        Token::Op(BinaryOp::GreaterEqual)
+                    }
+                    ('(', _) => Token::LParen,
+                    (')', _) => Token::RParen,
+                    (',', _) => Token::Comma,
+                    ('-', _) => Token::Minus,
+                    ('+', _) => Token::Op(BinaryOp::Add),
+                    ('*', _) => Token::Op(BinaryOp::Multiply),
+                    ('/', _) => Token::Op(BinaryOp::Divide),
+                    ('%', _) => Token::Op(BinaryOp::Remainder),
+                    ('&', _) => Token::Op(BinaryOp::Concat),
+                    ('<', _) => Token::Op(BinaryOp::Less),
+                    ('>', _) => Token::Op(BinaryOp::Greater),
+                    _ => return Err(format!("Unexpected character '{}'", c)),
+                }
+            }
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn take_while(chars: &mut Peekable<Chars>, predicate: impl Fn(char) -> bool) -> String {
+    let mut taken = String::new();
+    while let Some(&c) = chars.peek() {
+        if !predicate(c) {
+            break;
+        }
+        taken.push(c);
+        chars.next();
+    }
+    taken
+}
+
+/// Reads a number literal. Numbers with a fractional part are floats, and an
+/// `m` suffix makes the number a decimal.
+fn take_number(chars: &mut Peekable<Chars>) -> Result<Token, String> {
+    let mut digits = take_while(chars, |c| c.is_ascii_digit());
+    let fractional = chars.peek() == Some(&'.');
+    if fractional {
+        chars.next();
+        digits.push('.');
+        digits.push_str(&take_while(chars, |c| c.is_ascii_digit()));
+    }
+
+    if chars.peek() == Some(&'m') {
+        chars.next();
+        return digits
+            .parse()
+            .map(Token::Decimal)
+            .map_err(|_| format!("Invalid decimal: {}m", digits));
+    }
+
+    match fractional {
+        true => digits
+            .parse()
+            .map(Token::Float)
+            .map_err(|_| format!("Invalid number: {}", digits)),
+        false => digits
+            .parse()
+            .map(Token::Number)
+            .map_err(|_| format!("Number is too large: {}", digits)),
+    }
+}
+
+/// Reads the rest of a string literal, after the opening quote.
+fn take_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some(c @ ('"' | '\\')) => string.push(c),
+                Some(c) => return Err(format!("Unknown escape sequence '\\{}'", c)),
+                None => return Err("Unterminated string".to_string()),
+            },
+            Some(c) => string.push(c),
+            None => return Err("Unterminated string".to_string()),
+        }
+    }
+}
+
+/// A recursive descent parser. Each `parse_*` method handles one level of
+/// precedence, from lowest to highest.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+
+    /// How deeply nested the part being parsed is. Every recursive step, and
+    /// every operator in a chain, goes a level deeper through `descend`,
+    /// which stops at `max_nesting` rather than letting a formula overflow
+    /// the stack.
+    depth: usize,
+    max_nesting: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("Expected {:?} but found {:?}", expected, token)),
+            None => Err(format!("Expected {:?} but the formula ended", expected)),
+        }
+    }
+
+    fn descend(&mut self) -> Result<(), String> {
+        if self.depth >= self.max_nesting {
+            return Err(format!(
+                "Formula is nested more than {} levels deep",
+                self.max_nesting
+            ));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parses a part of the formula nested one level deeper.
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        self.descend()?;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    /// Parses a left associative level of binary operators.
+    fn parse_binary(
+        &mut self,
+        ops: &[BinaryOp],
+        next: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let depth = self.depth;
+        let mut lhs = next(self)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(op)) if ops.contains(op) => *op,
+                Some(Token::Minus) if ops.contains(&BinaryOp::Subtract) => BinaryOp::Subtract,
+                _ => break,
+            };
+            self.next();
+
+            // Each operator puts the ones before it a level deeper in the
+            // tree, which the evaluator has to recurse through.
+            self.descend()?;
+            let rhs = next(self)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        self.depth = depth;
+        Ok(lhs)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, String> {
+        use BinaryOp::*;
+        self.parse_binary(
+            &[Equal, NotEqual, Less, LessEqual, Greater, GreaterEqual],
+            Self::parse_concat,
+        )
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr, String> {
+        self.parse_binary(&[BinaryOp::Concat], Self::parse_additive)
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        self.parse_binary(
+            &[BinaryOp::Add, BinaryOp::Subtract],
+            Self::parse_multiplicative,
+        )
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        use BinaryOp::*;
+        self.parse_binary(&[Multiply, Divide, Remainder], Self::parse_power)
+    }
+
+    /// Powers are right associative, so `2 ** 3 ** 2` is `2 ** 9`.
+    fn parse_power(&mut self) -> Result<Expr, String> {
+        let base = self.parse_unary()?;
+        if self.peek() == Some(&Token::Op(BinaryOp::Power)) {
+            self.next();
+            let exponent = self.nested(Self::parse_power)?;
+            return Ok(Expr::Binary(
+                BinaryOp::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.peek() == Some(&Token::Minus) {
+            self.next();
+            let expr = self.nested(Self::parse_unary)?;
+            return Ok(Expr::Unary(UnaryOp::Negate, Box::new(expr)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::Float(number)) => Ok(Expr::Float(number)),
+            Some(Token::Decimal(number)) => Ok(Expr::Decimal(number)),
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::LParen) => {
+                if self.peek() == Some(&Token::RParen) {
+                    self.next();
+                    return Ok(Expr::Empty);
+                }
+                let expr = self.nested(Self::parse_expr)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.next();
+                    let args = self.parse_args()?;
+                    Ok(Expr::Call(name, args))
+                } else if is_valid_cell(&name) {
+                    Ok(Expr::Reference(name))
+                } else if name.eq_ignore_ascii_case("true") {
+                    Ok(Expr::Bool(true))
+                } else if name.eq_ignore_ascii_case("false") {
+                    Ok(Expr::Bool(false))
+                } else {
+                    Ok(Expr::Name(name))
+                }
+            }
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+            None => Err("Unexpected end of formula".to_string()),
+        }
+    }
+
+    /// Parses the arguments of a function call, after the opening bracket.
+    fn parse_args(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.next();
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.nested(Self::parse_expr)?);
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => return Ok(args),
+                Some(token) => return Err(format!("Expected ',' or ')' but found {:?}", token)),
+                None => return Err("Unterminated function call".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+
+    /// Parses with the default limit on nesting.
+    fn parse(formula: &str) -> Result<Expr, String> {
+        super::parse(formula, Limits::default().max_nesting)
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse("1 + 2 * -3").unwrap(),
+            binary(
+                BinaryOp::Add,
+                Expr::Number(1),
+                binary(
+                    BinaryOp::Multiply,
+                    Expr::Number(2),
+                    Expr::Unary(UnaryOp::Negate, Box::new(Expr::Number(3)))
+                )
+            )
+        );
+        assert_eq!(
+            parse("A1 - 1 < 2 & \"x\"").unwrap(),
+            binary(
+                BinaryOp::Less,
+                binary(
+                    BinaryOp::Subtract,
+                    Expr::Reference("A1".to_string()),
+                    Expr::Number(1)
+                ),
+                binary(
+                    BinaryOp::Concat,
+                    Expr::Number(2),
+                    Expr::Text("x".to_string())
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_calls_and_references() {
+        let expr = parse("sum(A1_B2, x, (), \"a \\\"b\\\"\")").unwrap();
+        assert_eq!(
+            expr,
+            Expr::Call(
+                "sum".to_string(),
+                vec![
+                    Expr::Reference("A1_B2".to_string()),
+                    Expr::Name("x".to_string()),
+                    Expr::Empty,
+                    Expr::Text("a \"b\"".to_string()),
+                ]
+            )
+        );
+        assert_eq!(expr.references(), vec!["A1_B2".to_string()]);
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(parse("1.5").unwrap(), Expr::Float(1.5));
+        assert_eq!(parse("2.").unwrap(), Expr::Float(2.0));
+        assert_eq!(
+            parse("19.90m").unwrap(),
+            Expr::Decimal(Decimal::new(1990, 2))
+        );
+        assert_eq!(parse("3m").unwrap(), Expr::Decimal(Decimal::new(3, 0)));
+        assert!(parse("1.5.2").is_err());
+        assert_eq!(parse("TRUE").unwrap(), Expr::Bool(true));
+        assert_eq!(parse("false").unwrap(), Expr::Bool(false));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("1 +").is_err());
+        assert!(parse("(1").is_err());
+        assert!(parse("sum(1 2)").is_err());
+        assert!(parse("\"open").is_err());
+        assert!(parse("1 $ 2").is_err());
+        assert!(parse("99999999999999999999").is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::io::Read;
+use std::sync::Arc;
+
+use rayon::ThreadPool;
+use rsheet_lib::replies::Reply;
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    acl,
+    audit::{audited, Origin},
+    auth::Credentials,
+    commands::{clear::clear, get, set::set},
+    quota::Quotas,
+    shutdown::{Shutdown, POLL_INTERVAL},
+    spreadsheet::Spreadsheet,
+    utils::{current_timestamp, is_valid_cell, range_size},
+};
+
+/// Room in a request body for whitespace around the longest formula.
+const BODY_HEADROOM: usize = 1024;
+
+/// Serves the REST API until a shutdown is requested. Each request is handled
+/// on the shared thread pool so that a slow `set` doesn't block other
+/// requests.
+///
+/// The API is:
+///
+/// - `GET /cells/{ref}`: the value of a cell.
+/// - `PUT /cells/{ref}`: sets a cell to the expression in the request body.
+/// - `DELETE /cells/{ref}`: clears a cell.
+/// - `GET /ranges/{range}`: the values of a range such as `A1_C3`, row by row.
+///   A range can have at most `Limits::max_range_cells` cells.
+///
+/// If credentials are given, every request has to authenticate with an
+/// `Authorization: Bearer <user>:<token>` header. Bodies longer than the
+/// longest formula, give or take some whitespace, are turned away with 413.
+pub fn serve(
+    server: Server,
+    spreadsheet: Arc<Spreadsheet>,
+    pool: Arc<ThreadPool>,
+    shutdown: Shutdown,
+    credentials: Option<Arc<Credentials>>,
+    quotas: Arc<Quotas>,
+) {
+    while !shutdown.is_requested() {
+        match server.recv_timeout(POLL_INTERVAL) {
+            Ok(Some(mut request)) => {
+                // The body is read here so that a large or slow upload never
+                // holds on to a thread of the pool.
+                let max_body_len = spreadsheet.limits().max_formula_len + BODY_HEADROOM;
+                let body = read_body(&mut request, max_body_len);
+                let spreadsheet = spreadsheet.clone();
+                let credentials = credentials.clone();
+                let quotas = quotas.clone();
+                pool.spawn(move || {
+                    handle_request(&spreadsheet, request, body, credentials.as_deref(), &quotas)
+                });
+            }
+            Ok(None) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Reads the body of a request, reading no more than one byte past
+/// `max_len`. Returns the status code and error of the response if the body
+/// is too long or isn't UTF-8.
+fn read_body(request: &mut Request, max_len: usize) -> Result<String, (u16, Option<Value>)> {
+    let mut body = Vec::new();
+    if request
+        .as_reader()
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut body)
+        .is_err()
+    {
+        return Err((400, Some(json!({ "error": "Could not read request body" }))));
+    }
+    if body.len() > max_len {
+        return Err((413, Some(json!({ "error": "Request body is too large" }))));
+    }
+    String::from_utf8(body)
+        .map_err(|_| (400, Some(json!({ "error": "Request body must be UTF-8" }))))
+}
+
+fn handle_request(
+    spreadsheet: &Arc<Spreadsheet>,
+    request: Request,
+    body: Result<String, (u16, Option<Value>)>,
+    credentials: Option<&Credentials>,
+    quotas: &Quotas,
+) {
+    let (status, json) = match body {
+        Ok(body) => match authorized_user(&request, credentials) {
+            Ok(user) => route(
+                spreadsheet,
+                &request,
+                body.trim(),
+                &Origin {
+                    user,
+                    ..Origin::connect(request.remote_addr().map(|addr| addr.to_string()))
+                },
+                quotas,
+                current_timestamp(),
+            ),
+            Err(_) => (401, Some(json!({ "error": "Authentication required" }))),
+        },
+        Err(error) => error,
+    };
+
+    let response = match json {
+        Some(json) => {
+            let header = Header::from_bytes("Content-Type", "application/json")
+                .expect("Header is valid ASCII.");
+            Response::from_string(json.to_string())
+                .with_status_code(status)
+                .with_header(header)
+                .boxed()
+        }
+        None => Response::empty(status).boxed(),
+    };
+
+    // If the client has gone away there is no one to tell about the error.
+    let _ = request.respond(response);
+}
+
+/// Checks the request's bearer token, which is the user and their token
+/// separated by a colon, and returns the user. Every request is allowed
+/// without credentials, but isn't made by any user.
+fn authorized_user(
+    request: &Request,
+    credentials: Option<&Credentials>,
+) -> Result<Option<String>, ()> {
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .filter_map(|bearer| bearer.split_once(':'))
+        .find(|(user, token)| credentials.verify(user, token))
+        .map(|(user, _)| Some(user.to_string()))
+        .ok_or(())
+}
+
+/// Maps a request onto the command functions used by the text protocol.
+/// Returns the status code and the JSON body of the response, if any.
+fn route(
+    spreadsheet: &Arc<Spreadsheet>,
+    request: &Request,
+    body: &str,
+    origin: &Origin,
+    quotas: &Quotas,
+    timestamp: u64,
+) -> (u16, Option<Value>) {
+    let (method, url) = (request.method(), request.url());
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    let (resource, reference) = match segments.as_slice() {
+        [resource, reference] => (*resource, *reference),
+        _ => return not_found(),
+    };
+
+    // Quotas and access are checked with the same command the request maps
+    // onto. Each request is a connection of its own, so the quotas of a
+    // connection apply to the client's address.
+    let command = match method {
+        Method::Put => vec!["set", reference, body],
+        Method::Delete => vec!["clear", reference],
+        _ => vec!["get", reference],
+    };
+    let client = request.remote_addr().map(|addr| addr.ip());
+    if let Err(e) = quotas.check_client(client, origin.user.as_deref(), &command) {
+        return error_reply(429, e);
+    }
+
+    if !is_valid_cell(reference) || (resource == "cells" && reference.contains('_')) {
+        return (400, Some(json!({ "error": "Invalid cell" })));
+    }
+    // A range is checked before anything works through its cells, so that a
+    // huge one can't use up the server's memory.
+    let max_cells = spreadsheet.limits().max_range_cells;
+    match range_size(reference) {
+        Some(size) if size <= max_cells as u64 => {}
+        Some(_) => {
+            let message = format!("Range is too large, the limit is {} cells", max_cells);
+            return (413, Some(json!({ "error": message })));
+        }
+        None => return (400, Some(json!({ "error": "Invalid range" }))),
+    }
+
+    if let Err(e) = acl::check(spreadsheet, origin.user.as_deref(), &command) {
+        return error_reply(403, e);
+    }
+
+    match (resource, method) {
+        ("cells", Method::Get) => get_cell(spreadsheet, reference, timestamp),
+        ("cells", Method::Put) => {
+            if body.is_empty() {
+                return (400, Some(json!({ "error": "Missing expression" })));
+            }
+
+            let run = || {
+                spreadsheet.set_author(reference, origin.user.as_deref());
+                set(spreadsheet, vec!["set", reference, body], timestamp)
+            };
+            match audited(spreadsheet, origin, "set", reference, timestamp, run) {
+                Ok(_) => {
+                    quotas.count_client_cell(client, reference);
+                    get_cell(spreadsheet, reference, timestamp)
+                }
+                Err(e) => error_reply(400, e),
+            }
+        }
+        ("cells", Method::Delete) => {
+            let run = || clear(spreadsheet, reference, timestamp);
+            match audited(spreadsheet, origin, "clear", reference, timestamp, run) {
+                Ok(_) => (204, None),
+                Err(e) => error_reply(400, e),
+            }
+        }
+        ("ranges", Method::Get) => match get::get_range(spreadsheet, reference, timestamp) {
+            Ok(values) => (200, Some(json!({ "range": reference, "values": values }))),
+            Err(e) => error_reply(400, e),
+        },
+        ("cells", _) | ("ranges", _) => (405, Some(json!({ "error": "Method not allowed" }))),
+        _ => not_found(),
+    }
+}
+
+fn get_cell(spreadsheet: &Arc<Spreadsheet>, cell: &str, timestamp: u64) -> (u16, Option<Value>) {
+    match get::get(spreadsheet, vec!["get", cell], timestamp) {
+        Ok((cell, value)) if spreadsheet.is_stale(&cell) => (
+            200,
+            Some(json!({ "cell": cell, "value": value, "stale": true })),
+        ),
+        Ok((cell, value)) => (200, Some(json!({ "cell": cell, "value": value }))),
+        // The cell is valid at this point, so any error is a problem with the
+        // cell's value rather than the request.
+        Err((_cell, e)) => error_reply(422, e),
+    }
+}
+
+fn error_reply(status: u16, reply: Reply) -> (u16, Option<Value>) {
+    let message = match reply {
+        Reply::Error(e) => e,
+        Reply::Value(cell, value) => format!("{} = {}", cell, value),
+    };
+    (status, Some(json!({ "error": message })))
+}
+
+fn not_found() -> (u16, Option<Value>) {
+    (404, Some(json!({ "error": "Not found" })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    use rayon::ThreadPoolBuilder;
+
+    use super::*;
+    use crate::acl::Acl;
+    use crate::auth::hash_token;
+    use crate::limits::Limits;
+    use crate::quota::{QuotaLimits, Rate};
+    use crate::ServerOptions;
+
+    /// Starts the API on a random port with the credentials, ACL, limits and
+    /// quotas of `options`, and returns its address.
+    fn start(options: ServerOptions) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+
+        let mut spreadsheet = Spreadsheet::with_limits(options.engine, options.limits);
+        spreadsheet.set_acl(options.acl);
+        let spreadsheet = Arc::new(spreadsheet);
+        let quotas = Arc::new(Quotas::new(options.quotas));
+        thread::spawn(move || {
+            serve(
+                server,
+                spreadsheet,
+                pool,
+                options.shutdown,
+                options.credentials,
+                quotas,
+            )
+        });
+        addr
+    }
+
+    /// A minimal HTTP client. Returns the status code and the body.
+    fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+        request_as(addr, method, path, body, "")
+    }
+
+    fn request_as(
+        addr: &str,
+        method: &str,
+        path: &str,
+        body: &str,
+        headers: &str,
+    ) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\n{headers}Connection: close\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    fn parse(body: &str) -> Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_put_and_get_cells() {
+        let addr = start(ServerOptions::default());
+
+        let (status, body) = request(&addr, "PUT", "/cells/A1", "5");
+        assert_eq!(status, 200);
+        assert_eq!(
+            parse(&body),
+            json!({ "cell": "A1", "value": { "type": "Int", "value": 5 } })
+        );
+
+        request(&addr, "PUT", "/cells/B1", "A1 * 2");
+        request(&addr, "PUT", "/cells/A1", "7");
+
+        let (status, body) = request(&addr, "GET", "/cells/B1", "");
+        assert_eq!(status, 200);
+        assert_eq!(parse(&body)["value"], json!({ "type": "Int", "value": 14 }));
+    }
+
+    #[test]
+    fn test_get_range() {
+        let addr = start(ServerOptions::default());
+        request(&addr, "PUT", "/cells/A1", "1");
+        request(&addr, "PUT", "/cells/B2", "\"two words\"");
+
+        let (status, body) = request(&addr, "GET", "/ranges/A1_B2", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            parse(&body)["values"],
+            json!([
+                [{ "type": "Int", "value": 1 }, { "type": "None" }],
+                [{ "type": "None" }, { "type": "String", "value": "two words" }],
+            ])
+        );
+    }
+
+    #[test]
+    fn test_delete_cell() {
+        let addr = start(ServerOptions::default());
+        request(&addr, "PUT", "/cells/A1", "3");
+        request(&addr, "PUT", "/cells/A2", "A1 + 1");
+
+        let (status, _) = request(&addr, "DELETE", "/cells/A1", "");
+        assert_eq!(status, 204);
+
+        let (_, body) = request(&addr, "GET", "/cells/A1", "");
+        assert_eq!(parse(&body)["value"], json!({ "type": "None" }));
+    }
+
+    #[test]
+    fn test_authorization() {
+        let credentials = Credentials::parse(&format!("alice {}", hash_token("secret"))).unwrap();
+        let addr = start(ServerOptions {
+            credentials: Some(Arc::new(credentials)),
+            ..ServerOptions::default()
+        });
+
+        assert_eq!(request(&addr, "PUT", "/cells/A1", "1").0, 401);
+        let wrong = "Authorization: Bearer alice:wrong\r\n";
+        assert_eq!(request_as(&addr, "PUT", "/cells/A1", "1", wrong).0, 401);
+
+        let right = "Authorization: Bearer alice:secret\r\n";
+        assert_eq!(request_as(&addr, "PUT", "/cells/A1", "1", right).0, 200);
+        let (_, body) = request_as(&addr, "GET", "/cells/A1", "", right);
+        assert_eq!(parse(&body)["value"], json!({ "type": "Int", "value": 1 }));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let credentials = format!("alice {}\nbob {}", hash_token("a"), hash_token("b"));
+        let credentials = Credentials::parse(&credentials).unwrap();
+        let acl = Acl::parse("allow alice write A1_A9\nallow bob write B1_B9").unwrap();
+        let addr = start(ServerOptions {
+            credentials: Some(Arc::new(credentials)),
+            acl: Some(acl),
+            ..ServerOptions::default()
+        });
+        let alice = "Authorization: Bearer alice:a\r\n";
+        let bob = "Authorization: Bearer bob:b\r\n";
+
+        assert_eq!(request_as(&addr, "PUT", "/cells/A1", "1", alice).0, 200);
+        assert_eq!(request_as(&addr, "PUT", "/cells/A1", "2", bob).0, 403);
+        assert_eq!(request_as(&addr, "DELETE", "/cells/A1", "", bob).0, 403);
+        assert_eq!(request_as(&addr, "GET", "/ranges/A1_A2", "", bob).0, 403);
+
+        let (status, body) = request_as(&addr, "PUT", "/cells/B1", "A1 + 1", bob);
+        assert_eq!(status, 403);
+        assert_eq!(parse(&body), json!({ "error": "permission denied" }));
+    }
+
+    #[test]
+    fn test_quotas_apply_to_each_client() {
+        let addr = start(ServerOptions {
+            quotas: QuotaLimits {
+                connection_rate: Some(Rate {
+                    per_second: 0.01,
+                    burst: 4.0,
+                }),
+                max_cells: Some(1),
+                ..QuotaLimits::default()
+            },
+            ..ServerOptions::default()
+        });
+
+        // A refused `set` doesn't count towards the cells the client can set.
+        assert_eq!(request(&addr, "PUT", "/cells/A1", "").0, 400);
+        assert_eq!(request(&addr, "PUT", "/cells/A2", "1").0, 200);
+        assert_eq!(request(&addr, "PUT", "/cells/A3", "1").0, 429);
+
+        // Every request of the client comes out of the same bucket.
+        let (status, body) = request(&addr, "GET", "/cells/A2", "");
+        assert_eq!(status, 200, "{}", body);
+        let (status, body) = request(&addr, "GET", "/cells/A2", "");
+        assert_eq!(status, 429);
+        assert_eq!(parse(&body), json!({ "error": "Rate limit exceeded" }));
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let addr = start(ServerOptions::default());
+
+        assert_eq!(request(&addr, "GET", "/cells/a1", "").0, 400);
+        assert_eq!(request(&addr, "PUT", "/cells/A1", "").0, 400);
+        assert_eq!(request(&addr, "POST", "/cells/A1", "1").0, 405);
+        assert_eq!(request(&addr, "GET", "/sheets/A1", "").0, 404);
+        assert_eq!(request(&addr, "GET", "/cells", "").0, 404);
+
+        // Only single cells can be used with /cells, and ranges are limited
+        // in size before any of their cells are looked at.
+        assert_eq!(request(&addr, "GET", "/cells/A1_B2", "").0, 400);
+        assert_eq!(request(&addr, "PUT", "/cells/A1_B2", "1").0, 400);
+        assert_eq!(request(&addr, "GET", "/ranges/A1_ZZZ9999999", "").0, 413);
+        assert_eq!(request(&addr, "GET", "/ranges/A1_A99999999999", "").0, 400);
+        assert_eq!(request(&addr, "GET", "/ranges/A1_J1000", "").0, 200);
+    }
+
+    #[test]
+    fn test_body_size_is_limited() {
+        let addr = start(ServerOptions {
+            limits: Limits {
+                max_formula_len: 10,
+                ..Limits::default()
+            },
+            ..ServerOptions::default()
+        });
+
+        // The body can be as long as the longest formula and the headroom.
+        let padded = format!("1{}", " ".repeat(9 + BODY_HEADROOM));
+        assert_eq!(request(&addr, "PUT", "/cells/A1", &padded).0, 200);
+
+        let (status, body) = request(&addr, "PUT", "/cells/A1", &format!("{padded} "));
+        assert_eq!(status, 413);
+        assert_eq!(
+            parse(&body),
+            json!({ "error": "Request body is too large" })
+        );
+    }
+}
@@ -1,104 +1,1198 @@
-mod commands;
+pub mod acl;
+pub mod audit;
+pub mod auth;
+pub mod commands;
+pub mod config;
+pub mod connect;
+pub mod formula;
+mod http;
+pub mod limits;
+pub mod protocol;
+pub mod quota;
+pub mod scheduler;
+pub mod shutdown;
 pub mod spreadsheet;
+mod tcp;
+pub mod tls;
 pub mod utils;
+pub mod value;
+mod websocket;
 
-use rayon::ThreadPoolBuilder;
-use rsheet_lib::connect::{Manager, Reader, Writer};
+use acl::Acl;
+use audit::{audited, AuditLog, Origin};
+use auth::Credentials;
+use connect::{ConnectionLimit, LineWriter};
+use formula::EngineKind;
+use limits::Limits;
+use protocol::{CommandReply, JsonCommand, JsonReply, JsonRequest, Protocol};
+use quota::{ConnectionQuota, QuotaLimits, Quotas};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rsheet_lib::connect::{Manager, Reader, ReaderWriter, Writer};
 use rsheet_lib::replies::Reply;
+use rustls::ServerConfig;
+use shutdown::{Shutdown, POLL_INTERVAL};
 use spreadsheet::Spreadsheet;
 
-use std::sync::Arc;
-use std::time;
+use utils::current_timestamp;
 
-pub fn start_server<M>(mut manager: M)
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// Settings for the optional parts of the server.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Address for the HTTP API to listen on. The API is disabled if this is
+    /// `None`.
+    pub http_addr: Option<SocketAddr>,
+
+    /// Address for the WebSocket server to listen on. WebSockets are disabled
+    /// if this is `None`.
+    pub ws_addr: Option<SocketAddr>,
+
+    /// Requests that the server stops accepting connections and exits once
+    /// the existing connections have finished.
+    pub shutdown: Shutdown,
+
+    /// How long connections have to finish after a shutdown is requested.
+    pub drain_timeout: Duration,
+
+    /// The engine used to evaluate the expressions in cells.
+    pub engine: EngineKind,
+
+    /// The protocol connections start with, apart from WebSockets, which
+    /// always reply in the JSON format. Clients can switch with `protocol`.
+    pub protocol: Protocol,
+
+    /// Bounds on the time and work used to evaluate formulas.
+    pub limits: Limits,
+
+    /// The users allowed to connect. If set, every connection has to start
+    /// with `auth <user> <token>` before any other command is accepted, and
+    /// HTTP requests need a bearer token.
+    pub credentials: Option<Arc<Credentials>>,
+
+    /// Who can read and write each range of the spreadsheet. Every user can
+    /// access every cell if this is `None`.
+    pub acl: Option<Acl>,
+
+    /// Where every change made by a client is recorded, if anywhere.
+    pub audit_log: Option<Arc<AuditLog>>,
+
+    /// How fast clients can send commands, and how many cells each
+    /// connection can set.
+    pub quotas: QuotaLimits,
+
+    /// How often cells calling volatile functions, such as `NOW()` or
+    /// `RAND()`, are recalculated. They are only recalculated by `recalc` if
+    /// this is `None`.
+    pub recalc_interval: Option<Duration>,
+
+    /// How many TCP and WebSocket clients can be connected at once. Anyone
+    /// connecting past the limit gets an error and is disconnected straight
+    /// away.
+    pub max_connections: Option<usize>,
+
+    /// How long a client can go without sending anything before it is
+    /// disconnected. Connections from a `TcpManager` are given theirs by the
+    /// manager instead.
+    pub idle_timeout: Option<Duration>,
+
+    /// How many threads run commands and HTTP requests.
+    pub compute_threads: usize,
+
+    /// How many threads wait on TCP connections, and again on WebSocket
+    /// connections. They only move bytes around, so a couple is plenty for
+    /// thousands of connections.
+    pub io_threads: usize,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            http_addr: None,
+            ws_addr: None,
+            shutdown: Shutdown::new(),
+            drain_timeout: Duration::from_secs(10),
+            engine: EngineKind::default(),
+            protocol: Protocol::default(),
+            limits: Limits::default(),
+            credentials: None,
+            acl: None,
+            audit_log: None,
+            quotas: QuotaLimits::default(),
+            recalc_interval: None,
+            max_connections: None,
+            idle_timeout: None,
+            compute_threads: 8,
+            io_threads: 2,
+        }
+    }
+}
+
+/// Runs the server, taking connections from `manager` until it stops giving
+/// them out or a shutdown is requested. Each connection gets its own thread,
+/// so this suits a handful of connections, such as the one from the terminal.
+/// `start_tcp_server` handles any number of TCP clients.
+pub fn start_server<M>(mut manager: M, options: ServerOptions)
 where
     M: Manager + Send + 'static,
+    <M::ReaderWriter as ReaderWriter>::Writer: LineWriter,
 {
-    let spreadsheet = spreadsheet::new_shared_spreadsheet();
+    let Services {
+        spreadsheet,
+        quotas,
+        connections,
+        ..
+    } = match start_services(&options) {
+        Some(services) => services,
+        None => return,
+    };
+
+    // Connections are accepted on their own thread and handed over through a
+    // channel. `accept_new_connection` can block forever (e.g. waiting on
+    // stdin), so this lets us stop accepting once a shutdown is requested.
+    let (conn_sender, conn_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(connection) = manager.accept_new_connection() {
+            if conn_sender.send(connection).is_err() {
+                return;
+            }
+        }
+    });
+
+    // Using `scope` to ensure that all threads complete their work before
+    // the program exits. The scope runs on its own thread so that we can give
+    // up on it if connections take too long to finish during a shutdown.
+    let (done_sender, done_receiver) = mpsc::channel();
+    let shutdown = options.shutdown.clone();
+    let credentials = options.credentials.clone();
+    let protocol = options.protocol;
+    thread::spawn(move || {
+        thread::scope(|s| loop {
+            match conn_receiver.recv_timeout(POLL_INTERVAL) {
+                Ok((mut recv, mut send)) => {
+                    let slot = match connections.try_acquire() {
+                        Some(slot) => slot,
+                        None => {
+                            reject(&mut send);
+                            continue;
+                        }
+                    };
+
+                    let spreadsheet = spreadsheet.clone();
+                    let shutdown = shutdown.clone();
+                    let credentials = credentials.clone();
+                    let quotas = quotas.clone();
+                    s.spawn(move || {
+                        let _slot = slot;
+                        // A panic while handling one connection closes it,
+                        // rather than taking down the scope and every other
+                        // connection with it.
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle_connection(
+                                &spreadsheet,
+                                &mut recv,
+                                &mut send,
+                                &shutdown,
+                                credentials.as_deref(),
+                                &quotas,
+                                protocol,
+                            )
+                        }));
+                        if result.is_err() {
+                            log::error!("Closed a connection after it panicked");
+                        }
+                    });
+                }
+                Err(RecvTimeoutError::Timeout) if shutdown.is_requested() => break,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+        let _ = done_sender.send(());
+    });
+
+    wait_for_connections(&done_receiver, &options);
+
+    // Make sure that everything written to the terminal is seen before the
+    // program exits.
+    let _ = io::stdout().flush();
+}
+
+/// Runs the server, accepting TCP connections on `listener` until a shutdown
+/// is requested. Connections are handled asynchronously, so an idle one
+/// doesn't hold on to a thread, and their commands are run on a thread pool of
+/// a fixed size. Connections are wrapped in TLS if a config is given.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::net::TcpListener;
+/// # use rsheet::{start_tcp_server, ServerOptions};
+/// # fn main() -> std::io::Result<()> {
+/// let listener = TcpListener::bind("127.0.0.1:5000")?;
+/// start_tcp_server(listener, None, ServerOptions::default());
+/// # Ok(())
+/// # }
+/// ```
+pub fn start_tcp_server(
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    options: ServerOptions,
+) {
+    let services = match start_services(&options) {
+        Some(services) => services,
+        None => return,
+    };
+    tcp::serve(listener, tls, services, &options);
+
+    let _ = io::stdout().flush();
+}
+
+/// What every kind of connection shares.
+#[derive(Clone)]
+struct Services {
+    spreadsheet: Arc<Spreadsheet>,
+
+    /// Runs the commands sent over TCP, HTTP and WebSockets.
+    pool: Arc<ThreadPool>,
+    quotas: Arc<Quotas>,
+    connections: ConnectionLimit,
+}
+
+/// Creates the spreadsheet, and starts the HTTP API, WebSocket server and
+/// scheduled recalculation if they are enabled. Returns `None` if any of them
+/// couldn't be started.
+fn start_services(options: &ServerOptions) -> Option<Services> {
+    let mut spreadsheet = Spreadsheet::with_limits(options.engine, options.limits);
+    spreadsheet.set_acl(options.acl.clone());
+    spreadsheet.set_audit_log(options.audit_log.clone());
+    let spreadsheet = Arc::new(spreadsheet);
+    let quotas = Arc::new(Quotas::new(options.quotas));
+    let connections = ConnectionLimit::new(options.max_connections);
     // BUG: When letting Rayon manage the threads, the program context switches
     // and causes autotest failures. Increasing the number of threads does not
-    // fix the core issue.
-    let pool = match ThreadPoolBuilder::new().num_threads(8).build() {
-        Ok(pool) => pool,
+    // fix the core issue. Connections never hold on to one of its threads
+    // while they wait for a message: TCP and WebSocket connections only use it
+    // to run their commands, and connections from a `Manager` have their own
+    // threads.
+    //
+    // Rayon aborts the whole program if a job on the pool panics, unless the
+    // pool has a panic handler. With one, the connection whose command
+    // panicked never gets its reply and is closed, while the rest carry on.
+    let pool = match ThreadPoolBuilder::new()
+        .num_threads(options.compute_threads)
+        .panic_handler(|_| log::error!("A command panicked while running on the thread pool"))
+        .build()
+    {
+        Ok(pool) => Arc::new(pool),
         Err(e) => {
             eprintln!("Error creating thread pool: {}", e);
-            return;
+            return None;
         }
     };
 
-    // // Using `scope` to ensure that all threads complete their work before
-    // // the program exists.
-    pool.scope(|s| {
-        while let Ok((mut recv, mut send)) = manager.accept_new_connection() {
-            let spreadsheet = spreadsheet.clone();
-            s.spawn(move |_| {
-                handle_connection(&spreadsheet, &mut recv, &mut send);
-            })
-        }
+    // The HTTP API runs on its own thread, sharing the spreadsheet with the
+    // connections below and running its requests on the thread pool.
+    if let Some(addr) = options.http_addr {
+        let server = match tiny_http::Server::http(addr) {
+            Ok(server) => server,
+            Err(e) => {
+                eprintln!("Error starting HTTP server on {}: {}", addr, e);
+                return None;
+            }
+        };
+
+        let spreadsheet = spreadsheet.clone();
+        let pool = pool.clone();
+        let shutdown = options.shutdown.clone();
+        let credentials = options.credentials.clone();
+        let quotas = quotas.clone();
+        thread::spawn(move || {
+            http::serve(server, spreadsheet, pool, shutdown, credentials, quotas)
+        });
+    }
+
+    if let Some(addr) = options.ws_addr {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error starting WebSocket server on {}: {}", addr, e);
+                return None;
+            }
+        };
+
+        let services = Services {
+            spreadsheet: spreadsheet.clone(),
+            pool: pool.clone(),
+            quotas: quotas.clone(),
+            connections: connections.clone(),
+        };
+        let options = options.clone();
+        thread::spawn(move || websocket::serve(listener, services, &options));
+    }
+
+    if let Some(interval) = options.recalc_interval {
+        let spreadsheet = spreadsheet.clone();
+        let shutdown = options.shutdown.clone();
+        thread::spawn(move || scheduler::run(&spreadsheet, interval, &shutdown));
+    }
+
+    Some(Services {
+        spreadsheet,
+        pool,
+        quotas,
+        connections,
     })
 }
 
-fn handle_connection<R, W>(spreadsheet: &Arc<Spreadsheet>, reader: &mut R, writer: &mut W)
-where
+/// Waits until every connection has finished. Once a shutdown is requested,
+/// connections have `drain_timeout` to finish what they are doing.
+fn wait_for_connections(done: &Receiver<()>, options: &ServerOptions) {
+    loop {
+        match done.recv_timeout(POLL_INTERVAL) {
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) if options.shutdown.is_requested() => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+
+    if done.recv_timeout(options.drain_timeout).is_err() {
+        eprintln!("Timed out waiting for connections to finish, shutting down anyway");
+    }
+}
+
+fn handle_connection<R, W>(
+    spreadsheet: &Arc<Spreadsheet>,
+    reader: &mut R,
+    writer: &mut W,
+    shutdown: &Shutdown,
+    credentials: Option<&Credentials>,
+    quotas: &Arc<Quotas>,
+    mut protocol: Protocol,
+) where
     R: Reader,
-    W: Writer,
+    W: LineWriter + Send,
 {
-    loop {
+    // Where the connection's commands come from, including the user it has
+    // authenticated as. Until then, only `auth` and `protocol` are accepted if
+    // credentials are required. Readers without a peer, such as stdin, have
+    // an empty id.
+    let peer = Some(reader.id()).filter(|id| !id.is_empty());
+    let mut origin = Origin::connect(peer);
+    let quota = quotas.connection();
+
+    // JSON requests with an id can be run concurrently, so the writer is
+    // shared between the threads running them. Using `scope` means that all
+    // requests finish before the connection is closed.
+    let writer = Mutex::new(writer);
+    thread::scope(|s| loop {
+        // Once a shutdown is requested we stop taking new commands. Anything
+        // already running finishes before the scope ends.
+        if shutdown.is_requested() {
+            return;
+        }
+
         let msg = reader.read_message();
 
         // Get the current timestamp from the message to accommodate for the
         // complex edge case in Part 4 where recent messages are prioritised
         // over older messages that occurred after due to the use of sleep_then.
-        let timestamp = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        match msg {
-            Ok(msg) => {
-                let args: Vec<&str> = msg.split_whitespace().collect();
-                if args.is_empty() {
-                    continue;
-                }
+        let timestamp = current_timestamp();
 
-                // A valid message will always have the command as the first
-                // argument.
-                let command = args[0];
-                match command {
-                    "get" => match commands::get::get(spreadsheet, args) {
-                        Ok((cell, cell_val)) => {
-                            writer.write_message(Reply::Value(cell, cell_val)).expect(
-                                "Error could be a ConnectionError which could be a disconnection.",
-                            );
-                        }
-                        Err((_cell, e)) => {
-                            writer.write_message(e).expect(
-                                "Error could be a ConnectionError which could be a disconnection.",
-                            );
-                        }
-                    },
-                    "set" => match commands::set::set(spreadsheet, args.clone(), timestamp) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            writer.write_message(e).expect(
-                                "Error could be a ConnectionError which could be a disconnection.",
-                            );
-                        }
-                    },
-                    _ => {
-                        writer
-                            .write_message(Reply::Error(format!("Invalid command: {}", command)))
-                            .expect(
-                                "Error could be a ConnectionError which could be a disconnection.",
-                            );
-                    }
-                }
-            }
+        let msg = match msg {
+            Ok(msg) => msg,
             Err(_) => {
                 // If we get an error reading the message, we assume the client
                 // has disconnected.
                 return;
             }
+        };
+
+        let mut session = Session {
+            protocol: &mut protocol,
+            origin: &mut origin,
+            credentials,
+            quota: &quota,
+        };
+        // Requests tagged with an id are run on their own thread so that a
+        // slow request doesn't hold up the rest of the connection.
+        let run_concurrently = |request, origin: Origin| {
+            let writer = &writer;
+            let quota = &quota;
+            s.spawn(move || {
+                // If the client has disconnected, the connection finds out
+                // when it next reads a message.
+                let reply = run_json_request(spreadsheet, request, &origin, quota, timestamp);
+                write_line(writer, &reply);
+            });
+        };
+        if !handle_message(
+            spreadsheet,
+            &msg,
+            timestamp,
+            &mut session,
+            &writer,
+            run_concurrently,
+        ) {
+            return;
         }
+    });
+
+    if shutdown.is_requested() {
+        let writer = writer.into_inner().unwrap_or_else(|e| e.into_inner());
+        notify_shutdown(writer, protocol);
+    }
+}
+
+/// Turns away a client when there are already too many connections. Doing so
+/// straight away lets it retry later, rather than leaving it waiting for a
+/// reply. The client may have already disconnected, so any error is ignored.
+fn reject<W: Writer>(writer: &mut W) {
+    let message = "Too many connections, try again later".to_string();
+    let _ = writer.write_message(Reply::Error(message));
+}
+
+/// Lets the client know why the connection is being closed. They may have
+/// already disconnected, so any error is ignored.
+fn notify_shutdown<W: LineWriter>(writer: &mut W, protocol: Protocol) {
+    let message = "Server is shutting down".to_string();
+    let _ = match protocol {
+        Protocol::Text => writer.write_message(Reply::Error(message)),
+        Protocol::Json => writer.write_line(&JsonReply::error(None, message).to_line()),
+    };
+}
+
+/// The state of a connection which a message of the text protocol can
+/// change.
+struct Session<'a> {
+    protocol: &'a mut Protocol,
+    origin: &'a mut Origin,
+    credentials: Option<&'a Credentials>,
+    quota: &'a ConnectionQuota,
+}
+
+/// Handles a single message of the plain text protocol, where arguments are
+/// separated by whitespace. Returns `false` if the connection should be
+/// closed.
+fn handle_text_message<W>(
+    spreadsheet: &Arc<Spreadsheet>,
+    msg: &str,
+    timestamp: u64,
+    session: &mut Session,
+    writer: &Mutex<&mut W>,
+) -> bool
+where
+    W: LineWriter,
+{
+    let args: Vec<&str> = msg.split_whitespace().collect();
+    if args.is_empty() {
+        return true;
+    }
+
+    // Switching protocols is handled here rather than in `run_command` as it
+    // changes the state of the connection, not the spreadsheet.
+    if args[0] == "protocol" {
+        return match args.get(1).and_then(|name| Protocol::from_name(name)) {
+            Some(new_protocol) => {
+                *session.protocol = new_protocol;
+                if new_protocol == Protocol::Json {
+                    // Acknowledge the switch in the new protocol so that the
+                    // client knows when to start sending JSON.
+                    let reply = JsonReply {
+                        protocol: Some(new_protocol),
+                        ..JsonReply::ok(None)
+                    };
+                    return write_line(writer, &reply);
+                }
+                true
+            }
+            None => write_message(writer, CommandReply::Error("Invalid protocol".to_string())),
+        };
+    }
+
+    // A failed attempt to authenticate closes the connection.
+    if args[0] == "auth" {
+        return match auth::authenticate(session.credentials, &args[1..]) {
+            Ok(user) => {
+                session.origin.user = Some(user);
+                true
+            }
+            Err(e) => {
+                write_message(writer, CommandReply::Error(e));
+                false
+            }
+        };
+    }
+
+    if session.credentials.is_some() && session.origin.user.is_none() {
+        let reply = CommandReply::Error("Authentication required".to_string());
+        write_message(writer, reply);
+        return false;
+    }
+
+    match run_command(spreadsheet, args, session.origin, session.quota, timestamp) {
+        Some(reply) => write_message(writer, reply),
+        None => true,
+    }
+}
+
+/// Handles a single message from a client, in whichever protocol the
+/// connection is using. JSON requests tagged with an id are handed to
+/// `run_concurrently`, which runs them alongside the connection's later
+/// messages. Returns `false` if the connection should be closed.
+fn handle_message<W>(
+    spreadsheet: &Arc<Spreadsheet>,
+    msg: &str,
+    timestamp: u64,
+    session: &mut Session,
+    writer: &Mutex<&mut W>,
+    run_concurrently: impl FnOnce(JsonRequest, Origin),
+) -> bool
+where
+    W: LineWriter,
+{
+    match session.protocol {
+        Protocol::Text => handle_text_message(spreadsheet, msg, timestamp, session, writer),
+        Protocol::Json => handle_json_message(
+            spreadsheet,
+            msg,
+            timestamp,
+            session,
+            writer,
+            run_concurrently,
+        ),
+    }
+}
+
+/// Handles a single message of the JSON protocol. Returns `false` if the
+/// connection should be closed.
+fn handle_json_message<W>(
+    spreadsheet: &Arc<Spreadsheet>,
+    msg: &str,
+    timestamp: u64,
+    session: &mut Session,
+    writer: &Mutex<&mut W>,
+    run_concurrently: impl FnOnce(JsonRequest, Origin),
+) -> bool
+where
+    W: LineWriter,
+{
+    if msg.trim().is_empty() {
+        return true;
+    }
+
+    match serde_json::from_str::<JsonRequest>(msg) {
+        // Authenticating changes the state of the connection, so it always
+        // happens in order. A failed attempt closes the connection.
+        Ok(JsonRequest {
+            id,
+            command: JsonCommand::Auth { user: name, token },
+        }) => match auth::authenticate(session.credentials, &[&name, &token]) {
+            Ok(name) => {
+                session.origin.user = Some(name);
+                write_line(writer, &JsonReply::ok(id))
+            }
+            Err(e) => {
+                write_line(writer, &JsonReply::error(id, e));
+                false
+            }
+        },
+        // Switching protocols changes the state of the connection, so it
+        // always happens in order.
+        Ok(JsonRequest {
+            id,
+            command: JsonCommand::Protocol { mode },
+        }) => {
+            *session.protocol = mode;
+            let reply = JsonReply {
+                protocol: Some(mode),
+                ..JsonReply::ok(id)
+            };
+            write_line(writer, &reply)
+        }
+        Ok(JsonRequest { id, .. })
+            if session.credentials.is_some() && session.origin.user.is_none() =>
+        {
+            let reply = JsonReply::error(id, "Authentication required".to_string());
+            write_line(writer, &reply);
+            false
+        }
+        // Requests tagged with an id are run concurrently so that a slow
+        // request doesn't hold up the rest of the connection. The id in the
+        // reply lets the client match it to the request.
+        Ok(request) if request.id.is_some() => {
+            run_concurrently(request, session.origin.clone());
+            true
+        }
+        // Requests without an id are run in order, as in the text protocol.
+        Ok(request) => {
+            let reply = run_json_request(
+                spreadsheet,
+                request,
+                session.origin,
+                session.quota,
+                timestamp,
+            );
+            write_line(writer, &reply)
+        }
+        Err(e) => {
+            let reply = JsonReply::error(None, format!("Invalid request: {}", e));
+            write_line(writer, &reply)
+        }
+    }
+}
+
+/// Runs a single request of the JSON protocol. Every request gets exactly one
+/// reply, including `set` which is silent in the text protocol.
+fn run_json_request(
+    spreadsheet: &Arc<Spreadsheet>,
+    request: JsonRequest,
+    origin: &Origin,
+    quota: &ConnectionQuota,
+    timestamp: u64,
+) -> JsonReply {
+    let JsonRequest { id, command } = request;
+    let reply = match command {
+        JsonCommand::Get { cell } => {
+            run_command(spreadsheet, vec!["get", &cell], origin, quota, timestamp)
+        }
+        // The expression is passed through as a single argument so that any
+        // whitespace inside of it is preserved.
+        JsonCommand::Set { cell, expr } => run_command(
+            spreadsheet,
+            vec!["set", &cell, &expr],
+            origin,
+            quota,
+            timestamp,
+        ),
+        JsonCommand::Recalc { range } => {
+            let mut args = vec!["recalc"];
+            args.extend(range.as_deref());
+            run_command(spreadsheet, args, origin, quota, timestamp)
+        }
+        JsonCommand::Calcmode { mode } => run_command(
+            spreadsheet,
+            vec!["calcmode", mode.name()],
+            origin,
+            quota,
+            timestamp,
+        ),
+        JsonCommand::Lock { range } => {
+            run_command(spreadsheet, vec!["lock", &range], origin, quota, timestamp)
+        }
+        JsonCommand::Unlock { range } => run_command(
+            spreadsheet,
+            vec!["unlock", &range],
+            origin,
+            quota,
+            timestamp,
+        ),
+        JsonCommand::Protocol { .. } => {
+            return JsonReply::error(id, "Protocol can't be changed here".to_string())
+        }
+        JsonCommand::Auth { .. } => {
+            return JsonReply::error(id, "Can't authenticate here".to_string())
+        }
+    };
+
+    match reply {
+        Some(reply) => JsonReply::from_reply(id, reply),
+        None => JsonReply::ok(id),
+    }
+}
+
+/// Sends a reply of the text protocol. Returns `false` if it couldn't be
+/// written, which we take to mean that the client has disconnected.
+fn write_message<W: LineWriter>(writer: &Mutex<&mut W>, reply: CommandReply) -> bool {
+    lock(writer).write_reply(reply).is_ok()
+}
+
+/// Sends a reply of the JSON protocol. Returns `false` if it couldn't be
+/// written, which we take to mean that the client has disconnected.
+fn write_line<W: LineWriter>(writer: &Mutex<&mut W>, reply: &JsonReply) -> bool {
+    lock(writer).write_line(&reply.to_line()).is_ok()
+}
+
+/// Locks the writer of a connection. A request that panicked while writing
+/// leaves the writer as usable as any other failed write, so the poison is
+/// ignored.
+fn lock<'a, 'w, W>(writer: &'a Mutex<&'w mut W>) -> MutexGuard<'a, &'w mut W> {
+    writer.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs a command against the spreadsheet on behalf of the user it came from,
+/// within the quotas of their connection. Changes are recorded in the audit
+/// log. A valid command will always have the command as the first argument.
+/// Returns the reply to send back to the client, if there is one.
+fn run_command(
+    spreadsheet: &Arc<Spreadsheet>,
+    args: Vec<&str>,
+    origin: &Origin,
+    quota: &ConnectionQuota,
+    timestamp: u64,
+) -> Option<CommandReply> {
+    let user = origin.user.as_deref();
+    if let Err(e) = quota
+        .check(user, &args)
+        .and_then(|_| acl::check(spreadsheet, user, &args))
+    {
+        return Some(e.into());
+    }
+
+    let command = args[0];
+    let cell = args.get(1).copied().unwrap_or_default();
+    match command {
+        "get" => match commands::get::get(spreadsheet, args, timestamp) {
+            Ok((cell, cell_val)) if spreadsheet.is_stale(&cell) => {
+                Some(CommandReply::Stale(cell, cell_val))
+            }
+            Ok((cell, cell_val)) => Some(CommandReply::Value(cell, cell_val)),
+            Err((_cell, e)) => Some(e.into()),
+        },
+        "set" => {
+            let result = audited(spreadsheet, origin, command, cell, timestamp, || {
+                spreadsheet.set_author(cell, user);
+                commands::set::set(spreadsheet, args, timestamp)
+            });
+            if result.is_ok() {
+                quota.count_cell(cell);
+            }
+            result.err().map(CommandReply::from)
+        }
+        "recalc" => commands::recalc::recalc(spreadsheet, args, timestamp)
+            .err()
+            .map(CommandReply::from),
+        "calcmode" => commands::calcmode::calcmode(spreadsheet, args, timestamp)
+            .err()
+            .map(CommandReply::from),
+        "lock" => audited(spreadsheet, origin, command, cell, timestamp, || {
+            commands::lock::lock(spreadsheet, args)
+        })
+        .err()
+        .map(CommandReply::from),
+        "unlock" => audited(spreadsheet, origin, command, cell, timestamp, || {
+            commands::lock::unlock(spreadsheet, args)
+        })
+        .err()
+        .map(CommandReply::from),
+        _ => Some(CommandReply::Error(format!("Invalid command: {}", command))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::value::Value;
+    use rsheet_lib::connect::{ConnectionError, Writer};
+
+    use super::*;
+
+    /// Replays a fixed list of messages, then disconnects.
+    struct MockReader(VecDeque<String>);
+
+    impl MockReader {
+        fn new(messages: &[&str]) -> Self {
+            Self(messages.iter().map(|msg| msg.to_string()).collect())
+        }
+    }
+
+    impl Reader for MockReader {
+        fn read_message(&mut self) -> Result<String, ConnectionError> {
+            self.0.pop_front().ok_or(ConnectionError::ConnectionClosed)
+        }
+
+        fn id(&self) -> String {
+            "mock".to_string()
+        }
+    }
+
+    /// Records every line written to it.
+    #[derive(Default)]
+    struct MockWriter(Vec<String>);
+
+    impl Writer for MockWriter {
+        fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+            self.0.push(serde_json::to_string(&message).unwrap());
+            Ok(())
+        }
+
+        fn id(&self) -> String {
+            "mock".to_string()
+        }
+    }
+
+    impl LineWriter for MockWriter {
+        fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+            self.0.push(line.to_string());
+            Ok(())
+        }
+    }
+
+    /// Records lines like `MockWriter` until `limit` of them have been
+    /// written, as if the client then disconnected. Any write after that
+    /// fails, or panics if `panics` is set.
+    struct DisconnectingWriter {
+        lines: Arc<Mutex<Vec<String>>>,
+        limit: usize,
+        panics: bool,
+    }
+
+    impl DisconnectingWriter {
+        fn new(limit: usize) -> Self {
+            Self {
+                lines: Arc::default(),
+                limit,
+                panics: false,
+            }
+        }
+
+        fn write(&mut self, line: String) -> Result<(), ConnectionError> {
+            let mut lines = self.lines.lock().unwrap();
+            if lines.len() < self.limit {
+                lines.push(line);
+                Ok(())
+            } else if self.panics {
+                panic!("Client disconnected");
+            } else {
+                Err(ConnectionError::ConnectionClosed)
+            }
+        }
+    }
+
+    impl Writer for DisconnectingWriter {
+        fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+            self.write(serde_json::to_string(&message).unwrap())
+        }
+
+        fn id(&self) -> String {
+            "mock".to_string()
+        }
+    }
+
+    impl LineWriter for DisconnectingWriter {
+        fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+            self.write(line.to_string())
+        }
+    }
+
+    /// Hands out a fixed list of connections, then stops accepting.
+    struct MockManager(VecDeque<(MockReader, DisconnectingWriter)>);
+
+    struct MockReaderWriter;
+
+    impl ReaderWriter for MockReaderWriter {
+        type Reader = MockReader;
+        type Writer = DisconnectingWriter;
+    }
+
+    impl Manager for MockManager {
+        type ReaderWriter = MockReaderWriter;
+
+        fn accept_new_connection(&mut self) -> Result<(MockReader, DisconnectingWriter), ()> {
+            self.0.pop_front().ok_or(())
+        }
+    }
+
+    fn unlimited() -> Arc<Quotas> {
+        Arc::new(Quotas::default())
+    }
+
+    #[test]
+    fn test_requests_with_ids_reply_out_of_order() {
+        let spreadsheet = spreadsheet::new_shared_spreadsheet();
+        let mut reader = MockReader::new(&[
+            "protocol json",
+            r#"{"op":"set","cell":"A2","expr":"2"}"#,
+            r#"{"id":1,"op":"set","cell":"A1","expr":"sleep_then(500, 1)"}"#,
+            r#"{"id":2,"op":"get","cell":"A2"}"#,
+        ]);
+        let mut writer = MockWriter::default();
+
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut writer,
+            &Shutdown::new(),
+            None,
+            &unlimited(),
+            Protocol::Text,
+        );
+
+        assert_eq!(
+            writer.0,
+            vec![
+                r#"{"id":null,"status":"ok","protocol":"json"}"#,
+                r#"{"id":null,"status":"ok"}"#,
+                r#"{"id":2,"status":"ok","cell":"A2","value":{"type":"Int","value":2}}"#,
+                r#"{"id":1,"status":"ok"}"#,
+            ]
+        );
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(1));
+    }
+
+    #[test]
+    fn test_shutdown_stops_new_commands_and_notifies() {
+        let spreadsheet = spreadsheet::new_shared_spreadsheet();
+        let mut reader = MockReader::new(&["set A1 1", "get A1"]);
+        let mut writer = MockWriter::default();
+
+        let shutdown = Shutdown::new();
+        shutdown.request();
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut writer,
+            &shutdown,
+            None,
+            &unlimited(),
+            Protocol::Text,
+        );
+
+        assert_eq!(writer.0, vec![r#"{"Error":"Server is shutting down"}"#]);
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::None);
+    }
+
+    #[test]
+    fn test_manual_calculation_reports_stale_values() {
+        let spreadsheet = spreadsheet::new_shared_spreadsheet();
+        let mut reader = MockReader::new(&[
+            "protocol json",
+            r#"{"op":"calcmode","mode":"manual"}"#,
+            r#"{"op":"set","cell":"A1","expr":"2"}"#,
+            r#"{"op":"set","cell":"A2","expr":"A1 * 3"}"#,
+            r#"{"op":"get","cell":"A2"}"#,
+            r#"{"op":"recalc"}"#,
+            r#"{"op":"get","cell":"A2"}"#,
+        ]);
+        let mut writer = MockWriter::default();
+
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut writer,
+            &Shutdown::new(),
+            None,
+            &unlimited(),
+            Protocol::Text,
+        );
+
+        assert_eq!(
+            writer.0[4],
+            r#"{"id":null,"status":"ok","cell":"A2","value":{"type":"None"},"stale":true}"#
+        );
+        assert_eq!(
+            writer.0[6],
+            r#"{"id":null,"status":"ok","cell":"A2","value":{"type":"Int","value":6}}"#
+        );
+    }
+
+    #[test]
+    fn test_connections_have_to_authenticate() {
+        let credentials = format!("alice {}", auth::hash_token("secret"));
+        let credentials = Credentials::parse(&credentials).unwrap();
+        let run = |messages: &[&str]| {
+            let spreadsheet = spreadsheet::new_shared_spreadsheet();
+            let mut reader = MockReader::new(messages);
+            let mut writer = MockWriter::default();
+            let shutdown = Shutdown::new();
+            handle_connection(
+                &spreadsheet,
+                &mut reader,
+                &mut writer,
+                &shutdown,
+                Some(&credentials),
+                &unlimited(),
+                Protocol::Text,
+            );
+            writer.0
+        };
+
+        // Anything before `auth` closes the connection.
+        assert_eq!(
+            run(&["set A1 1", "auth alice secret", "get A1"]),
+            vec![r#"{"Error":"Authentication required"}"#]
+        );
+        assert_eq!(
+            run(&["auth alice wrong", "get A1"]),
+            vec![r#"{"Error":"Authentication failed"}"#]
+        );
+        assert_eq!(
+            run(&["auth alice secret", "set A1 1", "get A1"]),
+            vec![r#"{"Value":["A1",1]}"#]
+        );
+        assert_eq!(
+            run(&[
+                "protocol json",
+                r#"{"id":1,"op":"auth","user":"alice","token":"secret"}"#,
+                r#"{"op":"get","cell":"A1"}"#,
+            ]),
+            vec![
+                r#"{"id":null,"status":"ok","protocol":"json"}"#,
+                r#"{"id":1,"status":"ok"}"#,
+                r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"None"}}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_users_only_access_their_ranges() {
+        let credentials = format!(
+            "alice {}\nbob {}",
+            auth::hash_token("a"),
+            auth::hash_token("b")
+        );
+        let credentials = Credentials::parse(&credentials).unwrap();
+        let mut spreadsheet = Spreadsheet::new();
+        spreadsheet.set_acl(Some(
+            Acl::parse("allow alice write A1_A9\nallow bob write B1_B9").unwrap(),
+        ));
+        let spreadsheet = Arc::new(spreadsheet);
+        let run = |messages: &[&str]| {
+            let mut reader = MockReader::new(messages);
+            let mut writer = MockWriter::default();
+            handle_connection(
+                &spreadsheet,
+                &mut reader,
+                &mut writer,
+                &Shutdown::new(),
+                Some(&credentials),
+                &unlimited(),
+                Protocol::Text,
+            );
+            writer.0
+        };
+
+        let denied = r#"{"Error":"permission denied"}"#;
+        assert!(run(&["auth alice a", "set A1 5"]).is_empty());
+        assert_eq!(
+            run(&[
+                "auth bob b",
+                "get A1",
+                "set A2 1",
+                "set B1 sum(A1_A3)",
+                "get B1"
+            ]),
+            vec![denied, denied, denied, r#"{"Value":["B1",null]}"#]
+        );
+    }
+
+    #[test]
+    fn test_commands_over_the_limits_get_an_error() {
+        let quotas = Arc::new(Quotas::new(QuotaLimits {
+            connection_rate: Some(quota::Rate {
+                per_second: 0.001,
+                burst: 3.0,
+            }),
+            ..QuotaLimits::default()
+        }));
+        let spreadsheet = spreadsheet::new_shared_spreadsheet();
+        let mut reader = MockReader::new(&["set A1 1", "get A1", "get A1", "get A1"]);
+        let mut writer = MockWriter::default();
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut writer,
+            &Shutdown::new(),
+            None,
+            &quotas,
+            Protocol::Text,
+        );
+
+        assert_eq!(
+            writer.0,
+            vec![
+                r#"{"Value":["A1",1]}"#,
+                r#"{"Value":["A1",1]}"#,
+                r#"{"Error":"Rate limit exceeded"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disconnected_clients_close_the_connection() {
+        let spreadsheet = spreadsheet::new_shared_spreadsheet();
+
+        // The client disconnects before the reply to `get A1`, so the
+        // messages after it are never read.
+        let mut reader = MockReader::new(&["set A1 1", "get A1", "set A2 2", "get A2"]);
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut DisconnectingWriter::new(0),
+            &Shutdown::new(),
+            None,
+            &unlimited(),
+            Protocol::Text,
+        );
+        assert_eq!(reader.0.len(), 2);
+        assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(1));
+        assert_eq!(spreadsheet.get_cell_val("A2"), Value::None);
+
+        // Likewise with the JSON protocol, where a request with an id can
+        // fail to reply without holding up the connection.
+        let mut reader = MockReader::new(&[
+            "protocol json",
+            r#"{"id":1,"op":"get","cell":"A1"}"#,
+            r#"{"op":"set","cell":"A3","expr":"3"}"#,
+            r#"{"op":"set","cell":"A4","expr":"4"}"#,
+        ]);
+        let mut writer = DisconnectingWriter::new(1);
+        handle_connection(
+            &spreadsheet,
+            &mut reader,
+            &mut writer,
+            &Shutdown::new(),
+            None,
+            &unlimited(),
+            Protocol::Text,
+        );
+        assert_eq!(reader.0.len(), 1);
+        assert_eq!(writer.lines.lock().unwrap().len(), 1);
+        assert_eq!(spreadsheet.get_cell_val("A3"), Value::Int(3));
+        assert_eq!(spreadsheet.get_cell_val("A4"), Value::None);
+    }
+
+    #[test]
+    fn test_a_panicking_connection_doesnt_affect_others() {
+        let panicking = DisconnectingWriter {
+            panics: true,
+            ..DisconnectingWriter::new(0)
+        };
+        let writer = DisconnectingWriter::new(usize::MAX);
+        let lines = writer.lines.clone();
+        let manager = MockManager(VecDeque::from([
+            (MockReader::new(&["get A1"]), panicking),
+            (MockReader::new(&["set A1 1", "get A1"]), writer),
+        ]));
+
+        start_server(manager, ServerOptions::default());
+
+        assert_eq!(*lines.lock().unwrap(), vec![r#"{"Value":["A1",1]}"#]);
+    }
+
+    #[test]
+    fn test_panics_on_the_pool_dont_abort_the_server() {
+        let options = ServerOptions {
+            compute_threads: 1,
+            ..ServerOptions::default()
+        };
+        let services = start_services(&options).unwrap();
+
+        // With a single thread, the panic has been dealt with by the time the
+        // pool runs anything else.
+        let (sender, receiver) = mpsc::channel::<()>();
+        services.pool.spawn(move || {
+            let _sender = sender;
+            panic!("Evaluation failed");
+        });
+        assert!(receiver.recv().is_err());
+        assert_eq!(services.pool.install(|| 1 + 1), 2);
     }
 }
@@ -0,0 +1,169 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use crate::value::{CellError, ErrorKind};
+
+/// Bounds on the work a single command can cause, so that one expensive
+/// formula can't tie up a worker thread forever.
+///
+/// # Example
+///
+/// ```
+/// # use std::time::Duration;
+/// # use rsheet::formula::EngineKind;
+/// # use rsheet::limits::Limits;
+/// # use rsheet::spreadsheet::Spreadsheet;
+/// let limits = Limits {
+///     timeout: Duration::from_millis(500),
+///     ..Limits::default()
+/// };
+/// let spreadsheet = Spreadsheet::with_limits(EngineKind::Native, limits);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// How long a single formula can take to evaluate.
+    pub timeout: Duration,
+
+    /// How many steps the native engine can take to evaluate a single
+    /// formula. Each part of the formula is a step, as is each value an
+    /// operator or function works through.
+    pub max_steps: u64,
+
+    /// How long a chain of dependent cells a recalculation can follow.
+    pub max_depth: usize,
+
+    /// How many cells can depend directly on a cell that is recalculated.
+    pub max_fan_out: usize,
+
+    /// How many characters a formula can have.
+    pub max_formula_len: usize,
+
+    /// How deeply the parts of a formula can be nested in one another, such
+    /// as brackets within brackets or calls within calls. Each operator in a
+    /// chain such as `1 + 2 + 3` nests the ones before it a level deeper. The
+    /// native engine parses and evaluates formulas recursively, so this keeps
+    /// it within its stack.
+    pub max_nesting: usize,
+
+    /// How many cells a range can cover, whether it is used in a formula or
+    /// read over HTTP. Each cell of a range in a formula becomes a dependency
+    /// and a value, so this is checked before any of them are made.
+    pub max_range_cells: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_steps: 1_000_000,
+            max_depth: 200,
+            max_fan_out: 10_000,
+            max_formula_len: 4096,
+            max_nesting: 64,
+            max_range_cells: 100_000,
+        }
+    }
+}
+
+/// Keeps track of the time and steps used by one evaluation.
+#[derive(Debug)]
+pub struct Budget {
+    timeout: Duration,
+    deadline: Instant,
+    max_steps: u64,
+    steps: Cell<u64>,
+}
+
+impl Budget {
+    /// Starts a budget for an evaluation starting now.
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            timeout: limits.timeout,
+            deadline: Instant::now() + limits.timeout,
+            max_steps: limits.max_steps,
+            steps: Cell::new(0),
+        }
+    }
+
+    /// Uses up some steps, failing if there are no steps or no time left.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::limits::{Budget, Limits};
+    /// let budget = Budget::new(&Limits { max_steps: 10, ..Limits::default() });
+    /// assert!(budget.spend(10).is_ok());
+    /// assert!(budget.spend(1).is_err());
+    /// ```
+    pub fn spend(&self, steps: u64) -> Result<(), CellError> {
+        let steps = self.steps.get().saturating_add(steps);
+        self.steps.set(steps);
+
+        if steps > self.max_steps {
+            return Err(CellError::new(
+                ErrorKind::Calc,
+                format!("Evaluation exceeded the limit of {} steps", self.max_steps),
+            ));
+        }
+        self.check_time()
+    }
+
+    /// Sleeps for a while, but gives up at the deadline rather than sleeping
+    /// past it.
+    pub fn sleep(&self, duration: Duration) -> Result<(), CellError> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        std::thread::sleep(duration.min(remaining));
+        self.check_time()
+    }
+
+    fn check_time(&self) -> Result<(), CellError> {
+        match Instant::now() >= self.deadline {
+            true => Err(timed_out(self.timeout)),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self::new(&Limits::default())
+    }
+}
+
+/// The error for an evaluation which took longer than `timeout`.
+pub fn timed_out(timeout: Duration) -> CellError {
+    CellError::new(
+        ErrorKind::Calc,
+        format!("Evaluation timed out after {:?}", timeout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps() {
+        let budget = Budget::new(&Limits {
+            max_steps: 10,
+            ..Limits::default()
+        });
+
+        assert!(budget.spend(4).is_ok());
+        assert!(budget.spend(6).is_ok());
+        assert!(budget.spend(1).is_err());
+    }
+
+    #[test]
+    fn test_sleep_stops_at_deadline() {
+        let budget = Budget::new(&Limits {
+            timeout: Duration::from_millis(50),
+            ..Limits::default()
+        });
+
+        let start = Instant::now();
+        assert!(budget.sleep(Duration::from_secs(60)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(budget.spend(1).is_err());
+    }
+}
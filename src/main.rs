@@ -1,33 +1,358 @@
 use std::error::Error;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
-use rsheet::start_server;
-use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
+use rsheet::acl::Acl;
+use rsheet::audit::{self, AuditLog};
+use rsheet::auth::{self, Credentials};
+use rsheet::config::{
+    Config, FormulaSection, ProtocolSection, QuotaSection, SecuritySection, ServerSection,
+};
+use rsheet::limits::Limits;
+use rsheet::quota::{QuotaLimits, Rate};
+use rsheet::shutdown::Shutdown;
+use rsheet::{start_server, start_tcp_server, tls, ServerOptions};
+use rsheet_lib::connect::{resolve_address, TerminalManager};
 
+/// Every option except `--mark-mode`, `--hash-token` and the audit queries
+/// can also be set in the config file, and the command line takes precedence.
 #[derive(Parser, Debug)]
 struct Args {
     /// Address to listen on
     addr: Option<String>,
 
+    /// TOML file of settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Address for the HTTP API to listen on, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    http: Option<String>,
+
+    /// Address for the WebSocket server to listen on, e.g. 127.0.0.1:8081
+    #[arg(long)]
+    ws: Option<String>,
+
+    /// Engine used to evaluate expressions: rhai or native. Defaults to rhai
+    #[arg(long)]
+    engine: Option<String>,
+
+    /// Longest time a single formula can take to evaluate, in milliseconds
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
+    /// Most steps the native engine can take to evaluate a single formula
+    #[arg(long)]
+    max_steps: Option<u64>,
+
+    /// Longest chain of dependent cells a recalculation can follow
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Most cells that can depend directly on a recalculated cell
+    #[arg(long)]
+    max_fan_out: Option<usize>,
+
+    /// Longest formula that can be set, in characters
+    #[arg(long)]
+    max_formula_len: Option<usize>,
+
+    /// How deeply a formula can nest brackets, calls and chained operators
+    #[arg(long)]
+    max_nesting: Option<usize>,
+
+    /// Most cells a range in a formula or an HTTP read can cover
+    #[arg(long)]
+    max_range_cells: Option<usize>,
+
+    /// Commands each connection can send per second. Unlimited if not given
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Commands each authenticated user can send per second, across all of
+    /// their connections. Unlimited if not given
+    #[arg(long)]
+    user_rate_limit: Option<f64>,
+
+    /// Commands a client can send at once before its rate limit applies.
+    /// Defaults to one second's worth of commands
+    #[arg(long)]
+    rate_burst: Option<f64>,
+
+    /// Most cells each connection can set
+    #[arg(long)]
+    max_cells: Option<usize>,
+
+    /// How often cells calling volatile functions such as NOW() and RAND() are
+    /// recalculated, in milliseconds. 0 disables scheduled recalculation
+    #[arg(long)]
+    recalc_interval_ms: Option<u64>,
+
+    /// Most TCP and WebSocket clients that can be connected at once. Unlimited
+    /// if not given
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// How long a client can go without sending anything before it is
+    /// disconnected, in seconds. 0 never disconnects idle clients
+    #[arg(long)]
+    idle_timeout_secs: Option<u64>,
+
+    /// How long connections have to finish once a shutdown is requested, in
+    /// seconds. Defaults to 10
+    #[arg(long)]
+    drain_timeout_secs: Option<u64>,
+
+    /// The protocol connections start with: text or json. Defaults to text
+    #[arg(long)]
+    protocol: Option<String>,
+
+    /// Threads running commands and HTTP requests. Defaults to 8
+    #[arg(long)]
+    compute_threads: Option<usize>,
+
+    /// Threads waiting on TCP connections, and again on WebSocket
+    /// connections. Defaults to 2
+    #[arg(long)]
+    io_threads: Option<usize>,
+
+    /// What to log: off, error, warn, info, debug or trace. Defaults to error,
+    /// and RUST_LOG takes precedence
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// File of users and hashed tokens. If given, connections have to
+    /// authenticate with `auth <user> <token>` before anything else
+    #[arg(long)]
+    auth_file: Option<PathBuf>,
+
+    /// File of rules granting users read or write access to ranges. If given,
+    /// anything not granted is denied
+    #[arg(long)]
+    acl_file: Option<PathBuf>,
+
+    /// PEM file holding the certificate chain to accept TLS connections with.
+    /// Connections to the address are plain TCP without it
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file holding the private key of the TLS certificate
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+
+    /// File to append a record of every change to
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Prints the changes recorded in the audit log, then exits
+    #[arg(long)]
+    query_audit: bool,
+
+    /// Only prints changes to cells in this range when querying the audit log
+    #[arg(long, requires = "query_audit")]
+    audit_cell: Option<String>,
+
+    /// Only prints changes made by this user when querying the audit log
+    #[arg(long, requires = "query_audit")]
+    audit_user: Option<String>,
+
+    /// Prints the hash of a token for the auth file, then exits
+    #[arg(long)]
+    hash_token: Option<String>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+impl Args {
+    /// The settings given on the command line, to apply over the config
+    /// file.
+    fn overrides(&self) -> Config {
+        Config {
+            server: ServerSection {
+                addr: self.addr.clone(),
+                http: self.http.clone(),
+                ws: self.ws.clone(),
+                tls_cert: self.tls_cert.clone(),
+                tls_key: self.tls_key.clone(),
+                compute_threads: self.compute_threads,
+                io_threads: self.io_threads,
+                max_connections: self.max_connections,
+                idle_timeout_secs: self.idle_timeout_secs,
+                drain_timeout_secs: self.drain_timeout_secs,
+                log_level: self.log_level.clone(),
+            },
+            formulas: FormulaSection {
+                engine: self.engine.clone(),
+                timeout_ms: self.timeout_ms,
+                max_steps: self.max_steps,
+                max_depth: self.max_depth,
+                max_fan_out: self.max_fan_out,
+                max_formula_len: self.max_formula_len,
+                max_nesting: self.max_nesting,
+                max_range_cells: self.max_range_cells,
+                recalc_interval_ms: self.recalc_interval_ms,
+            },
+            quotas: QuotaSection {
+                rate_limit: self.rate_limit,
+                user_rate_limit: self.user_rate_limit,
+                rate_burst: self.rate_burst,
+                max_cells: self.max_cells,
+            },
+            protocol: ProtocolSection {
+                default: self.protocol.clone(),
+            },
+            security: SecuritySection {
+                auth_file: self.auth_file.clone(),
+                acl_file: self.acl_file.clone(),
+                audit_log: self.audit_log.clone(),
+            },
+        }
+    }
+}
 
-    let args = Args::parse();
+fn main() -> ExitCode {
+    // Errors are shown with `Display` rather than the `Debug` that returning
+    // them from `main` would use, as they are meant for whoever is starting
+    // the server.
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    if let Some(addr) = args.addr {
-        let addr = resolve_address(&addr)?;
-        let manager = ConnectionManager::launch(addr.ip(), addr.port());
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    if let Some(token) = args.hash_token {
+        println!("{}", auth::hash_token(&token));
+        return Ok(());
+    }
+
+    let file = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let config = file.merge(args.overrides());
+    config
+        .validate()
+        .map_err(|e| format!("Invalid configuration:\n{}", e))?;
+
+    env_logger::Builder::new()
+        .filter_level(config.log_level())
+        .parse_default_env()
+        .init();
+
+    if args.query_audit {
+        let path = config
+            .security
+            .audit_log
+            .ok_or("--query-audit needs --audit-log or security.audit_log")?;
+        let entries = audit::query(path, args.audit_cell.as_deref(), args.audit_user.as_deref())?;
+        for entry in entries {
+            println!("{}", entry);
+        }
+        return Ok(());
+    }
+
+    // The first SIGINT/SIGTERM starts a graceful shutdown, a second one exits
+    // straight away.
+    let shutdown = Shutdown::new();
+    let handle = shutdown.clone();
+    ctrlc::set_handler(move || {
+        if handle.request() {
+            std::process::exit(130);
+        }
+        eprintln!("Shutting down, waiting for connections to finish...");
+    })?;
 
-        start_server(manager);
-        Ok(())
+    let Config {
+        server,
+        formulas,
+        quotas,
+        security,
+        ..
+    } = config.clone();
+
+    let defaults = Limits::default();
+    let limits = Limits {
+        timeout: formulas
+            .timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.timeout),
+        max_steps: formulas.max_steps.unwrap_or(defaults.max_steps),
+        max_depth: formulas.max_depth.unwrap_or(defaults.max_depth),
+        max_fan_out: formulas.max_fan_out.unwrap_or(defaults.max_fan_out),
+        max_formula_len: formulas.max_formula_len.unwrap_or(defaults.max_formula_len),
+        max_nesting: formulas.max_nesting.unwrap_or(defaults.max_nesting),
+        max_range_cells: formulas.max_range_cells.unwrap_or(defaults.max_range_cells),
+    };
+
+    let rate = |per_second: f64| Rate {
+        per_second,
+        burst: quotas.rate_burst.unwrap_or(per_second).max(1.0),
+    };
+    let quotas = QuotaLimits {
+        connection_rate: quotas.rate_limit.map(rate),
+        user_rate: quotas.user_rate_limit.map(rate),
+        max_cells: quotas.max_cells,
+    };
+
+    let defaults = ServerOptions::default();
+    let options = ServerOptions {
+        http_addr: server.http.as_deref().map(resolve_address).transpose()?,
+        ws_addr: server.ws.as_deref().map(resolve_address).transpose()?,
+        shutdown: shutdown.clone(),
+        drain_timeout: server
+            .drain_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(defaults.drain_timeout),
+        engine: config.engine(),
+        limits,
+        protocol: config.protocol(),
+        quotas,
+        credentials: security
+            .auth_file
+            .map(Credentials::load)
+            .transpose()?
+            .map(Arc::new),
+        acl: security.acl_file.map(Acl::load).transpose()?,
+        audit_log: security
+            .audit_log
+            .map(AuditLog::open)
+            .transpose()?
+            .map(Arc::new),
+        recalc_interval: formulas
+            .recalc_interval_ms
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis),
+        max_connections: server.max_connections,
+        idle_timeout: server
+            .idle_timeout_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        compute_threads: server.compute_threads.unwrap_or(defaults.compute_threads),
+        io_threads: server.io_threads.unwrap_or(defaults.io_threads),
+    };
+
+    let tls = match (server.tls_cert, server.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::load_config(cert, key)?),
+        _ => None,
+    };
+
+    if let Some(addr) = server.addr {
+        let addr = resolve_address(&addr)?;
+        let listener = TcpListener::bind(addr)?;
+        start_tcp_server(listener, tls, options);
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager);
-        Ok(())
+        start_server(manager, options);
     }
+    Ok(())
 }
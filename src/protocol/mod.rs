@@ -0,0 +1,257 @@
+use rsheet_lib::replies::Reply;
+use serde::{Deserialize, Serialize};
+
+use crate::spreadsheet::CalcMode;
+use crate::value::Value;
+
+/// The wire format a connection is currently speaking. Connections start with
+/// the server's default protocol, text unless configured otherwise, and can
+/// switch with `protocol json` or `protocol text`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Text,
+    Json,
+}
+
+impl Protocol {
+    /// Parses the protocol name used by the `protocol` command.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::protocol::Protocol;
+    /// assert_eq!(Protocol::from_name("json"), Some(Protocol::Json));
+    /// assert_eq!(Protocol::from_name("xml"), None);
+    /// ```
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Protocol::Text),
+            "json" => Some(Protocol::Json),
+            _ => None,
+        }
+    }
+}
+
+/// A single line of the JSON protocol. The `id` is optional and is copied
+/// into the reply so that clients can match replies to their requests.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::protocol::JsonRequest;
+/// let request: JsonRequest =
+///     serde_json::from_str(r#"{"id":1,"op":"set","cell":"A1","expr":"1 + 2"}"#).unwrap();
+/// assert_eq!(request.id, Some(1));
+/// ```
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct JsonRequest {
+    #[serde(default)]
+    pub id: Option<u64>,
+
+    #[serde(flatten)]
+    pub command: JsonCommand,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JsonCommand {
+    Get { cell: String },
+    Set { cell: String, expr: String },
+    Recalc { range: Option<String> },
+    Calcmode { mode: CalcMode },
+    Lock { range: String },
+    Unlock { range: String },
+    Auth { user: String, token: String },
+    Protocol { mode: Protocol },
+}
+
+/// The reply to a command, before it is written in either protocol. This is
+/// `rsheet_lib`'s `Reply`, but holding a `Value` so that the type of the value
+/// isn't lost before it reaches a protocol that can express it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandReply {
+    Value(String, Value),
+    /// A value which is out of date, as the spreadsheet is in manual
+    /// calculation mode. `Reply` can't express this, so it is only shown by
+    /// the terminal and the JSON protocol.
+    Stale(String, Value),
+    Error(String),
+}
+
+impl From<Reply> for CommandReply {
+    fn from(reply: Reply) -> Self {
+        match reply {
+            Reply::Value(cell, value) => CommandReply::Value(cell, value.into()),
+            Reply::Error(e) => CommandReply::Error(e),
+        }
+    }
+}
+
+impl From<CommandReply> for Reply {
+    fn from(reply: CommandReply) -> Self {
+        match reply {
+            CommandReply::Value(cell, value) | CommandReply::Stale(cell, value) => {
+                Reply::Value(cell, value.into())
+            }
+            CommandReply::Error(e) => Reply::Error(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Error,
+}
+
+/// A single reply line of the JSON protocol.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::protocol::JsonReply;
+/// # use rsheet::value::Value;
+/// let reply = JsonReply::value(Some(1), "A1".to_string(), Value::Int(3));
+/// assert_eq!(
+///     reply.to_line(),
+///     r#"{"id":1,"status":"ok","cell":"A1","value":{"type":"Int","value":3}}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JsonReply {
+    pub id: Option<u64>,
+    pub status: Status,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cell: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+
+    /// Set for a value which is out of date.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl JsonReply {
+    /// An acknowledgement for a request that has no value to return.
+    pub fn ok(id: Option<u64>) -> Self {
+        Self {
+            id,
+            status: Status::Ok,
+            cell: None,
+            value: None,
+            stale: None,
+            protocol: None,
+            message: None,
+        }
+    }
+
+    pub fn value(id: Option<u64>, cell: String, value: Value) -> Self {
+        Self {
+            cell: Some(cell),
+            value: Some(value),
+            ..Self::ok(id)
+        }
+    }
+
+    pub fn error(id: Option<u64>, message: String) -> Self {
+        Self {
+            status: Status::Error,
+            message: Some(message),
+            ..Self::ok(id)
+        }
+    }
+
+    /// Converts the reply to a command into a JSON reply.
+    pub fn from_reply(id: Option<u64>, reply: CommandReply) -> Self {
+        match reply {
+            CommandReply::Value(cell, value) => Self::value(id, cell, value),
+            CommandReply::Stale(cell, value) => Self {
+                stale: Some(true),
+                ..Self::value(id, cell, value)
+            },
+            CommandReply::Error(e) => Self::error(id, e),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("JsonReply only contains serializable types.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::{CellError, ErrorKind};
+
+    #[test]
+    fn test_parse_json_request() {
+        let request: JsonRequest =
+            serde_json::from_str(r#"{"id":4,"op":"set","cell":"A1","expr":"\"a b\""}"#).unwrap();
+        assert_eq!(
+            request,
+            JsonRequest {
+                id: Some(4),
+                command: JsonCommand::Set {
+                    cell: "A1".to_string(),
+                    expr: "\"a b\"".to_string()
+                }
+            }
+        );
+
+        let request: JsonRequest = serde_json::from_str(r#"{"op":"get","cell":"B2"}"#).unwrap();
+        assert_eq!(request.id, None);
+        assert_eq!(
+            request.command,
+            JsonCommand::Get {
+                cell: "B2".to_string()
+            }
+        );
+
+        assert!(serde_json::from_str::<JsonRequest>(r#"{"op":"delete"}"#).is_err());
+    }
+
+    #[test]
+    fn test_json_reply_is_typed() {
+        let string = JsonReply::value(None, "A1".to_string(), Value::String("x".to_string()));
+        let error = CellError::new(ErrorKind::DivideByZero, "x");
+        let error = JsonReply::value(None, "A1".to_string(), Value::Error(error));
+        let none = JsonReply::value(Some(2), "A1".to_string(), Value::None);
+
+        assert_eq!(
+            string.to_line(),
+            r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"String","value":"x"}}"#
+        );
+        assert_eq!(
+            error.to_line(),
+            r##"{"id":null,"status":"ok","cell":"A1","value":{"type":"Error","value":{"code":"#DIV/0!","message":"x"}}}"##
+        );
+        assert_eq!(
+            none.to_line(),
+            r#"{"id":2,"status":"ok","cell":"A1","value":{"type":"None"}}"#
+        );
+        assert_eq!(
+            JsonReply::value(None, "A1".to_string(), Value::Float(0.5)).to_line(),
+            r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"Float","value":0.5}}"#
+        );
+        assert_eq!(
+            JsonReply::from_reply(None, CommandReply::Stale("A1".to_string(), Value::Int(1)))
+                .to_line(),
+            r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"Int","value":1},"stale":true}"#
+        );
+        assert_eq!(
+            JsonReply::error(Some(3), "Invalid cell".to_string()).to_line(),
+            r#"{"id":3,"status":"error","message":"Invalid cell"}"#
+        );
+    }
+}
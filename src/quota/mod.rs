@@ -0,0 +1,345 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use rsheet_lib::replies::Reply;
+
+use crate::utils::is_valid_cell;
+
+/// A token bucket rate. A client can send `burst` commands at once, after
+/// which it gets `per_second` more each second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+/// Limits on how much a single client can do, so that one client flooding
+/// the server can't starve everyone else. Every limit is off by default.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::quota::{QuotaLimits, Rate};
+/// let limits = QuotaLimits {
+///     connection_rate: Some(Rate { per_second: 10.0, burst: 20.0 }),
+///     max_cells: Some(1000),
+///     ..QuotaLimits::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuotaLimits {
+    /// How fast each connection can send commands. Each HTTP request is a
+    /// connection of its own, so this applies to each client address of the
+    /// REST API instead.
+    pub connection_rate: Option<Rate>,
+
+    /// How fast each authenticated user can send commands, across all of
+    /// their connections.
+    pub user_rate: Option<Rate>,
+
+    /// How many different cells each connection, or client address of the
+    /// REST API, can set.
+    pub max_cells: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            updated: now,
+        }
+    }
+
+    /// Takes a token if there is one, after adding the tokens earned since
+    /// the last time.
+    fn take(&mut self, now: Instant) -> bool {
+        let earned =
+            now.saturating_duration_since(self.updated).as_secs_f64() * self.rate.per_second;
+        self.tokens = (self.tokens + earned).min(self.rate.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The quotas shared by every connection, which keep track of each user's
+/// rate across all of their connections, and of each client of the REST API.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    limits: QuotaLimits,
+    users: Mutex<HashMap<String, TokenBucket>>,
+    clients: Mutex<HashMap<IpAddr, ConnectionState>>,
+}
+
+impl Quotas {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            users: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts keeping track of a new connection.
+    pub fn connection(self: &Arc<Self>) -> ConnectionQuota {
+        ConnectionQuota {
+            quotas: self.clone(),
+            state: Mutex::new(ConnectionState::default()),
+        }
+    }
+
+    /// Checks that a request to the REST API is within the quotas of the
+    /// client's address, which stand in for the quotas of a connection as
+    /// each request is a connection of its own. A request from an unknown
+    /// address only takes one of its user's tokens.
+    pub fn check_client(
+        &self,
+        client: Option<IpAddr>,
+        user: Option<&str>,
+        args: &[&str],
+    ) -> Result<(), Reply> {
+        let now = Instant::now();
+        if let Some(client) = client {
+            let mut clients = self.clients.lock().unwrap();
+            clients
+                .entry(client)
+                .or_default()
+                .check(self.limits, args, now)?;
+        }
+        self.take_user_token(user, now)
+    }
+
+    /// Counts a cell set by a client of the REST API towards the cells it
+    /// can set.
+    pub fn count_client_cell(&self, client: Option<IpAddr>, cell: &str) {
+        if let Some(client) = client {
+            let mut clients = self.clients.lock().unwrap();
+            clients
+                .entry(client)
+                .or_default()
+                .count_cell(self.limits, cell);
+        }
+    }
+
+    fn take_user_token(&self, user: Option<&str>, now: Instant) -> Result<(), Reply> {
+        let (rate, user) = match (self.limits.user_rate, user) {
+            (Some(rate), Some(user)) => (rate, user),
+            _ => return Ok(()),
+        };
+
+        let mut users = self.users.lock().unwrap();
+        let bucket = users
+            .entry(user.to_string())
+            .or_insert_with(|| TokenBucket::new(rate, now));
+        match bucket.take(now) {
+            true => Ok(()),
+            false => Err(Reply::Error(format!("Rate limit exceeded for {}", user))),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnectionState {
+    bucket: Option<TokenBucket>,
+    cells: HashSet<String>,
+}
+
+impl ConnectionState {
+    /// Takes one of the connection's tokens, and checks that a `set` is of a
+    /// cell it has already set or that it has room for another cell.
+    fn check(&mut self, limits: QuotaLimits, args: &[&str], now: Instant) -> Result<(), Reply> {
+        if let Some(rate) = limits.connection_rate {
+            let bucket = self
+                .bucket
+                .get_or_insert_with(|| TokenBucket::new(rate, now));
+            if !bucket.take(now) {
+                return Err(Reply::Error("Rate limit exceeded".to_string()));
+            }
+        }
+
+        if let (Some(max_cells), ["set", cell, ..]) = (limits.max_cells, args) {
+            if is_valid_cell(cell) && !self.cells.contains(*cell) && self.cells.len() >= max_cells {
+                return Err(Reply::Error(format!(
+                    "A connection can't set more than {} cells",
+                    max_cells
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn count_cell(&mut self, limits: QuotaLimits, cell: &str) {
+        if limits.max_cells.is_some() {
+            self.cells.insert(cell.to_string());
+        }
+    }
+}
+
+/// The quotas of a single connection. Requests of a connection can run
+/// concurrently, so its state is behind a lock.
+#[derive(Debug)]
+pub struct ConnectionQuota {
+    quotas: Arc<Quotas>,
+    state: Mutex<ConnectionState>,
+}
+
+impl ConnectionQuota {
+    /// Checks that a command is within the connection's quotas, using up one
+    /// of its tokens and one of its user's. A `set` of a cell the connection
+    /// hasn't set before is refused once it has set as many cells as it can,
+    /// but only counts towards them once `count_cell` is called.
+    pub fn check(&self, user: Option<&str>, args: &[&str]) -> Result<(), Reply> {
+        self.check_at(user, args, Instant::now())
+    }
+
+    fn check_at(&self, user: Option<&str>, args: &[&str], now: Instant) -> Result<(), Reply> {
+        let limits = self.quotas.limits;
+        self.state.lock().unwrap().check(limits, args, now)?;
+        self.quotas.take_user_token(user, now)
+    }
+
+    /// Counts a cell the connection has set towards the cells it can set.
+    /// This is left until the `set` succeeds, so that a command which is
+    /// refused doesn't use up the quota.
+    pub fn count_cell(&self, cell: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .count_cell(self.quotas.limits, cell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const RATE: Rate = Rate {
+        per_second: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn test_connection_rate() {
+        let quotas = Arc::new(Quotas::new(QuotaLimits {
+            connection_rate: Some(RATE),
+            ..QuotaLimits::default()
+        }));
+        let quota = quotas.connection();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(quota.check_at(None, &["get", "A1"], start).is_ok());
+        }
+        assert_eq!(
+            quota.check_at(None, &["get", "A1"], start),
+            Err(Reply::Error("Rate limit exceeded".to_string()))
+        );
+
+        // Half a second earns one more token.
+        let later = start + Duration::from_millis(500);
+        assert!(quota.check_at(None, &["get", "A1"], later).is_ok());
+        assert!(quota.check_at(None, &["get", "A1"], later).is_err());
+
+        // Other connections have their own bucket.
+        assert!(quotas
+            .connection()
+            .check_at(None, &["get", "A1"], later)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_user_rate_is_shared_between_connections() {
+        let quotas = Arc::new(Quotas::new(QuotaLimits {
+            user_rate: Some(RATE),
+            ..QuotaLimits::default()
+        }));
+        let (first, second) = (quotas.connection(), quotas.connection());
+        let now = Instant::now();
+
+        assert!(first.check_at(Some("alice"), &["get", "A1"], now).is_ok());
+        assert!(second.check_at(Some("alice"), &["get", "A1"], now).is_ok());
+        assert!(first.check_at(Some("alice"), &["get", "A1"], now).is_ok());
+        assert!(second.check_at(Some("alice"), &["get", "A1"], now).is_err());
+
+        assert!(second.check_at(Some("bob"), &["get", "A1"], now).is_ok());
+        assert!(second.check_at(None, &["get", "A1"], now).is_ok());
+    }
+
+    #[test]
+    fn test_max_cells() {
+        let quotas = Arc::new(Quotas::new(QuotaLimits {
+            max_cells: Some(2),
+            ..QuotaLimits::default()
+        }));
+        let quota = quotas.connection();
+
+        for cell in ["A1", "A2"] {
+            assert!(quota.check(None, &["set", cell, "1"]).is_ok());
+            quota.count_cell(cell);
+        }
+        assert!(quota.check(None, &["set", "A1", "2"]).is_ok());
+        assert!(quota.check(None, &["get", "A3"]).is_ok());
+        assert!(quota.check(None, &["set", "A3", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_max_cells_only_counts_cells_that_were_set() {
+        let quotas = Arc::new(Quotas::new(QuotaLimits {
+            max_cells: Some(1),
+            ..QuotaLimits::default()
+        }));
+        let quota = quotas.connection();
+
+        // A `set` which was checked but failed doesn't use up the quota.
+        assert!(quota.check(None, &["set", "A1", "1"]).is_ok());
+        assert!(quota.check(None, &["set", "A2", "1"]).is_ok());
+        quota.count_cell("A2");
+        assert!(quota.check(None, &["set", "A1", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_clients_are_limited_by_address() {
+        let quotas = Quotas::new(QuotaLimits {
+            connection_rate: Some(RATE),
+            max_cells: Some(1),
+            ..QuotaLimits::default()
+        });
+        let (first, second) = (Some([10, 0, 0, 1].into()), Some([10, 0, 0, 2].into()));
+
+        assert!(quotas
+            .check_client(first, None, &["set", "A1", "1"])
+            .is_ok());
+        quotas.count_client_cell(first, "A1");
+        assert!(quotas
+            .check_client(first, None, &["set", "A2", "1"])
+            .is_err());
+        assert!(quotas
+            .check_client(second, None, &["set", "A2", "1"])
+            .is_ok());
+
+        // The refused `set` still took one of the address's tokens.
+        assert!(quotas.check_client(first, None, &["get", "A1"]).is_ok());
+        assert_eq!(
+            quotas.check_client(first, None, &["get", "A1"]),
+            Err(Reply::Error("Rate limit exceeded".to_string()))
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rsheet_lib::replies::Reply;
+
+use crate::commands::recalc::{invalidate_volatile, recalc_volatile};
+use crate::shutdown::{Shutdown, POLL_INTERVAL};
+use crate::spreadsheet::{CalcMode, Spreadsheet};
+use crate::utils::current_timestamp;
+
+/// Recalculates the volatile cells of the spreadsheet, and their dependents,
+/// every `interval` until a shutdown is requested. In lazy calculation mode
+/// they are marked as dirty instead, to be evaluated when they are read.
+/// Nothing is recalculated while the spreadsheet is in manual calculation
+/// mode.
+///
+/// # Example
+///
+/// ```
+/// # use std::thread;
+/// # use std::time::Duration;
+/// # use rsheet::scheduler;
+/// # use rsheet::shutdown::Shutdown;
+/// # use rsheet::spreadsheet::new_shared_spreadsheet;
+/// let spreadsheet = new_shared_spreadsheet();
+/// let shutdown = Shutdown::new();
+/// let handle = shutdown.clone();
+/// let scheduler =
+///     thread::spawn(move || scheduler::run(&spreadsheet, Duration::from_secs(1), &handle));
+///
+/// shutdown.request();
+/// scheduler.join().unwrap();
+/// ```
+pub fn run(spreadsheet: &Arc<Spreadsheet>, interval: Duration, shutdown: &Shutdown) {
+    let mut next = Instant::now() + interval;
+    loop {
+        // Sleep in short steps so that a shutdown isn't held up by a long
+        // interval.
+        while Instant::now() < next {
+            if shutdown.is_requested() {
+                return;
+            }
+            let remaining = next.saturating_duration_since(Instant::now());
+            thread::sleep(remaining.min(POLL_INTERVAL));
+        }
+        if shutdown.is_requested() {
+            return;
+        }
+
+        match spreadsheet.calc_mode() {
+            CalcMode::Auto => {
+                if let Err(Reply::Error(e)) = recalc_volatile(spreadsheet, current_timestamp()) {
+                    eprintln!("Error recalculating volatile cells: {}", e);
+                }
+            }
+            CalcMode::Lazy => invalidate_volatile(spreadsheet),
+            // Volatile cells are left for `recalc`.
+            CalcMode::Manual => {}
+        }
+
+        // A recalculation that takes longer than the interval delays the next
+        // one, rather than starting them back to back.
+        next = (next + interval).max(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::set::set;
+    use crate::formula::EngineKind;
+
+    #[test]
+    fn test_recalculates_until_shutdown() {
+        let spreadsheet = Arc::new(Spreadsheet::with_engine(EngineKind::Native));
+        set(&spreadsheet, vec!["set", "A1", "RAND()"], 0).unwrap();
+        let mut changes = spreadsheet.watch();
+
+        let shutdown = Shutdown::new();
+        let handle = {
+            let spreadsheet = spreadsheet.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || run(&spreadsheet, Duration::from_millis(10), &shutdown))
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let change = loop {
+            match changes.try_recv() {
+                Ok(change) => break change,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("No change was recalculated: {}", e),
+            }
+        };
+        assert_eq!(change.cell, "A1");
+
+        shutdown.request();
+        handle.join().unwrap();
+    }
+}
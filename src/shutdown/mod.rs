@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often blocking loops (accepting connections, reading messages) wake up
+/// to check if a shutdown has been requested.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A handle used to request that the server shuts down. Every clone shares the
+/// same state, so a signal handler can hold one while the server checks
+/// another.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::shutdown::Shutdown;
+/// let shutdown = Shutdown::new();
+/// let handle = shutdown.clone();
+///
+/// handle.request();
+/// assert!(shutdown.is_requested());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a shutdown. Returns `true` if one had already been requested.
+    pub fn request(&self) -> bool {
+        self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
@@ -1,26 +1,143 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use rsheet_lib::cells::column_number_to_name;
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, Receiver, Sender};
 
-use rsheet_lib::command_runner::CellValue;
+use crate::acl::{Access, Acl};
+use crate::audit::{self, AuditLog, CellState, Change};
+use crate::formula::{EngineKind, FormulaEngine};
+use crate::limits::Limits;
+use crate::utils::{cell_in_range, cell_position, range_bounds};
+use crate::value::Value;
+
+/// The column and row of the top left and bottom right cells of a range.
+type Bounds = ((u32, u32), (u32, u32));
 
 #[derive(Debug)]
 struct Cell {
-    value: CellValue,
+    value: Value,
     expression: Option<String>,
     timestamp: u64,
+
+    /// The cell whose array formula spilled this value, if any.
+    spilled_from: Option<String>,
 }
 
 impl Default for Cell {
     fn default() -> Self {
         Self {
-            value: CellValue::None,
+            value: Value::None,
             expression: None,
             timestamp: 0,
+            spilled_from: None,
         }
     }
 }
 
+/// A notification that a cell's value has been modified, either by a `set`
+/// or by the recalculation of its dependencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellChange {
+    pub cell: String,
+    pub value: Value,
+}
+
+/// How many changes can be waiting for a watcher before it is considered to
+/// have fallen behind, and is stopped.
+pub const WATCH_CAPACITY: usize = 1024;
+
+/// The ranges of cells a watcher wants to hear about. Every clone shares the
+/// same ranges, so the watcher can change them while it is watching.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions(Arc<RwLock<Vec<String>>>);
+
+impl Subscriptions {
+    pub fn subscribe(&self, range: &str) {
+        self.0.write().unwrap().push(range.to_string());
+    }
+
+    pub fn unsubscribe(&self, range: &str) {
+        self.0
+            .write()
+            .unwrap()
+            .retain(|subscribed| subscribed != range);
+    }
+
+    /// Gets every subscribed range, in the order they were subscribed to.
+    pub fn ranges(&self) -> Vec<String> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Checks if a cell is in any of the subscribed ranges.
+    pub fn covers(&self, cell: &str) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .any(|range| cell_in_range(cell, range))
+    }
+}
+
+/// A receiver returned by `watch`, and the cells it wants to hear about.
+/// `None` means every cell.
+#[derive(Debug)]
+struct Watcher {
+    sender: Sender<CellChange>,
+    subscriptions: Option<Subscriptions>,
+}
+
+/// When the spreadsheet recalculates the cells that depend on a changed cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalcMode {
+    /// Dependents are recalculated as soon as a cell changes.
+    #[default]
+    Auto,
+
+    /// Dependents are only marked as dirty, and are recalculated by `recalc`.
+    Manual,
+
+    /// Dependents are only marked as dirty, and are evaluated when they are
+    /// read. The result is kept until a cell they depend on changes again.
+    Lazy,
+}
+
+impl CalcMode {
+    /// Parses the mode name used by the `calcmode` command.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::CalcMode;
+    /// assert_eq!(CalcMode::from_name("manual"), Some(CalcMode::Manual));
+    /// assert_eq!(CalcMode::from_name("never"), None);
+    /// ```
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(CalcMode::Auto),
+            "manual" => Some(CalcMode::Manual),
+            "lazy" => Some(CalcMode::Lazy),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CalcMode::Auto => "auto",
+            CalcMode::Manual => "manual",
+            CalcMode::Lazy => "lazy",
+        }
+    }
+
+    /// Checks if recalculating the dependents of a changed cell is put off
+    /// until later.
+    pub fn defers(self) -> bool {
+        self != CalcMode::Auto
+    }
+}
+
 #[derive(Debug)]
 pub struct Spreadsheet {
     /// Cells is the main data structure for the spreadsheet. It uses a
@@ -40,19 +157,137 @@ pub struct Spreadsheet {
     /// A1 is the parent of B1 and C1. If the parent changes, the children
     /// will also change.
     pub dependencies: DashMap<String, Vec<String>>,
+
+    /// spills: a map where the key cell holds an array formula, and the value
+    /// is every cell its result covers, including the key cell itself. A
+    /// result that was blocked by another cell is still recorded, so that
+    /// the formula can spill once the way is clear.
+    ///
+    /// Example: spills[B1] = [B1, B2, B3] means that the formula in B1
+    /// produced three values, which were stored in B1, B2 and B3.
+    spills: DashMap<String, Vec<String>>,
+
+    /// volatile: the cells whose formula calls a function such as `NOW()` or
+    /// `RAND()`, which are recalculated on a schedule.
+    volatile: DashSet<String>,
+
+    /// calc_mode: whether changes are recalculated straight away.
+    calc_mode: RwLock<CalcMode>,
+
+    /// dirty: the cells whose value is out of date because they, or a cell
+    /// they depend on, changed while in manual calculation mode.
+    dirty: DashSet<String>,
+
+    /// locked: the ranges of cells which can't be set or cleared until they
+    /// are unlocked, such as reference data that shouldn't change by
+    /// accident. The ranges never overlap, and are kept as their corners so
+    /// that locking a huge range takes no more memory than locking a cell.
+    ///
+    /// Like the cells, locks are only kept in memory and are lost when the
+    /// server stops, as there is nowhere the spreadsheet is saved to yet.
+    locked: RwLock<Vec<Bounds>>,
+
+    /// authors: the user who last set each cell, if access is restricted. An
+    /// array formula only spills into cells its author can write to.
+    authors: DashMap<String, Option<String>>,
+
+    /// watchers: every receiver returned by `watch` has a sender here. Senders
+    /// whose receiver has been dropped, or has fallen behind, are removed on
+    /// the next change.
+    watchers: Mutex<Vec<Watcher>>,
+
+    /// engine: evaluates the expressions stored in the cells.
+    engine: Box<dyn FormulaEngine>,
+
+    /// limits: bounds on evaluating formulas and recalculating dependencies.
+    limits: Limits,
+
+    /// acl: the rules for who can read and write each range, if access is
+    /// restricted at all.
+    acl: Option<Acl>,
+
+    /// audit: where every change made by a client is recorded, if anywhere.
+    audit: Option<Arc<AuditLog>>,
 }
 
 impl Spreadsheet {
     pub fn new() -> Self {
+        Self::with_engine(EngineKind::default())
+    }
+
+    /// Creates a spreadsheet which evaluates expressions with the given
+    /// engine.
+    pub fn with_engine(engine: EngineKind) -> Self {
+        Self::with_limits(engine, Limits::default())
+    }
+
+    /// Creates a spreadsheet which evaluates expressions with the given
+    /// engine, within the given limits.
+    pub fn with_limits(engine: EngineKind, limits: Limits) -> Self {
         Self {
             cells: DashMap::new(),
             dependencies: DashMap::new(),
+            spills: DashMap::new(),
+            volatile: DashSet::new(),
+            calc_mode: RwLock::new(CalcMode::default()),
+            dirty: DashSet::new(),
+            locked: RwLock::new(Vec::new()),
+            authors: DashMap::new(),
+            watchers: Mutex::new(Vec::new()),
+            engine: engine.engine(&limits),
+            limits,
+            acl: None,
+            audit: None,
         }
     }
 
+    pub fn engine(&self) -> &dyn FormulaEngine {
+        self.engine.as_ref()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn acl(&self) -> Option<&Acl> {
+        self.acl.as_ref()
+    }
+
+    /// Restricts who can read and write each range. `None` lets everyone
+    /// access every cell.
+    pub fn set_acl(&mut self, acl: Option<Acl>) {
+        self.acl = acl;
+    }
+
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit.as_deref()
+    }
+
+    /// Records every change made by a client in `audit`.
+    pub fn set_audit_log(&mut self, audit: Option<Arc<AuditLog>>) {
+        self.audit = audit;
+    }
+
     /// Set the cell's value, expression and timestamp. If the incoming
     /// timestamp is not more recent, then we don't update the cell.
-    pub fn set_cell(&self, key: &str, value: CellValue, expr: Option<String>, inc_timestamp: u64) {
+    pub fn set_cell(&self, key: &str, value: Value, expr: Option<String>, inc_timestamp: u64) {
+        self.store(key, value, expr, None, inc_timestamp);
+    }
+
+    /// Sets a cell to a value spilled from the array formula in `anchor`.
+    /// The cell is owned by the anchor until it is set to something else.
+    pub fn set_spilled_cell(&self, key: &str, value: Value, anchor: &str, inc_timestamp: u64) {
+        self.store(key, value, None, Some(anchor.to_string()), inc_timestamp);
+    }
+
+    fn store(
+        &self,
+        key: &str,
+        value: Value,
+        expr: Option<String>,
+        spilled_from: Option<String>,
+        inc_timestamp: u64,
+    ) {
         // Get the cell entry, otherwise default to the default Cell struct.
         let mut cell_entry = self.cells.entry(key.to_string()).or_default();
 
@@ -60,25 +295,124 @@ impl Spreadsheet {
         // then we update the cell. Otherwise, we do not update.
         let curr_timestamp = cell_entry.timestamp;
         if inc_timestamp >= curr_timestamp {
-            cell_entry.value = value;
+            // A command being audited has its writes recorded while the cell
+            // is still held, so that no other write can come in between.
+            let audit = self.audit.as_ref().filter(|_| audit::is_recording());
+            let old = audit.map(|_| CellState {
+                expr: cell_entry.expression.clone(),
+                value: cell_entry.value.clone(),
+            });
+            let old_spilled_from = cell_entry.spilled_from.take();
+
+            cell_entry.value = value.clone();
             cell_entry.expression = expr;
             cell_entry.timestamp = inc_timestamp;
+            cell_entry.spilled_from = spilled_from;
+
+            if let (Some(log), Some(old)) = (audit, old) {
+                audit::record(Change {
+                    sequence: log.next_sequence(),
+                    cell: key.to_string(),
+                    spilled_from: cell_entry.spilled_from.clone().or(old_spilled_from),
+                    old,
+                    new: CellState {
+                        expr: cell_entry.expression.clone(),
+                        value: value.clone(),
+                    },
+                });
+            }
+
+            // Release the entry before notifying so that watchers are free to
+            // read the spreadsheet.
+            drop(cell_entry);
+            self.notify(CellChange {
+                cell: key.to_string(),
+                value,
+            });
         }
     }
 
+    /// Returns a receiver which is sent every change to a cell's value from
+    /// now on. Dropping the receiver stops the notifications, as does falling
+    /// more than `WATCH_CAPACITY` changes behind, after which the receiver is
+    /// closed once the changes it has been sent are read.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// # use rsheet::value::Value;
+    /// let spreadsheet = Spreadsheet::new();
+    /// let mut changes = spreadsheet.watch();
+    ///
+    /// spreadsheet.set_cell("A1", Value::Int(10), None, 0);
+    /// assert_eq!(changes.try_recv().unwrap().cell, "A1");
+    /// ```
+    pub fn watch(&self) -> Receiver<CellChange> {
+        self.add_watcher(None)
+    }
+
+    /// Like `watch`, but only sends changes to the cells covered by
+    /// `subscriptions` at the time of the change.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::{Spreadsheet, Subscriptions};
+    /// # use rsheet::value::Value;
+    /// let spreadsheet = Spreadsheet::new();
+    /// let subscriptions = Subscriptions::default();
+    /// let mut changes = spreadsheet.watch_ranges(subscriptions.clone());
+    ///
+    /// subscriptions.subscribe("B1_B3");
+    /// spreadsheet.set_cell("A1", Value::Int(10), None, 0);
+    /// spreadsheet.set_cell("B2", Value::Int(20), None, 0);
+    /// assert_eq!(changes.try_recv().unwrap().cell, "B2");
+    /// ```
+    pub fn watch_ranges(&self, subscriptions: Subscriptions) -> Receiver<CellChange> {
+        self.add_watcher(Some(subscriptions))
+    }
+
+    fn add_watcher(&self, subscriptions: Option<Subscriptions>) -> Receiver<CellChange> {
+        let (sender, receiver) = mpsc::channel(WATCH_CAPACITY);
+        self.watchers.lock().unwrap().push(Watcher {
+            sender,
+            subscriptions,
+        });
+        receiver
+    }
+
+    fn notify(&self, change: CellChange) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| {
+            let wanted = match &watcher.subscriptions {
+                Some(subscriptions) => subscriptions.covers(&change.cell),
+                None => true,
+            };
+            match wanted {
+                // A watcher that is full has fallen behind, so it is dropped
+                // rather than left to queue up changes without end.
+                true => watcher.sender.try_send(change.clone()).is_ok(),
+                false => !watcher.sender.is_closed(),
+            }
+        });
+    }
+
     /// Gets the cell's value from the `cells` map, as the value is a tuple.
     ///
     /// # Example
     ///
     /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// # use rsheet::value::Value;
     /// let spreadsheet = Spreadsheet::new();
-    /// spreadsheet.set_cell("A1", CellValue::Int(10.0), None);
-    /// assert_eq!(spreadsheet.get_cell_val("A1"), CellValue::Int(10.0));
+    /// spreadsheet.set_cell("A1", Value::Int(10), None, 0);
+    /// assert_eq!(spreadsheet.get_cell_val("A1"), Value::Int(10));
     /// ```
-    pub fn get_cell_val(&self, key: &str) -> CellValue {
+    pub fn get_cell_val(&self, key: &str) -> Value {
         match self.cells.get(key) {
             Some(cell) => cell.value.clone(),
-            None => CellValue::None,
+            None => Value::None,
         }
     }
 
@@ -87,8 +421,10 @@ impl Spreadsheet {
     /// # Example
     ///
     /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// # use rsheet::value::Value;
     /// let spreadsheet = Spreadsheet::new();
-    /// spreadsheet.set_cell("A1", CellValue::Int(10.0), Some("A2 + 10".to_string()));
+    /// spreadsheet.set_cell("A1", Value::Int(10), Some("A2 + 10".to_string()), 0);
     /// assert_eq!(spreadsheet.get_cell_expr("A1"), Some("A2 + 10".to_string()));
     /// ```
     pub fn get_cell_expr(&self, key: &str) -> Option<String> {
@@ -98,11 +434,259 @@ impl Spreadsheet {
         }
     }
 
+    /// Gets the cell whose array formula spilled into this cell.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// # use rsheet::value::Value;
+    /// let spreadsheet = Spreadsheet::new();
+    /// spreadsheet.set_spilled_cell("A2", Value::Int(10), "A1", 0);
+    /// assert_eq!(spreadsheet.get_spilled_from("A2"), Some("A1".to_string()));
+    /// ```
+    pub fn get_spilled_from(&self, key: &str) -> Option<String> {
+        self.cells
+            .get(key)
+            .and_then(|cell| cell.spilled_from.clone())
+    }
+
+    /// Records the user setting a cell, or `None` for a client that hasn't
+    /// authenticated. Nothing is recorded if access isn't restricted.
+    pub fn set_author(&self, key: &str, user: Option<&str>) {
+        if self.acl.is_some() {
+            self.authors
+                .insert(key.to_string(), user.map(str::to_string));
+        }
+    }
+
+    /// Gets the user who last set a cell, if there was one.
+    pub fn get_author(&self, key: &str) -> Option<String> {
+        self.authors
+            .get(key)
+            .and_then(|author| author.value().clone())
+    }
+
+    /// Checks if a cell would block the result of the array formula in
+    /// `anchor`, set by `user`, from spilling into it. Only cells holding
+    /// something, which are locked, or which the user isn't allowed to write
+    /// to, are in the way, unless they hold a value spilled from the anchor.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::acl::Acl;
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// let mut spreadsheet = Spreadsheet::new();
+    /// spreadsheet.set_acl(Some(Acl::parse("allow alice write A1_A2").unwrap()));
+    ///
+    /// assert!(!spreadsheet.blocks_spill("A2", "A1", Some("alice")));
+    /// assert!(spreadsheet.blocks_spill("A3", "A1", Some("alice")));
+    /// ```
+    pub fn blocks_spill(&self, key: &str, anchor: &str, user: Option<&str>) -> bool {
+        let denied = self
+            .acl
+            .as_ref()
+            .is_some_and(|acl| !acl.allows(user, key, Access::Write));
+        let off_limits = self.is_locked(key) || denied;
+        match self.cells.get(key) {
+            Some(cell) => {
+                (cell.value != Value::None || cell.expression.is_some() || off_limits)
+                    && cell.spilled_from.as_deref() != Some(anchor)
+            }
+            None => off_limits,
+        }
+    }
+
+    /// Records the cells covered by the result of the array formula in
+    /// `anchor`, returning the cells it covered before.
+    pub fn replace_spill(&self, anchor: &str, cells: Vec<String>) -> Vec<String> {
+        let old = match cells.len() {
+            // A single value doesn't spill anywhere.
+            0 | 1 => self.spills.remove(anchor).map(|(_, cells)| cells),
+            _ => self.spills.insert(anchor.to_string(), cells),
+        };
+        old.unwrap_or_default()
+    }
+
+    /// Finds the array formulas whose result covers, or would cover if it
+    /// wasn't blocked, the given cell.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// let spreadsheet = Spreadsheet::new();
+    /// spreadsheet.replace_spill("A1", vec!["A1".to_string(), "A2".to_string()]);
+    /// assert_eq!(spreadsheet.get_spills_over("A2"), vec!["A1".to_string()]);
+    /// ```
+    pub fn get_spills_over(&self, key: &str) -> Vec<String> {
+        self.spills
+            .iter()
+            .filter(|spill| spill.key() != key && spill.value().iter().any(|cell| cell == key))
+            .map(|spill| spill.key().clone())
+            .collect()
+    }
+
+    /// Marks whether the formula in a cell is volatile.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// let spreadsheet = Spreadsheet::new();
+    /// spreadsheet.set_volatile("A1", true);
+    /// assert_eq!(spreadsheet.get_volatile_cells(), vec!["A1".to_string()]);
+    /// ```
+    pub fn set_volatile(&self, key: &str, volatile: bool) {
+        match volatile {
+            true => self.volatile.insert(key.to_string()),
+            false => self.volatile.remove(key).is_some(),
+        };
+    }
+
+    /// Gets every cell holding a volatile formula.
+    pub fn get_volatile_cells(&self) -> Vec<String> {
+        self.volatile
+            .iter()
+            .map(|cell| cell.key().clone())
+            .collect()
+    }
+
+    /// Gets every cell holding a formula, i.e. every cell with an expression.
+    pub fn get_formula_cells(&self) -> Vec<String> {
+        self.cells
+            .iter()
+            .filter(|cell| cell.expression.is_some())
+            .map(|cell| cell.key().clone())
+            .collect()
+    }
+
+    pub fn calc_mode(&self) -> CalcMode {
+        *self.calc_mode.read().unwrap()
+    }
+
+    pub fn set_calc_mode(&self, mode: CalcMode) {
+        *self.calc_mode.write().unwrap() = mode;
+    }
+
+    /// Marks a cell's value as out of date.
+    pub fn mark_dirty(&self, key: &str) {
+        self.dirty.insert(key.to_string());
+    }
+
+    /// Marks a cell's value as up to date again.
+    pub fn mark_clean(&self, key: &str) {
+        self.dirty.remove(key);
+    }
+
+    /// Gets every cell whose value is out of date.
+    pub fn get_dirty_cells(&self) -> Vec<String> {
+        self.dirty.iter().map(|cell| cell.key().clone()).collect()
+    }
+
+    pub fn is_dirty(&self, key: &str) -> bool {
+        self.dirty.contains(key)
+    }
+
+    /// Locks or unlocks every cell in a range, which can also be a single
+    /// cell. A locked cell can't be set or cleared. Unlocking part of a
+    /// locked range leaves the rest of it locked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// let spreadsheet = Spreadsheet::new();
+    /// spreadsheet.set_locked("A1_B2", true);
+    /// spreadsheet.set_locked("A1", false);
+    /// assert!(!spreadsheet.is_locked("A1"));
+    /// assert!(spreadsheet.is_locked("B2"));
+    /// ```
+    pub fn set_locked(&self, range: &str, locked: bool) {
+        let bounds = match range_bounds(range) {
+            Some(((col, row), (end_col, end_row))) if col <= end_col && row <= end_row => {
+                ((col, row), (end_col, end_row))
+            }
+            _ => return,
+        };
+
+        // The range is cut out of the locked ranges, so that they don't
+        // overlap, before it is added back when it's being locked.
+        let mut ranges = self.locked.write().unwrap();
+        let old = std::mem::take(&mut *ranges);
+        for other in old {
+            ranges.extend(subtract(other, bounds));
+        }
+        if locked {
+            ranges.push(bounds);
+        }
+    }
+
+    pub fn is_locked(&self, key: &str) -> bool {
+        let (col, row) = match cell_position(key) {
+            Some(position) => position,
+            None => return false,
+        };
+
+        self.locked
+            .read()
+            .unwrap()
+            .iter()
+            .any(|((start_col, start_row), (end_col, end_row))| {
+                (*start_col..=*end_col).contains(&col) && (*start_row..=*end_row).contains(&row)
+            })
+    }
+
+    /// Gets every locked range. A range of one cell is given as the cell.
+    pub fn get_locked_ranges(&self) -> Vec<String> {
+        let name = |(col, row): (u32, u32)| format!("{}{}", column_number_to_name(col), row);
+        self.locked
+            .read()
+            .unwrap()
+            .iter()
+            .map(|&(start, end)| match start == end {
+                true => name(start),
+                false => format!("{}_{}", name(start), name(end)),
+            })
+            .collect()
+    }
+
+    /// Checks if a cell's value is out of date. A value spilled from an array
+    /// formula is out of date if the formula is.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
+    /// # use rsheet::value::Value;
+    /// let spreadsheet = Spreadsheet::new();
+    /// spreadsheet.set_spilled_cell("A2", Value::Int(10), "A1", 0);
+    /// spreadsheet.mark_dirty("A1");
+    /// assert!(spreadsheet.is_stale("A2"));
+    /// ```
+    pub fn is_stale(&self, key: &str) -> bool {
+        self.dirty.contains(key)
+            || self
+                .get_spilled_from(key)
+                .is_some_and(|anchor| self.dirty.contains(&anchor))
+    }
+
+    /// Gets the cells covered by the result of the array formula in
+    /// `anchor`, including the anchor itself. A single value covers nothing.
+    pub fn get_spill(&self, anchor: &str) -> Vec<String> {
+        self.spills
+            .get(anchor)
+            .map(|cells| cells.value().clone())
+            .unwrap_or_default()
+    }
+
     /// Get the parent's dependencies.
     ///
     /// # Example
     ///
     /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
     /// let spreadsheet = Spreadsheet::new();
     /// spreadsheet.add_dependency("A1", "B1");
     /// assert_eq!(spreadsheet.get_dependencies("A1"), Some(vec!["B1".to_string()]));
@@ -119,6 +703,7 @@ impl Spreadsheet {
     /// # Example
     ///
     /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
     /// let spreadsheet = Spreadsheet::new();
     /// spreadsheet.add_dependency("A1", "B1");
     /// assert_eq!(spreadsheet.get_dependencies("A1"), Some(vec!["B1".to_string()]));
@@ -141,13 +726,14 @@ impl Spreadsheet {
     /// # Example
     ///
     /// ```
+    /// # use rsheet::spreadsheet::Spreadsheet;
     /// let spreadsheet = Spreadsheet::new();
     ///
     /// spreadsheet.add_dependency("A1", "B1");
     /// assert_eq!(spreadsheet.get_dependencies("A1"), Some(vec!["B1".to_string()]));
     ///
     /// spreadsheet.remove_dependency("A1", "B1");
-    /// assert_eq!(spreadsheet.get_dependencies("A1"), None);
+    /// assert_eq!(spreadsheet.get_dependencies("A1"), Some(vec![]));
     /// ```
     pub fn remove_dependency(&self, parent: &str, child: &str) {
         if let Some(mut parent_deps) = self.dependencies.get_mut(parent) {
@@ -162,7 +748,66 @@ impl Default for Spreadsheet {
     }
 }
 
+/// Cuts `cut` out of the range `from`, giving the rest of it as up to four
+/// ranges: the rows above and below the cut, and the columns to either side
+/// of it.
+fn subtract(from: Bounds, cut: Bounds) -> Vec<Bounds> {
+    let ((col, row), (end_col, end_row)) = from;
+    let ((cut_col, cut_row), (cut_end_col, cut_end_row)) = cut;
+    if cut_col > end_col || col > cut_end_col || cut_row > end_row || row > cut_end_row {
+        return vec![from];
+    }
+
+    let mut rest = Vec::new();
+    if row < cut_row {
+        rest.push(((col, row), (end_col, cut_row - 1)));
+    }
+    if cut_end_row < end_row {
+        rest.push(((col, cut_end_row + 1), (end_col, end_row)));
+    }
+
+    let (middle_row, middle_end_row) = (row.max(cut_row), end_row.min(cut_end_row));
+    if col < cut_col {
+        rest.push(((col, middle_row), (cut_col - 1, middle_end_row)));
+    }
+    if cut_end_col < end_col {
+        rest.push(((cut_end_col + 1, middle_row), (end_col, middle_end_row)));
+    }
+    rest
+}
+
 /// Creates an `Arc` instance of `Spreadsheet` for concurrency.
 pub fn new_shared_spreadsheet() -> Arc<Spreadsheet> {
     Arc::new(Spreadsheet::new())
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::error::TryRecvError;
+
+    use super::*;
+
+    #[test]
+    fn test_watchers_that_fall_behind_are_dropped() {
+        let spreadsheet = Spreadsheet::new();
+        let subscriptions = Subscriptions::default();
+        subscriptions.subscribe("A1");
+        let mut changes = spreadsheet.watch_ranges(subscriptions);
+
+        // Changes to other cells don't count towards the backlog.
+        for timestamp in 0..WATCH_CAPACITY as u64 * 2 {
+            spreadsheet.set_cell("B1", Value::Int(0), None, timestamp);
+        }
+        for timestamp in 0..=WATCH_CAPACITY as u64 {
+            let value = Value::Int(timestamp as i64);
+            spreadsheet.set_cell("A1", value, None, timestamp);
+        }
+        assert!(spreadsheet.watchers.lock().unwrap().is_empty());
+
+        // What was sent before it fell behind can still be read.
+        for _ in 0..WATCH_CAPACITY {
+            assert_eq!(changes.try_recv().unwrap().cell, "A1");
+        }
+        assert_eq!(changes.try_recv(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -0,0 +1,628 @@
+use std::future;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rayon::ThreadPool;
+use rsheet_lib::connect::{ConnectionError, Writer};
+use rsheet_lib::replies::Reply;
+use rustls::ServerConfig;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    audit::Origin,
+    auth::Credentials,
+    connect::{ConnectionLimit, ConnectionSlot, LineWriter, MAX_MESSAGE_LEN},
+    handle_message, notify_shutdown,
+    protocol::Protocol,
+    quota::{ConnectionQuota, Quotas},
+    reject, run_json_request,
+    shutdown::{Shutdown, POLL_INTERVAL},
+    spreadsheet::Spreadsheet,
+    utils::current_timestamp,
+    write_line, ServerOptions, Services, Session,
+};
+
+/// How many replies can be waiting to be written before the client is
+/// considered to have stopped reading them, and is disconnected.
+const REPLY_CAPACITY: usize = 1024;
+
+/// What every connection's task needs.
+struct Context {
+    spreadsheet: Arc<Spreadsheet>,
+    pool: Arc<ThreadPool>,
+    quotas: Arc<Quotas>,
+    credentials: Option<Arc<Credentials>>,
+    idle_timeout: Option<Duration>,
+    protocol: Protocol,
+    shutdown: Shutdown,
+}
+
+/// Accepts connections on `listener` until a shutdown is requested, then
+/// gives the open connections `drain_timeout` to finish.
+pub(crate) fn serve(
+    listener: StdTcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    services: Services,
+    options: &ServerOptions,
+) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(options.io_threads)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error starting the connection runtime: {}", e);
+            return;
+        }
+    };
+
+    let context = Arc::new(Context {
+        spreadsheet: services.spreadsheet,
+        pool: services.pool,
+        quotas: services.quotas,
+        credentials: options.credentials.clone(),
+        idle_timeout: options.idle_timeout,
+        protocol: options.protocol,
+        shutdown: options.shutdown.clone(),
+    });
+    runtime.block_on(accept_connections(
+        listener,
+        tls.map(TlsAcceptor::from),
+        services.connections,
+        context,
+        options.drain_timeout,
+    ));
+
+    // Connections which didn't finish within the drain timeout are dropped.
+    runtime.shutdown_background();
+}
+
+async fn accept_connections(
+    listener: StdTcpListener,
+    tls: Option<TlsAcceptor>,
+    connections: ConnectionLimit,
+    context: Arc<Context>,
+    drain_timeout: Duration,
+) {
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error listening for connections: {}", e);
+            return;
+        }
+    };
+
+    if let Ok(addr) = listener.local_addr() {
+        log::info!("Listening on {}", addr);
+    }
+
+    let mut shutdown = watch_shutdown(context.shutdown.clone());
+    let mut tasks = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(connection) => connection,
+                // Running out of file descriptors is the likely cause, which
+                // only gets better as connections close.
+                Err(_) => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|requested| *requested) => break,
+        };
+
+        // The slot is taken here rather than in the task, so that a burst of
+        // connections can't get past the limit before any of them start.
+        let slot = connections.try_acquire();
+        let tls = tls.clone();
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        tasks.spawn(async move {
+            match tls {
+                // The handshake happens in the task, so a slow client doesn't
+                // hold up accepting other connections.
+                Some(acceptor) => {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        handle_connection(stream, peer, slot, &context, shutdown).await;
+                    }
+                }
+                None => handle_connection(stream, peer, slot, &context, shutdown).await,
+            }
+        });
+
+        // Tasks of closed connections are cleaned up as we go, so they don't
+        // pile up on a long running server.
+        while tasks.try_join_next().is_some() {}
+    }
+
+    drop(listener);
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+        eprintln!("Timed out waiting for connections to finish, shutting down anyway");
+    }
+}
+
+/// Lets tasks wait for a shutdown, rather than each of them polling for one.
+pub(crate) fn watch_shutdown(shutdown: Shutdown) -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        while !sender.is_closed() {
+            interval.tick().await;
+            if shutdown.is_requested() {
+                let _ = sender.send(true);
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Handles a connection for as long as it is open. Each message is run on the
+/// compute pool, and the task waits for it without holding on to a thread.
+/// The messages of a connection are run in order, apart from JSON requests
+/// with an id, which are run alongside the messages after them.
+async fn handle_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    slot: Option<ConnectionSlot>,
+    context: &Arc<Context>,
+    mut shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let (mut writer, writing) = spawn_writer(writer, peer);
+
+    let slot = match slot {
+        Some(slot) => slot,
+        None => {
+            log::warn!("Turned away {}, as there are too many connections", peer);
+            reject(&mut writer);
+            drop(writer);
+            let _ = writing.await;
+            return;
+        }
+    };
+
+    log::debug!("{} connected", peer);
+
+    // The state of the connection is handed to the pool with each message,
+    // and handed back once the message has been handled.
+    let mut protocol = context.protocol;
+    let mut origin = Origin::connect(Some(peer.to_string()));
+    let quota = Arc::new(context.quotas.connection());
+
+    loop {
+        let msg = tokio::select! {
+            msg = read_message(&mut reader) => msg,
+            _ = shutdown.wait_for(|requested| *requested) => None,
+            _ = idle(context.idle_timeout) => None,
+            // A client that has stopped reading its replies is disconnected.
+            _ = writer.closed() => None,
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => break,
+        };
+        let timestamp = current_timestamp();
+
+        let (done_sender, done) = oneshot::channel();
+        let job = {
+            let context = context.clone();
+            let quota = quota.clone();
+            let writer = writer.clone();
+            move || {
+                let open = run_message(
+                    &context,
+                    &msg,
+                    timestamp,
+                    &mut protocol,
+                    &mut origin,
+                    &quota,
+                    writer,
+                );
+                let _ = done_sender.send((protocol, origin, open));
+            }
+        };
+        context.pool.spawn(job);
+
+        match done.await {
+            Ok((new_protocol, new_origin, open)) => {
+                protocol = new_protocol;
+                origin = new_origin;
+                if !open {
+                    break;
+                }
+            }
+            // The message panicked before it could be handled, so the
+            // connection's state is lost with it.
+            Err(_) => return,
+        }
+    }
+
+    if context.shutdown.is_requested() {
+        notify_shutdown(&mut writer, protocol);
+    }
+
+    // The connection no longer counts towards the limit by the time the
+    // client sees the socket shut down.
+    drop(slot);
+
+    // Replies still being worked on hold a copy of the writer, so this waits
+    // for them to be sent.
+    drop(writer);
+    let _ = writing.await;
+    log::debug!("{} disconnected", peer);
+}
+
+/// Handles a single message on the compute pool. Returns `false` if the
+/// connection should be closed.
+fn run_message(
+    context: &Arc<Context>,
+    msg: &str,
+    timestamp: u64,
+    protocol: &mut Protocol,
+    origin: &mut Origin,
+    quota: &Arc<ConnectionQuota>,
+    writer: ChannelWriter,
+) -> bool {
+    let mut session = Session {
+        protocol,
+        origin,
+        credentials: context.credentials.as_deref(),
+        quota,
+    };
+
+    // Requests with an id go back on the pool, so that the connection can
+    // move on to its next message straight away.
+    let run_concurrently = |request, origin: Origin| {
+        let context = context.clone();
+        let quota = quota.clone();
+        let mut writer = writer.clone();
+        context.pool.clone().spawn(move || {
+            let reply = run_json_request(&context.spreadsheet, request, &origin, &quota, timestamp);
+            write_line(&Mutex::new(&mut writer), &reply);
+        });
+    };
+
+    let mut writer = writer.clone();
+    handle_message(
+        &context.spreadsheet,
+        msg,
+        timestamp,
+        &mut session,
+        &Mutex::new(&mut writer),
+        run_concurrently,
+    )
+}
+
+/// Waits for as long as a connection can be idle, or forever if there is no
+/// limit.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => future::pending().await,
+    }
+}
+
+/// Reads the next message, with the same limits as `TcpReader`. Returns
+/// `None` once the client has disconnected or sent something which isn't a
+/// valid message.
+async fn read_message<R>(reader: &mut R) -> Option<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Only read one byte past the limit so that we can tell if the message
+    // was too long without buffering the entire thing.
+    let mut bytes = Vec::new();
+    (&mut *reader)
+        .take(MAX_MESSAGE_LEN as u64 + 1)
+        .read_until(b'\n', &mut bytes)
+        .await
+        .ok()?;
+
+    if bytes.last() != Some(&b'\n') {
+        return None;
+    }
+    bytes.pop();
+    if bytes.last() == Some(&b'\r') {
+        bytes.pop();
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Writes everything sent to the returned writer to the socket, in order.
+/// The task finishes once every copy of the writer has been dropped, or as
+/// soon as more than `REPLY_CAPACITY` replies are waiting to be written.
+fn spawn_writer<W>(mut socket: W, peer: SocketAddr) -> (ChannelWriter, tokio::task::JoinHandle<()>)
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = mpsc::channel::<String>(REPLY_CAPACITY);
+    let overflowed = Arc::new(Notify::new());
+    let writer = ChannelWriter {
+        sender,
+        overflowed: overflowed.clone(),
+        peer: peer.to_string(),
+    };
+
+    let writing = tokio::spawn(async move {
+        let write = async {
+            let mut connected = true;
+            while let Some(text) = receiver.recv().await {
+                // Requests still running may have more to send after the
+                // client has gone, which is thrown away.
+                if connected {
+                    connected = socket.write_all(text.as_bytes()).await.is_ok()
+                        && socket.flush().await.is_ok();
+                }
+            }
+        };
+        // A client that doesn't read its replies would block the write
+        // forever, so it is abandoned rather than shut down cleanly.
+        let finished = tokio::select! {
+            _ = write => true,
+            _ = overflowed.notified() => false,
+        };
+        if finished {
+            let _ = socket.shutdown().await;
+        } else {
+            log::warn!("Disconnected {}, as it stopped reading replies", peer);
+        }
+    });
+
+    (writer, writing)
+}
+
+/// Hands replies over to the task writing to the socket, so that commands
+/// running on the pool never wait on a slow client. Replies are sent in the
+/// same format as `TcpWriter`.
+#[derive(Clone)]
+struct ChannelWriter {
+    sender: mpsc::Sender<String>,
+    /// Tells the task writing to the socket that too many replies are
+    /// waiting, so that it gives up on the client.
+    overflowed: Arc<Notify>,
+    peer: String,
+}
+
+impl ChannelWriter {
+    fn send(&self, text: String) -> Result<(), ConnectionError> {
+        match self.sender.try_send(text) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.notify_one();
+                Err(ConnectionError::ConnectionClosed)
+            }
+            Err(TrySendError::Closed(_)) => Err(ConnectionError::ConnectionClosed),
+        }
+    }
+
+    /// Waits until the task writing to the socket has finished.
+    async fn closed(&self) {
+        self.sender.closed().await
+    }
+}
+
+impl Writer for ChannelWriter {
+    fn write_message(&mut self, message: Reply) -> Result<(), ConnectionError> {
+        let message =
+            serde_json::to_string(&message).map_err(|_| ConnectionError::CouldNotConvertToJson)?;
+        self.send(message)
+    }
+
+    fn id(&self) -> String {
+        self.peer.clone()
+    }
+}
+
+impl LineWriter for ChannelWriter {
+    fn write_line(&mut self, line: &str) -> Result<(), ConnectionError> {
+        self.send(format!("{}\n", line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader as StdBufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    use super::*;
+    use crate::formula::EngineKind;
+    use crate::start_tcp_server;
+
+    fn start(options: ServerOptions) -> (u16, JoinHandle<()>) {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || start_tcp_server(listener, None, options));
+        (port, server)
+    }
+
+    fn options(shutdown: &Shutdown) -> ServerOptions {
+        ServerOptions {
+            shutdown: shutdown.clone(),
+            engine: EngineKind::Native,
+            ..ServerOptions::default()
+        }
+    }
+
+    /// Replies of the text protocol aren't followed by a newline, so this
+    /// reads exactly as much as the expected reply.
+    fn read_reply(mut client: &TcpStream, expected: &str) {
+        let mut buffer = vec![0; expected.len()];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_text_and_json_protocols() {
+        let shutdown = Shutdown::new();
+        let (port, server) = start(options(&shutdown));
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"set A1 5\nget A1\n").unwrap();
+        read_reply(&client, r#"{"Value":["A1",5]}"#);
+
+        client.write_all(b"protocol json\n").unwrap();
+        client
+            .write_all(b"{\"id\":1,\"op\":\"set\",\"cell\":\"A2\",\"expr\":\"A1 * 2\"}\n")
+            .unwrap();
+
+        let mut lines = StdBufReader::new(&client).lines();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":null,"status":"ok","protocol":"json"}"#
+        );
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"id":1,"status":"ok"}"#);
+
+        // Requests with an id run alongside the ones after them, so the get
+        // waits for the set to be done.
+        (&client)
+            .write_all(b"{\"op\":\"get\",\"cell\":\"A2\"}\n")
+            .unwrap();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":null,"status":"ok","cell":"A2","value":{"type":"Int","value":10}}"#
+        );
+
+        shutdown.request();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":null,"status":"error","message":"Server is shutting down"}"#
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_default_protocol() {
+        let shutdown = Shutdown::new();
+        let (port, server) = start(ServerOptions {
+            protocol: Protocol::Json,
+            ..options(&shutdown)
+        });
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client
+            .write_all(b"{\"op\":\"get\",\"cell\":\"A1\"}\n")
+            .unwrap();
+        let mut lines = StdBufReader::new(&client).lines();
+        assert_eq!(
+            lines.next().unwrap().unwrap(),
+            r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"None"}}"#
+        );
+
+        shutdown.request();
+        server.join().unwrap();
+    }
+
+    /// Opens `clients` connections at once, and checks that every one of them
+    /// gets a reply while the rest are still connected. Returns how long it
+    /// took to connect them, and then to serve them.
+    fn connect_many(clients: usize) -> (Duration, Duration) {
+        let shutdown = Shutdown::new();
+        let (port, server) = start(options(&shutdown));
+
+        let start = Instant::now();
+        let mut connections: Vec<TcpStream> = (0..clients)
+            .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+            .collect();
+        let connected = start.elapsed();
+        for (i, connection) in connections.iter_mut().enumerate() {
+            let cell = format!("A{}", i + 1);
+            let message = format!("set {cell} {i}\nget {cell}\n");
+            connection.write_all(message.as_bytes()).unwrap();
+        }
+        for (i, connection) in connections.iter().enumerate() {
+            read_reply(connection, &format!(r#"{{"Value":["A{}",{}]}}"#, i + 1, i));
+        }
+        let served = start.elapsed() - connected;
+
+        shutdown.request();
+        server.join().unwrap();
+        (connected, served)
+    }
+
+    #[test]
+    fn test_idle_connections_dont_hold_threads() {
+        // Far more than the threads of the runtime and the compute pool.
+        connect_many(200);
+    }
+
+    /// Needs a limit on open files of more than twice the number of clients,
+    /// as both ends of every connection are in this process.
+    #[test]
+    #[ignore = "opens 2000 connections; run with `cargo test -- --ignored`"]
+    fn test_load() {
+        let (connected, served) = connect_many(2000);
+        println!("2000 clients connected in {connected:?}, and served in {served:?}");
+    }
+
+    #[tokio::test]
+    async fn test_clients_that_stop_reading_are_disconnected() {
+        // The client end is never read, so the first write blocks for good.
+        let (socket, _client) = tokio::io::duplex(64);
+        let (writer, writing) = spawn_writer(socket, "127.0.0.1:1".parse().unwrap());
+
+        for _ in 0..REPLY_CAPACITY {
+            writer.send("x".repeat(100)).unwrap();
+        }
+        assert!(writer.send("x".repeat(100)).is_err());
+
+        // The writer gives up on the client, even with the writer still open.
+        tokio::time::timeout(Duration::from_secs(5), writing)
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), writer.closed())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_connection_limit_and_idle_timeout() {
+        let shutdown = Shutdown::new();
+        let (port, server) = start(ServerOptions {
+            max_connections: Some(1),
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..options(&shutdown)
+        });
+
+        let mut first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        first.write_all(b"get A1\n").unwrap();
+        read_reply(&first, r#"{"Value":["A1",null]}"#);
+
+        let second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        read_reply(
+            &second,
+            r#"{"Error":"Too many connections, try again later"}"#,
+        );
+
+        // The first client is disconnected once it has been idle for too
+        // long, making room for another.
+        assert_eq!((&first).read(&mut [0; 16]).unwrap(), 0);
+        let mut third = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        third.write_all(b"get A1\n").unwrap();
+        read_reply(&third, r#"{"Value":["A1",null]}"#);
+
+        shutdown.request();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+/// Loads the certificate chain and private key used to accept TLS
+/// connections. Both files are PEM encoded, with the server's certificate
+/// first in the chain.
+///
+/// # Example
+///
+/// ```no_run
+/// # use rsheet::connect::TcpManager;
+/// # use rsheet::shutdown::Shutdown;
+/// # use rsheet::tls;
+/// # fn main() -> std::io::Result<()> {
+/// let config = tls::load_config("cert.pem", "key.pem")?;
+/// let manager = TcpManager::launch([127, 0, 0, 1], 5000, Some(config), Shutdown::new());
+/// # Ok(())
+/// # }
+/// ```
+pub fn load_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let invalid =
+        |e: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| invalid(&e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(&e))?;
+    if certs.is_empty() {
+        return Err(invalid(&"No certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(&e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid(&e))?;
+    Ok(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::thread;
+
+    use rsheet_lib::connect::{Manager, Reader};
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::*;
+    use crate::connect::{LineWriter, TcpManager};
+    use crate::shutdown::Shutdown;
+
+    /// Generates a self-signed certificate for `localhost`, returning the
+    /// paths of the certificate and key, and the certificate itself for the
+    /// client to trust.
+    fn self_signed(name: &str) -> (PathBuf, PathBuf, CertificateDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_path = dir.join(format!("rsheet-{}-{}-cert.pem", name, std::process::id()));
+        let key_path = dir.join(format!("rsheet-{}-{}-key.pem", name, std::process::id()));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path, certified.cert.der().clone())
+    }
+
+    fn connect(
+        port: u16,
+        cert: CertificateDer<'static>,
+    ) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        StreamOwned::new(connection, TcpStream::connect(("127.0.0.1", port)).unwrap())
+    }
+
+    #[test]
+    fn test_connections_are_encrypted() {
+        let (cert_path, key_path, cert) = self_signed("encrypted");
+        let config = load_config(&cert_path, &key_path).unwrap();
+        let mut manager = TcpManager::launch([127, 0, 0, 1], 0, Some(config), Shutdown::new());
+        let port = manager.local_addr().unwrap().port();
+
+        let client = thread::spawn(move || {
+            let mut client = connect(port, cert);
+            client.write_all(b"get A1\n").unwrap();
+            client.flush().unwrap();
+
+            let mut line = String::new();
+            BufReader::new(client).read_line(&mut line).unwrap();
+            line
+        });
+
+        let (mut reader, mut writer) = manager.accept_new_connection().unwrap();
+        assert_eq!(reader.read_message().unwrap(), "get A1");
+        writer.write_line("A1 = 1").unwrap();
+        assert_eq!(client.join().unwrap(), "A1 = 1\n");
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+
+    #[test]
+    fn test_plaintext_is_rejected() {
+        let (cert_path, key_path, _) = self_signed("plaintext");
+        let config = load_config(&cert_path, &key_path).unwrap();
+        let mut manager = TcpManager::launch([127, 0, 0, 1], 0, Some(config), Shutdown::new());
+        let port = manager.local_addr().unwrap().port();
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"get A1\n").unwrap();
+
+        let (mut reader, _) = manager.accept_new_connection().unwrap();
+        assert!(reader.read_message().is_err());
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+
+    #[test]
+    fn test_load_config_errors() {
+        let (cert_path, key_path, _) = self_signed("errors");
+
+        assert!(load_config(&key_path, &key_path).is_err());
+        assert!(load_config(&cert_path, &cert_path).is_err());
+        assert!(load_config(cert_path.with_extension("missing"), &key_path).is_err());
+
+        let _ = std::fs::remove_file(cert_path);
+        let _ = std::fs::remove_file(key_path);
+    }
+}
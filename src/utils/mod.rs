@@ -1,12 +1,15 @@
+use std::time;
+
 use once_cell::sync::Lazy;
 use regex::Regex;
+use rsheet_lib::cells::{column_name_to_number, column_number_to_name};
 
 /// Checks if a given cell is valid.
 ///
 /// # Example
 ///
 /// ```rust
-/// use rsheets::utils::is_valid_cell;
+/// use rsheet::utils::is_valid_cell;
 ///
 /// assert_eq!(is_valid_cell("A1"), true);
 /// assert_eq!(is_valid_cell("A1_B2"), true);
@@ -19,3 +22,143 @@ pub fn is_valid_cell(cell_name: &str) -> bool {
         Lazy::new(|| Regex::new(r"^[A-Z]+[0-9]+(_[A-Z]+[0-9]+)?$").unwrap());
     CELL_PATTERN.is_match(cell_name)
 }
+
+/// Gets the current timestamp in seconds. Commands are tagged with the time
+/// they were received so that recent messages are prioritised over older
+/// messages that finish later due to the use of `sleep_then`.
+pub fn current_timestamp() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Splits a single cell into its zero indexed column and its row.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::cell_position;
+/// assert_eq!(cell_position("B3"), Some((1, 3)));
+/// assert_eq!(cell_position("3B"), None);
+/// ```
+pub fn cell_position(cell: &str) -> Option<(u32, u32)> {
+    let split = cell.find(|c: char| c.is_ascii_digit())?;
+    let (col, row) = cell.split_at(split);
+    if col.is_empty() || !col.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+
+    Some((column_name_to_number(col), row.parse().ok()?))
+}
+
+/// Lists every cell in a range such as `A1_C3`, row by row. The range may
+/// also be a single cell.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::range_cells;
+/// assert_eq!(range_cells("A1_B2"), vec!["A1", "B1", "A2", "B2"]);
+/// assert_eq!(range_cells("C3"), vec!["C3"]);
+/// ```
+pub fn range_cells(range: &str) -> Vec<String> {
+    let (start, end) = range.split_once('_').unwrap_or((range, range));
+
+    match (cell_position(start), cell_position(end)) {
+        (Some((start_col, start_row)), Some((end_col, end_row))) => (start_row..=end_row)
+            .flat_map(|row| {
+                (start_col..=end_col)
+                    .map(move |col| format!("{}{}", column_number_to_name(col), row))
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Counts the cells in a range such as `A1_C3` without listing them. The
+/// range may also be a single cell. Returns `None` if either end isn't a
+/// cell.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::range_size;
+/// assert_eq!(range_size("A1_B3"), Some(6));
+/// assert_eq!(range_size("C3"), Some(1));
+/// assert_eq!(range_size("B2_A1"), Some(0));
+/// ```
+pub fn range_size(range: &str) -> Option<u64> {
+    let (start, end) = range.split_once('_').unwrap_or((range, range));
+    let (start_col, start_row) = cell_position(start)?;
+    let (end_col, end_row) = cell_position(end)?;
+
+    let span = |start: u32, end: u32| match end.checked_sub(start) {
+        Some(span) => u64::from(span) + 1,
+        None => 0,
+    };
+    Some(span(start_col, end_col) * span(start_row, end_row))
+}
+
+/// Gets the top left and bottom right corners of a range such as `A1_C3`, as
+/// the column and row numbers of each. The range may also be a single cell.
+/// Returns `None` if either end isn't a cell.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::range_bounds;
+/// assert_eq!(range_bounds("B1_C3"), Some(((1, 1), (2, 3))));
+/// assert_eq!(range_bounds("A2"), Some(((0, 2), (0, 2))));
+/// assert_eq!(range_bounds("A1_?"), None);
+/// ```
+pub fn range_bounds(range: &str) -> Option<((u32, u32), (u32, u32))> {
+    let (start, end) = range.split_once('_').unwrap_or((range, range));
+    Some((cell_position(start)?, cell_position(end)?))
+}
+
+/// Checks if two ranges, either of which may be a single cell, have any cell
+/// in common.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::ranges_overlap;
+/// assert_eq!(ranges_overlap("A2_C2", "B1_B3"), true);
+/// assert_eq!(ranges_overlap("A1_A3", "B1_B3"), false);
+/// ```
+pub fn ranges_overlap(a: &str, b: &str) -> bool {
+    match (range_bounds(a), range_bounds(b)) {
+        (
+            Some(((a_col, a_row), (a_end_col, a_end_row))),
+            Some(((b_col, b_row), (b_end_col, b_end_row))),
+        ) => a_col <= b_end_col && b_col <= a_end_col && a_row <= b_end_row && b_row <= a_end_row,
+        _ => false,
+    }
+}
+
+/// Checks if a cell is inside of a range such as `A1_C3`. The range may also
+/// be a single cell.
+///
+/// # Example
+///
+/// ```rust
+/// # use rsheet::utils::cell_in_range;
+/// assert_eq!(cell_in_range("B2", "A1_C3"), true);
+/// assert_eq!(cell_in_range("D2", "A1_C3"), false);
+/// assert_eq!(cell_in_range("A1", "A1"), true);
+/// ```
+pub fn cell_in_range(cell: &str, range: &str) -> bool {
+    let (start, end) = range.split_once('_').unwrap_or((range, range));
+
+    match (
+        cell_position(cell),
+        cell_position(start),
+        cell_position(end),
+    ) {
+        (Some((col, row)), Some((start_col, start_row)), Some((end_col, end_row))) => {
+            (start_col..=end_col).contains(&col) && (start_row..=end_row).contains(&row)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,475 @@
+use std::fmt::{self, Display, Formatter};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use rsheet_lib::command_runner::{CellArgument, CellValue};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// The value of a cell. This extends `rsheet_lib`'s `CellValue` with
+/// fractional numbers, which can either be a float or a fixed precision
+/// decimal, booleans, and dates with or without a time.
+///
+/// Values serialize with an explicit type tag, so an error and a string can
+/// be told apart. Decimals are serialized as strings so that no precision is
+/// lost.
+///
+/// # Example
+///
+/// ```
+/// # use rust_decimal::Decimal;
+/// # use rsheet::value::Value;
+/// let value = Value::Decimal(Decimal::new(1999, 2));
+/// assert_eq!(value.to_string(), "19.99");
+/// assert_eq!(
+///     serde_json::to_string(&value).unwrap(),
+///     r#"{"type":"Decimal","value":"19.99"}"#
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Decimal(Decimal),
+    Bool(bool),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+    String(String),
+    Error(CellError),
+    #[default]
+    None,
+}
+
+/// The kinds of errors a cell can hold. Each kind has a code, such as
+/// `#DIV/0!`, which is how it is shown and serialized. The codes are stable,
+/// so clients can match on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// Dividing by zero.
+    #[serde(rename = "#DIV/0!")]
+    DivideByZero,
+
+    /// A reference or index which points outside of a range.
+    #[serde(rename = "#REF!")]
+    Reference,
+
+    /// An unknown function or variable name.
+    #[serde(rename = "#NAME?")]
+    Name,
+
+    /// A value of the wrong kind, such as adding a number to a date.
+    #[serde(rename = "#VALUE!")]
+    Value,
+
+    /// A cell which depends on itself.
+    #[serde(rename = "#CYCLE!")]
+    Cycle,
+
+    /// A lookup which couldn't find its key.
+    #[serde(rename = "#N/A")]
+    NotAvailable,
+
+    /// A number which is too large, or a date which is out of range.
+    #[serde(rename = "#NUM!")]
+    Number,
+
+    /// An array formula which couldn't spill into its neighbouring cells.
+    #[serde(rename = "#SPILL!")]
+    Spill,
+
+    /// A calculation which couldn't finish, such as one that timed out.
+    #[serde(rename = "#CALC!")]
+    Calc,
+
+    /// A formula which couldn't be parsed.
+    #[serde(rename = "#ERROR!")]
+    Syntax,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 10] = [
+        ErrorKind::DivideByZero,
+        ErrorKind::Reference,
+        ErrorKind::Name,
+        ErrorKind::Value,
+        ErrorKind::Cycle,
+        ErrorKind::NotAvailable,
+        ErrorKind::Number,
+        ErrorKind::Spill,
+        ErrorKind::Calc,
+        ErrorKind::Syntax,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::DivideByZero => "#DIV/0!",
+            ErrorKind::Reference => "#REF!",
+            ErrorKind::Name => "#NAME?",
+            ErrorKind::Value => "#VALUE!",
+            ErrorKind::Cycle => "#CYCLE!",
+            ErrorKind::NotAvailable => "#N/A",
+            ErrorKind::Number => "#NUM!",
+            ErrorKind::Spill => "#SPILL!",
+            ErrorKind::Calc => "#CALC!",
+            ErrorKind::Syntax => "#ERROR!",
+        }
+    }
+}
+
+/// An error held by a cell: its kind, and a message explaining what went
+/// wrong. It is shown as the code followed by the message.
+///
+/// # Example
+///
+/// ```
+/// # use rsheet::value::{CellError, ErrorKind};
+/// let error = CellError::new(ErrorKind::DivideByZero, "Division by zero");
+/// assert_eq!(error.to_string(), "#DIV/0! Division by zero");
+/// assert_eq!(
+///     serde_json::to_string(&error).unwrap(),
+///     r##"{"code":"#DIV/0!","message":"Division by zero"}"##
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellError {
+    #[serde(rename = "code")]
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CellError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// The most common error, a value of the wrong kind.
+    pub fn value(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Value, message)
+    }
+
+    /// Recovers an error from its text, e.g. after it has been through
+    /// `rsheet_lib`, which only has untyped errors. Text without a code is an
+    /// error from Rhai, which is classified by its message.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::value::{CellError, ErrorKind};
+    /// let error = CellError::parse("#N/A MATCH couldn't find 3");
+    /// assert_eq!(error, CellError::new(ErrorKind::NotAvailable, "MATCH couldn't find 3"));
+    /// assert_eq!(CellError::parse("Division by zero").kind, ErrorKind::DivideByZero);
+    /// ```
+    pub fn parse(text: &str) -> Self {
+        for kind in ErrorKind::ALL {
+            if let Some(message) = text.strip_prefix(kind.code()) {
+                return Self::new(kind, message.trim_start());
+            }
+        }
+
+        let kind = if text.contains("Division by zero") || text.contains("division by zero") {
+            ErrorKind::DivideByZero
+        } else if text.starts_with("Variable not found")
+            || (text.starts_with("Function not found") && !text.contains('('))
+        {
+            // Rhai names the types for an operator that can't be applied,
+            // e.g. `Function not found: + (i64, string)`.
+            ErrorKind::Name
+        } else if text.contains("overflow") || text.contains("too large") {
+            ErrorKind::Number
+        } else {
+            ErrorKind::Value
+        };
+        Self::new(kind, text)
+    }
+}
+
+impl Display for CellError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.message.is_empty() {
+            true => write!(f, "{}", self.kind.code()),
+            false => write!(f, "{} {}", self.kind.code(), self.message),
+        }
+    }
+}
+
+/// The inputs to a formula, which mirror `rsheet_lib`'s `CellArgument`. A
+/// single cell is a `Value`, a row or column of cells is a `Vector`, and
+/// anything else is a `Matrix` of rows.
+///
+/// The native engine keeps track of orientation: a `Vector` is always a
+/// column and a row is a `Matrix` with one row.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Value(Value),
+    Vector(Vec<Value>),
+    Matrix(Vec<Vec<Value>>),
+}
+
+impl Argument {
+    /// The argument as rows of values. A vector is a column, so it becomes
+    /// one value per row.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use rsheet::value::{Argument, Value};
+    /// let vector = Argument::Vector(vec![Value::Int(1), Value::Int(2)]);
+    /// assert_eq!(vector.into_rows(), vec![vec![Value::Int(1)], vec![Value::Int(2)]]);
+    /// ```
+    pub fn into_rows(self) -> Vec<Vec<Value>> {
+        match self {
+            Argument::Value(value) => vec![vec![value]],
+            Argument::Vector(vector) => vector.into_iter().map(|value| vec![value]).collect(),
+            Argument::Matrix(matrix) => matrix,
+        }
+    }
+
+    /// The opposite of `into_rows`. A single value is a `Value` and a single
+    /// column is a `Vector`. Everything else, including a single row, is a
+    /// `Matrix`.
+    pub fn from_rows(mut rows: Vec<Vec<Value>>) -> Argument {
+        if rows.iter().all(|row| row.len() == 1) {
+            if rows.len() == 1 {
+                return Argument::Value(rows.remove(0).remove(0));
+            }
+            return Argument::Vector(rows.into_iter().flatten().collect());
+        }
+        Argument::Matrix(rows)
+    }
+}
+
+/// Floats are shown with at most 15 significant digits, like other
+/// spreadsheets, so that `0.1 + 0.2` is shown as `0.3`.
+fn format_float(f: f64) -> String {
+    match format!("{:.14e}", f).parse::<f64>() {
+        Ok(rounded) => rounded.to_string(),
+        Err(_) => f.to_string(),
+    }
+}
+
+impl Value {
+    /// Parses an ISO-8601 date such as `2024-01-31`, or a date and time such
+    /// as `2024-01-31T09:30:00`. The time can be separated by a space instead
+    /// of a `T`, and the seconds are optional.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use chrono::NaiveDate;
+    /// # use rsheet::value::Value;
+    /// let date = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+    /// assert_eq!(Value::parse_date("2024-01-31"), Some(Value::Date(date)));
+    /// assert_eq!(Value::parse_date("2024 - 01"), None);
+    /// ```
+    pub fn parse_date(text: &str) -> Option<Value> {
+        let text = text.trim();
+        if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+            return Some(Value::Date(date));
+        }
+
+        let text = text.strip_suffix('Z').unwrap_or(text);
+        [
+            "%Y-%m-%dT%H:%M:%S%.f",
+            "%Y-%m-%d %H:%M:%S%.f",
+            "%Y-%m-%dT%H:%M",
+            "%Y-%m-%d %H:%M",
+        ]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .map(Value::DateTime)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(float) => write!(f, "{}", format_float(*float)),
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Bool(true) => write!(f, "TRUE"),
+            Value::Bool(false) => write!(f, "FALSE"),
+            Value::Date(date) => write!(f, "{}", date.format("%Y-%m-%d")),
+            Value::DateTime(datetime) => write!(f, "{}", datetime.format("%Y-%m-%dT%H:%M:%S%.f")),
+            Value::String(s) => write!(f, "\"{}\"", s),
+            Value::Error(e) => write!(f, "Error: \"{}\"", e),
+            Value::None => write!(f, "None"),
+        }
+    }
+}
+
+impl From<CellValue> for Value {
+    fn from(value: CellValue) -> Self {
+        match value {
+            CellValue::Int(i) => Value::Int(i),
+            CellValue::String(s) => Value::String(s),
+            CellValue::Error(e) => Value::Error(CellError::parse(&e)),
+            CellValue::None => Value::None,
+        }
+    }
+}
+
+/// `CellValue` can only hold integers and strings, so every other kind of
+/// value is converted to its text.
+impl From<Value> for CellValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Int(i) => CellValue::Int(i),
+            Value::Float(_)
+            | Value::Decimal(_)
+            | Value::Bool(_)
+            | Value::Date(_)
+            | Value::DateTime(_) => CellValue::String(value.to_string()),
+            Value::String(s) => CellValue::String(s),
+            Value::Error(e) => CellValue::Error(e.to_string()),
+            Value::None => CellValue::None,
+        }
+    }
+}
+
+impl From<CellArgument> for Argument {
+    fn from(argument: CellArgument) -> Self {
+        match argument {
+            CellArgument::Value(value) => Argument::Value(value.into()),
+            CellArgument::Vector(vector) => {
+                Argument::Vector(vector.into_iter().map(Value::from).collect())
+            }
+            CellArgument::Matrix(matrix) => Argument::Matrix(
+                matrix
+                    .into_iter()
+                    .map(|row| row.into_iter().map(Value::from).collect())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<Argument> for CellArgument {
+    fn from(argument: Argument) -> Self {
+        match argument {
+            Argument::Value(value) => CellArgument::Value(value.into()),
+            Argument::Vector(vector) => {
+                CellArgument::Vector(vector.into_iter().map(CellValue::from).collect())
+            }
+            Argument::Matrix(matrix) => CellArgument::Matrix(
+                matrix
+                    .into_iter()
+                    .map(|row| row.into_iter().map(CellValue::from).collect())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Value::Int(-3).to_string(), "-3");
+        assert_eq!(Value::Float(0.1 + 0.2).to_string(), "0.3");
+        assert_eq!(Value::Float(10.0 / 3.0).to_string(), "3.33333333333333");
+        assert_eq!(Value::Float(2.5e-7).to_string(), "0.00000025");
+        assert_eq!(Value::Decimal(Decimal::new(1050, 2)).to_string(), "10.50");
+        assert_eq!(Value::String("a".to_string()).to_string(), "\"a\"");
+        assert_eq!(Value::Bool(true).to_string(), "TRUE");
+        assert_eq!(
+            Value::parse_date("2024-02-29T08:05").unwrap().to_string(),
+            "2024-02-29T08:05:00"
+        );
+    }
+
+    #[test]
+    fn test_parse_date() {
+        let date = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        let datetime = date.and_hms_milli_opt(13, 45, 10, 500).unwrap();
+
+        assert_eq!(Value::parse_date("2024-02-29"), Some(Value::Date(date)));
+        assert_eq!(
+            Value::parse_date("2024-02-29T13:45:10.5"),
+            Some(Value::DateTime(datetime))
+        );
+        assert_eq!(
+            Value::parse_date("2024-02-29 13:45:10.500Z"),
+            Some(Value::DateTime(datetime))
+        );
+        assert_eq!(Value::parse_date("2023-02-29"), None);
+        assert_eq!(Value::parse_date("2024-02-29 + 1"), None);
+        assert_eq!(Value::parse_date("20"), None);
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let values = [
+            Value::Int(7),
+            Value::Float(1.0 / 3.0),
+            Value::Bool(false),
+            Value::parse_date("1999-12-31").unwrap(),
+            Value::parse_date("1999-12-31T23:59:59.25").unwrap(),
+            Value::Decimal("12345678901234567.891".parse().unwrap()),
+            Value::String("x".to_string()),
+            Value::Error(CellError::new(ErrorKind::Reference, "bad")),
+            Value::None,
+        ];
+
+        for value in values {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(
+                serde_json::from_str::<Value>(&json).unwrap(),
+                value,
+                "{}",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for kind in ErrorKind::ALL {
+            let error = CellError::new(kind, "message");
+            assert_eq!(CellError::parse(&error.to_string()), error);
+
+            let json = serde_json::to_value(kind).unwrap();
+            assert_eq!(json, kind.code());
+        }
+
+        assert_eq!(
+            CellError::parse("Variable not found: X").kind,
+            ErrorKind::Name
+        );
+        assert_eq!(
+            CellError::parse("Function not found: + (i64, string)").kind,
+            ErrorKind::Value
+        );
+        assert_eq!(
+            Value::Error(CellError::new(ErrorKind::Cycle, "")).to_string(),
+            "Error: \"#CYCLE!\""
+        );
+    }
+
+    #[test]
+    fn test_argument_rows() {
+        let column = Argument::Vector(vec![Value::Int(1), Value::Int(2)]);
+        let row = Argument::Matrix(vec![vec![Value::Int(1), Value::Int(2)]]);
+
+        assert_eq!(Argument::from_rows(column.clone().into_rows()), column);
+        assert_eq!(Argument::from_rows(row.clone().into_rows()), row);
+        assert_eq!(
+            Argument::from_rows(vec![vec![Value::None]]),
+            Argument::Value(Value::None)
+        );
+    }
+
+    #[test]
+    fn test_cell_value_conversion() {
+        assert_eq!(CellValue::from(Value::Int(1)), CellValue::Int(1));
+        assert_eq!(
+            CellValue::from(Value::Float(1.5)),
+            CellValue::String("1.5".to_string())
+        );
+        assert_eq!(Value::from(CellValue::None), Value::None);
+    }
+}
@@ -0,0 +1,796 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rayon::ThreadPool;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{error::TryRecvError, Receiver};
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::{
+    acl,
+    audit::Origin,
+    auth::{self, Credentials},
+    connect::{ConnectionLimit, ConnectionSlot},
+    protocol::JsonReply,
+    quota::{ConnectionQuota, Quotas},
+    run_command,
+    shutdown::{Shutdown, POLL_INTERVAL},
+    spreadsheet::{CellChange, Spreadsheet, Subscriptions},
+    tcp::watch_shutdown,
+    utils::{current_timestamp, is_valid_cell},
+    value::Value,
+    ServerOptions, Services,
+};
+
+/// Room in a message for the command and cell that come before a formula.
+const COMMAND_LEN: usize = 32;
+
+/// The longest handshake request we are willing to buffer.
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+/// How long a connection which is being closed has to take the close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A client that has fallen too far behind stops being sent changes, and is
+/// disconnected so that it knows it has missed some.
+const FALLEN_BEHIND: (CloseCode, &str) = (CloseCode::Again, "Too far behind on changes");
+
+/// Sent to a client whenever a cell it is subscribed to changes.
+#[derive(Debug, Serialize)]
+struct ChangeEvent {
+    event: &'static str,
+    cell: String,
+    value: Value,
+}
+
+/// What every connection's task needs.
+struct Context {
+    spreadsheet: Arc<Spreadsheet>,
+    pool: Arc<ThreadPool>,
+    quotas: Arc<Quotas>,
+    credentials: Option<Arc<Credentials>>,
+    idle_timeout: Option<Duration>,
+    config: WebSocketConfig,
+}
+
+/// The socket under a WebSocket, which is read and written without blocking.
+/// When it isn't ready, reads and writes fail with `WouldBlock` and the
+/// connection's task waits for it to be ready, so an idle connection doesn't
+/// hold on to a thread.
+struct NonBlocking(Arc<TcpStream>);
+
+impl Read for NonBlocking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.try_read(buf)
+    }
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stands in for the socket during the handshake, which is read ahead of time
+/// so that the handshake itself never has to wait.
+struct Handshake {
+    request: io::Cursor<Vec<u8>>,
+    response: Vec<u8>,
+}
+
+impl Read for Handshake {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut self.request, buf)
+    }
+}
+
+impl Write for Handshake {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut self.response, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A WebSocket connection, along with its socket so that the connection can
+/// wait for the socket to be ready.
+struct Connection {
+    socket: WebSocket<NonBlocking>,
+    stream: Arc<TcpStream>,
+    /// How long sending a message can wait for the client to take it.
+    send_timeout: Option<Duration>,
+}
+
+/// Accepts WebSocket connections until a shutdown is requested, then gives
+/// the open connections `drain_timeout` to close. Clients send the same
+/// commands as the text protocol, plus `subscribe <range>` and
+/// `unsubscribe <range>`. Replies are sent in the format of the JSON protocol,
+/// and changes to subscribed cells are pushed as they happen.
+///
+/// Connections are handled on a tokio runtime like TCP connections, and their
+/// commands are run on the compute pool. If credentials are given, clients
+/// have to send `auth <user> <token>` before any other command. Connections
+/// count towards the same limit as TCP connections, and are closed once they
+/// have been idle for `idle_timeout`, including the time spent on the
+/// handshake.
+pub(crate) fn serve(listener: StdTcpListener, services: Services, options: &ServerOptions) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(options.io_threads)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error starting the WebSocket runtime: {}", e);
+            return;
+        }
+    };
+
+    // A message is a single command, so it can be no longer than the longest
+    // formula and the command around it.
+    let max_message_len = services.spreadsheet.limits().max_formula_len + COMMAND_LEN;
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_message_len))
+        .max_frame_size(Some(max_message_len));
+
+    let context = Arc::new(Context {
+        spreadsheet: services.spreadsheet,
+        pool: services.pool,
+        quotas: services.quotas,
+        credentials: options.credentials.clone(),
+        idle_timeout: options.idle_timeout,
+        config,
+    });
+    runtime.block_on(accept_connections(
+        listener,
+        services.connections,
+        context,
+        options.shutdown.clone(),
+        options.drain_timeout,
+    ));
+
+    runtime.shutdown_background();
+}
+
+async fn accept_connections(
+    listener: StdTcpListener,
+    connections: ConnectionLimit,
+    context: Arc<Context>,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+) {
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error listening for WebSocket connections: {}", e);
+            return;
+        }
+    };
+
+    let mut shutdown = watch_shutdown(shutdown);
+    let mut tasks = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|requested| *requested) => break,
+        };
+
+        // The slot is taken here rather than in the task, so that a burst of
+        // connections can't get past the limit before any of them start.
+        let slot = connections.try_acquire();
+        let context = context.clone();
+        let shutdown = shutdown.clone();
+        tasks.spawn(async move {
+            handle_connection(stream, slot, &context, shutdown).await;
+        });
+
+        while tasks.try_join_next().is_some() {}
+    }
+
+    drop(listener);
+    let drain = async { while tasks.join_next().await.is_some() {} };
+    if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+        eprintln!("Timed out waiting for WebSocket connections to close, shutting down anyway");
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    slot: Option<ConnectionSlot>,
+    context: &Arc<Context>,
+    shutdown: watch::Receiver<bool>,
+) {
+    // The handshake counts as time the connection is idle, so a client that
+    // never finishes it is dropped like one that never sends anything.
+    let deadline = context.idle_timeout.map(|timeout| Instant::now() + timeout);
+    let peer = stream.peer_addr().ok();
+    let handshake = handshake(stream, context.config);
+    let connection = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, handshake).await.ok(),
+        None => Some(handshake.await),
+    };
+    let mut connection = match connection.flatten() {
+        Some(socket) => socket,
+        None => return,
+    };
+    connection.send_timeout = context.idle_timeout;
+
+    // The handshake has to finish before the client can be told why it is
+    // being turned away.
+    let slot = match slot {
+        Some(slot) => slot,
+        None => {
+            close(&mut connection, CloseCode::Again, "Too many connections").await;
+            return;
+        }
+    };
+
+    let origin = Origin::connect(peer.map(|addr| addr.to_string()));
+    let quota = Arc::new(context.quotas.connection());
+    let reason = handle_socket(&mut connection, context, origin, quota, deadline, shutdown).await;
+
+    // The connection no longer counts towards the limit by the time the
+    // client finds out that it has been closed.
+    drop(slot);
+    if let Some((code, reason)) = reason {
+        close(&mut connection, code, reason).await;
+    }
+}
+
+/// Reads the client's handshake request and answers it. Returns `None` if the
+/// client disconnected or didn't send a valid request.
+async fn handshake(mut stream: TcpStream, config: WebSocketConfig) -> Option<Connection> {
+    let mut request = Vec::new();
+    let end = loop {
+        let mut chunk = [0; 1024];
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&chunk[..read]);
+
+        if let Some(end) = request.windows(4).position(|bytes| bytes == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() > MAX_HANDSHAKE_LEN {
+            return None;
+        }
+    };
+
+    // Anything after the request is the start of the first message.
+    let rest = request.split_off(end);
+    let handshake = Handshake {
+        request: io::Cursor::new(request),
+        response: Vec::new(),
+    };
+    let response = match tungstenite::accept_with_config(handshake, Some(config)) {
+        Ok(socket) => socket.get_ref().response.clone(),
+        Err(_) => return None,
+    };
+    stream.write_all(&response).await.ok()?;
+
+    let stream = Arc::new(stream);
+    let socket = NonBlocking(stream.clone());
+    Some(Connection {
+        socket: WebSocket::from_partially_read(socket, rest, Role::Server, Some(config)),
+        stream,
+        send_timeout: None,
+    })
+}
+
+/// Handles a connection until it is closed. Each command is run on the
+/// compute pool, and the task waits for it without holding on to a thread.
+/// Returns why the connection is being closed, if the client should be told.
+async fn handle_socket(
+    connection: &mut Connection,
+    context: &Arc<Context>,
+    mut origin: Origin,
+    quota: Arc<ConnectionQuota>,
+    mut deadline: Option<Instant>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Option<(CloseCode, &'static str)> {
+    // Start watching before anything is read so that no change made by this
+    // client is missed. Only changes to subscribed cells are sent our way.
+    let subscriptions = Subscriptions::default();
+    let mut changes = context.spreadsheet.watch_ranges(subscriptions.clone());
+
+    loop {
+        tokio::select! {
+            readable = connection.stream.readable() => {
+                if readable.is_err() {
+                    return None;
+                }
+            }
+            change = changes.recv() => {
+                let sent = match change {
+                    Some(change) => send_change(connection, change).await,
+                    None => return Some(FALLEN_BEHIND),
+                };
+                if !sent {
+                    return None;
+                }
+                continue;
+            }
+            _ = shutting_down(&mut shutdown) => {
+                return Some((CloseCode::Away, "Server is shutting down"));
+            }
+            _ = idle_until(deadline) => return Some((CloseCode::Away, "Idle timeout")),
+        }
+
+        // Handle every message that has arrived.
+        loop {
+            let msg = match connection.socket.read() {
+                Ok(Message::Text(msg)) => msg.to_string(),
+                Ok(Message::Close(_)) => {
+                    // Finish the closing handshake the client started.
+                    flush(connection).await;
+                    return None;
+                }
+                Ok(_) => continue,
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return None,
+            };
+            deadline = context.idle_timeout.map(|timeout| Instant::now() + timeout);
+
+            let credentials = context.credentials.as_deref();
+            let reply = match check_auth(credentials, &mut origin.user, &msg) {
+                Ok(Some(reply)) => Some(reply),
+                Ok(None) => {
+                    match run_on_pool(context, msg, &origin, &quota, &subscriptions).await {
+                        Some(reply) => reply,
+                        // The command panicked, taking its reply with it.
+                        None => return None,
+                    }
+                }
+                Err(reply) => {
+                    send(connection, Message::text(reply.to_line())).await;
+                    return Some((CloseCode::Policy, "Authentication failed"));
+                }
+            };
+            if let Some(reply) = reply {
+                if !send(connection, Message::text(reply.to_line())).await {
+                    return None;
+                }
+            }
+
+            // Changes made by the command go out before the next reply.
+            if let Err(reason) = send_changes(connection, &mut changes).await {
+                return reason;
+            }
+        }
+    }
+}
+
+/// Sends every pending change to a subscribed cell. Returns why the
+/// connection is being closed if it can't carry on.
+async fn send_changes(
+    connection: &mut Connection,
+    changes: &mut Receiver<CellChange>,
+) -> Result<(), Option<(CloseCode, &'static str)>> {
+    loop {
+        let change = match changes.try_recv() {
+            Ok(change) => change,
+            Err(TryRecvError::Empty) => return Ok(()),
+            Err(TryRecvError::Disconnected) => return Err(Some(FALLEN_BEHIND)),
+        };
+        if !send_change(connection, change).await {
+            return Err(None);
+        }
+    }
+}
+
+/// Sends a change to a subscribed cell. Returns `false` if the client has
+/// gone.
+async fn send_change(connection: &mut Connection, change: CellChange) -> bool {
+    let event = ChangeEvent {
+        event: "change",
+        cell: change.cell,
+        value: change.value,
+    };
+    let event = serde_json::to_string(&event).expect("ChangeEvent is serializable.");
+    send(connection, Message::text(event)).await
+}
+
+/// Runs a command on the compute pool and waits for its reply. Returns `None`
+/// if the command panicked.
+async fn run_on_pool(
+    context: &Arc<Context>,
+    msg: String,
+    origin: &Origin,
+    quota: &Arc<ConnectionQuota>,
+    subscriptions: &Subscriptions,
+) -> Option<Option<JsonReply>> {
+    let (sender, reply) = oneshot::channel();
+    let context = context.clone();
+    let (origin, quota, subscriptions) = (origin.clone(), quota.clone(), subscriptions.clone());
+    context.pool.clone().spawn(move || {
+        let reply = handle_message(&context.spreadsheet, &msg, &origin, &quota, &subscriptions);
+        let _ = sender.send(reply);
+    });
+    reply.await.ok()
+}
+
+/// Waits until a shutdown is requested.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|requested| *requested).await;
+}
+
+/// Waits until a connection has been idle for too long, or forever if there
+/// is no limit.
+async fn idle_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends a message, waiting for the client to take it. Returns `false` if it
+/// couldn't be sent, which we take to mean that the client has gone.
+async fn send(connection: &mut Connection, message: Message) -> bool {
+    match connection.socket.write(message) {
+        Ok(_) => flush(connection).await,
+        // The message is kept to be written once the socket is ready.
+        Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => flush(connection).await,
+        Err(_) => false,
+    }
+}
+
+/// Writes out everything the connection has been given to send. A client
+/// which doesn't take it within the send timeout is given up on.
+async fn flush(connection: &mut Connection) -> bool {
+    loop {
+        match connection.socket.flush() {
+            Ok(_) => return true,
+            Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                let writable = connection.stream.writable();
+                let writable = match connection.send_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, writable).await.ok(),
+                    None => Some(writable.await),
+                };
+                if !matches!(writable, Some(Ok(_))) {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Closes the connection, letting the client know why. The client may have
+/// already gone, so any error is ignored.
+async fn close(connection: &mut Connection, code: CloseCode, reason: &str) {
+    let frame = CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    };
+    connection.send_timeout = Some(CLOSE_TIMEOUT);
+    if connection.socket.close(Some(frame)).is_ok() {
+        flush(connection).await;
+    }
+}
+
+/// Handles `auth <user> <token>`, and turns away every other command until
+/// the client has authenticated. Returns the reply to `auth`, or `None` for
+/// any other command which can go ahead. An error closes the connection.
+fn check_auth(
+    credentials: Option<&Credentials>,
+    user: &mut Option<String>,
+    msg: &str,
+) -> Result<Option<JsonReply>, JsonReply> {
+    let args: Vec<&str> = msg.split_whitespace().collect();
+    match args.as_slice() {
+        ["auth", rest @ ..] => match auth::authenticate(credentials, rest) {
+            Ok(name) => {
+                *user = Some(name);
+                Ok(Some(JsonReply::ok(None)))
+            }
+            Err(e) => Err(JsonReply::error(None, e)),
+        },
+        _ if credentials.is_some() && user.is_none() => Err(JsonReply::error(
+            None,
+            "Authentication required".to_string(),
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Handles a single command from the client, returning the reply if there is
+/// one. Subscribing to a range needs permission to read it.
+fn handle_message(
+    spreadsheet: &Arc<Spreadsheet>,
+    msg: &str,
+    origin: &Origin,
+    quota: &ConnectionQuota,
+    subscriptions: &Subscriptions,
+) -> Option<JsonReply> {
+    let args: Vec<&str> = msg.split_whitespace().collect();
+
+    match args.as_slice() {
+        [] => None,
+        ["subscribe", range] | ["unsubscribe", range] if !is_valid_cell(range) => {
+            Some(JsonReply::error(None, "Invalid range".to_string()))
+        }
+        ["subscribe", range] => {
+            match acl::check(spreadsheet, origin.user.as_deref(), &["get", range]) {
+                Ok(_) => {
+                    subscriptions.subscribe(range);
+                    Some(JsonReply::ok(None))
+                }
+                Err(e) => Some(JsonReply::from_reply(None, e.into())),
+            }
+        }
+        ["unsubscribe", range] => {
+            subscriptions.unsubscribe(range);
+            Some(JsonReply::ok(None))
+        }
+        ["subscribe", ..] | ["unsubscribe", ..] => Some(JsonReply::error(
+            None,
+            format!("Invalid number of arguments for {}", args[0]),
+        )),
+        _ => run_command(spreadsheet, args, origin, quota, current_timestamp())
+            .map(|reply| JsonReply::from_reply(None, reply)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Acl;
+    use crate::spreadsheet::new_shared_spreadsheet;
+    use rayon::ThreadPoolBuilder;
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    type Client = WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>;
+
+    /// Starts a server in the background, returning its address.
+    fn start(
+        spreadsheet: Arc<Spreadsheet>,
+        connections: ConnectionLimit,
+        options: ServerOptions,
+    ) -> SocketAddr {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let services = Services {
+            spreadsheet,
+            pool: Arc::new(ThreadPoolBuilder::new().num_threads(2).build().unwrap()),
+            quotas: Arc::new(Quotas::default()),
+            connections,
+        };
+        thread::spawn(move || serve(listener, services, &options));
+        addr
+    }
+
+    fn read_text(client: &mut Client) -> String {
+        loop {
+            if let Message::Text(text) = client.read().unwrap() {
+                return text.to_string();
+            }
+        }
+    }
+
+    #[test]
+    fn test_subscribed_changes_are_streamed() {
+        let addr = start(
+            new_shared_spreadsheet(),
+            ConnectionLimit::default(),
+            ServerOptions::default(),
+        );
+
+        let (mut client, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+
+        client.send(Message::text("subscribe B1_B2")).unwrap();
+        assert_eq!(read_text(&mut client), r#"{"id":null,"status":"ok"}"#);
+
+        // A1 isn't subscribed to, but B1 is recalculated when it changes.
+        client.send(Message::text("set B1 A1 * 2")).unwrap();
+        client.send(Message::text("set A1 21")).unwrap();
+        client.send(Message::text("get A1")).unwrap();
+
+        let event = read_text(&mut client);
+        assert!(event.starts_with(r#"{"event":"change","cell":"B1""#));
+        assert_eq!(
+            read_text(&mut client),
+            r#"{"event":"change","cell":"B1","value":{"type":"Int","value":42}}"#
+        );
+        assert_eq!(
+            read_text(&mut client),
+            r#"{"id":null,"status":"ok","cell":"A1","value":{"type":"Int","value":21}}"#
+        );
+    }
+
+    #[test]
+    fn test_messages_are_limited_to_the_longest_formula() {
+        let spreadsheet = new_shared_spreadsheet();
+        let max_formula_len = spreadsheet.limits().max_formula_len;
+        let addr = start(
+            spreadsheet,
+            ConnectionLimit::default(),
+            ServerOptions::default(),
+        );
+
+        let (mut client, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        let formula = "1".repeat(max_formula_len);
+        client
+            .send(Message::text(format!("set A1 {}", formula)))
+            .unwrap();
+        client.send(Message::text("get A2")).unwrap();
+        assert!(read_text(&mut client).contains(r#""cell":"A2""#));
+
+        client
+            .send(Message::text(format!("set A1 {}", formula.repeat(2))))
+            .unwrap();
+        assert!(client.read().is_err());
+    }
+
+    /// Reads until the server closes the connection, returning the reason.
+    fn close_reason(client: &mut Client) -> Option<(CloseCode, String)> {
+        loop {
+            match client.read() {
+                Ok(Message::Close(frame)) => {
+                    return frame.map(|frame| (frame.code, frame.reason.to_string()))
+                }
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_connection_limit_and_idle_timeout() {
+        let addr = start(
+            new_shared_spreadsheet(),
+            ConnectionLimit::new(Some(1)),
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(300)),
+                ..ServerOptions::default()
+            },
+        );
+
+        let (mut first, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        first.send(Message::text("get A1")).unwrap();
+        read_text(&mut first);
+
+        let (mut second, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        assert_eq!(
+            close_reason(&mut second),
+            Some((CloseCode::Again, "Too many connections".to_string()))
+        );
+
+        // Once the first client has been idle for too long it is
+        // disconnected, making room for another.
+        assert_eq!(
+            close_reason(&mut first),
+            Some((CloseCode::Away, "Idle timeout".to_string()))
+        );
+        let (mut third, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        third.send(Message::text("get A1")).unwrap();
+        assert!(read_text(&mut third).contains(r#""cell":"A1""#));
+    }
+
+    #[test]
+    fn test_handshake_counts_as_idle_time() {
+        let addr = start(
+            new_shared_spreadsheet(),
+            ConnectionLimit::default(),
+            ServerOptions {
+                idle_timeout: Some(Duration::from_millis(300)),
+                ..ServerOptions::default()
+            },
+        );
+
+        // A client that never finishes its handshake is disconnected.
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_auth_is_required() {
+        let credentials = Credentials::parse(&format!("alice {}", auth::hash_token("secret")));
+        let credentials = credentials.unwrap();
+        let mut user = None;
+
+        assert!(check_auth(Some(&credentials), &mut user, "get A1").is_err());
+        assert!(check_auth(Some(&credentials), &mut user, "auth alice wrong").is_err());
+        assert_eq!(
+            check_auth(Some(&credentials), &mut user, "auth alice secret"),
+            Ok(Some(JsonReply::ok(None)))
+        );
+        assert_eq!(user, Some("alice".to_string()));
+        assert_eq!(
+            check_auth(Some(&credentials), &mut user, "get A1"),
+            Ok(None)
+        );
+        assert_eq!(check_auth(None, &mut None, "get A1"), Ok(None));
+    }
+
+    #[test]
+    fn test_subscribe_validates_range() {
+        let subscriptions = Subscriptions::default();
+        let quota = Arc::new(Quotas::default()).connection();
+        let spreadsheet = new_shared_spreadsheet();
+
+        let reply = handle_message(
+            &spreadsheet,
+            "subscribe a1",
+            &Origin::default(),
+            &quota,
+            &subscriptions,
+        )
+        .unwrap();
+        assert_eq!(reply, JsonReply::error(None, "Invalid range".to_string()));
+
+        handle_message(
+            &spreadsheet,
+            "subscribe A1_A3",
+            &Origin::default(),
+            &quota,
+            &subscriptions,
+        );
+        assert_eq!(subscriptions.ranges(), vec!["A1_A3".to_string()]);
+
+        handle_message(
+            &spreadsheet,
+            "unsubscribe A1_A3",
+            &Origin::default(),
+            &quota,
+            &subscriptions,
+        );
+        assert!(subscriptions.ranges().is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_needs_read_access() {
+        let subscriptions = Subscriptions::default();
+        let quota = Arc::new(Quotas::default()).connection();
+        let alice = Origin {
+            user: Some("alice".to_string()),
+            ..Origin::default()
+        };
+        let mut spreadsheet = Spreadsheet::new();
+        spreadsheet.set_acl(Some(Acl::parse("allow alice read A1_A9").unwrap()));
+        let spreadsheet = Arc::new(spreadsheet);
+
+        let reply = handle_message(&spreadsheet, "subscribe B1", &alice, &quota, &subscriptions);
+        assert_eq!(
+            reply,
+            Some(JsonReply::error(None, "permission denied".to_string()))
+        );
+        handle_message(
+            &spreadsheet,
+            "subscribe A1_A3",
+            &alice,
+            &quota,
+            &subscriptions,
+        );
+        assert_eq!(subscriptions.ranges(), vec!["A1_A3".to_string()]);
+    }
+}